@@ -0,0 +1,587 @@
+mod policy;
+mod store;
+
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use crate::cache::policy::CacheControl;
+use crate::cache::store::{Claim, Entry, Store, Writer};
+pub(crate) use crate::cache::store::Purge;
+use crate::config::CacheConfig;
+use crate::headers::HeaderMap;
+use crate::request::HttpRequest;
+use crate::response::{Body, HttpResponse};
+use crate::util::{parse_http_date, HttpMethod, HttpStatusCode};
+
+// How long a request waits for another one fetching the same response before
+// fetching on its own.
+const COALESCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Fields a client makes its request conditional with. The cache validates
+// with its own and answers the client's itself.
+const CONDITIONAL: [&str; 5] = ["if-match", "if-none-match", "if-modified-since", "if-unmodified-since", "if-range"];
+
+// Fields of a stored response repeated in a 304 to the client.
+// https://www.rfc-editor.org/rfc/rfc9110#section-15.4.5
+const NOT_MODIFIED_FIELDS: [&str; 6] = ["cache-control", "content-location", "date", "etag", "expires", "vary"];
+
+// A response as it came from upstream, fields that only concerned that
+// connection already removed.
+pub(crate) struct Fetched {
+    pub(crate) status: HttpStatusCode,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Option<Box<dyn Body>>,
+}
+
+impl Fetched {
+    pub(crate) fn into_response(self) -> HttpResponse {
+        let mut response = HttpResponse::new(self.status);
+        for (name, value) in self.headers.iter() {
+            // the length is set from the body, if there is one
+            if self.body.is_none() || !name.eq_ignore_ascii_case("content-length") {
+                response = response.append_header(name, value);
+            }
+        }
+        match self.body {
+            Some(body) => response.with_boxed_body(body),
+            None => response,
+        }
+    }
+}
+
+// Where the responses the cache doesn't have come from.
+pub(crate) trait Upstream: Clone + Send + 'static {
+    // Sends `request` with `headers` in place of its own.
+    fn fetch(&self, request: &HttpRequest, headers: HeaderMap) -> io::Result<Fetched>;
+}
+
+// Answers a request from the cache where RFC 9111 allows it, otherwise from
+// `upstream`, storing what may be reused.
+// https://www.rfc-editor.org/rfc/rfc9111
+pub(crate) fn serve<U: Upstream>(config: &CacheConfig, request: &HttpRequest, upstream: U) -> io::Result<HttpResponse> {
+    let store = store::open(config);
+    let key = request.effective_uri().to_string();
+    let method = request.method();
+    if *method != HttpMethod::Get && *method != HttpMethod::Head {
+        let fetched = upstream.fetch(request, request.headers().clone())?;
+        // what the cache has may have just been changed
+        // https://www.rfc-editor.org/rfc/rfc9111#section-4.4
+        if !method.is_safe() && (fetched.status.is_success() || fetched.status.is_redirection()) {
+            store.purge(&Purge::Key(key));
+        }
+        return Ok(fetched.into_response().header("X-Cache", "MISS"));
+    }
+
+    let directives = CacheControl::parse(request.headers());
+    // ranges are left to the origin
+    if directives.no_store || request.header("range").is_some() {
+        return pass(request, &upstream);
+    }
+    loop {
+        let now = SystemTime::now();
+        let entry = store.lookup(&key, request.headers());
+        if let Some(entry) = &entry {
+            match state(entry, &directives, now) {
+                State::Fresh => return respond(&store, entry, request, now, "HIT").or_else(|_| pass(request, &upstream)),
+                State::Revalidate => {
+                    if let Some(claim) = store.claim(&key) {
+                        revalidate_in_background(store.clone(), claim, upstream.clone(), request.clone(), entry.clone());
+                    }
+                    return respond(&store, entry, request, now, "STALE").or_else(|_| pass(request, &upstream));
+                }
+                State::Stale => {}
+            }
+        } else if directives.only_if_cached {
+            return Ok(HttpResponse::new(HttpStatusCode::GatewayTimeout).header("X-Cache", "MISS"));
+        } else if *method == HttpMethod::Head || CONDITIONAL.iter().any(|name| request.header(name).is_some()) {
+            // nothing to answer those from, and they don't produce a
+            // response worth storing
+            return pass(request, &upstream);
+        }
+
+        match store.claim(&key) {
+            Some(claim) => return fetch(&store, claim, &upstream, request, entry.as_ref(), &directives),
+            // someone else is fetching, what they get may do for us too
+            None if store.wait(&key, COALESCE_TIMEOUT) => continue,
+            None => return pass(request, &upstream),
+        }
+    }
+}
+
+// A stored response as the admin endpoint shows it.
+pub(crate) struct Listing {
+    pub(crate) key: String,
+    pub(crate) status: HttpStatusCode,
+    // of the file, head included
+    pub(crate) size: u64,
+    pub(crate) age: Duration,
+    pub(crate) hits: u64,
+    pub(crate) tags: Vec<String>,
+}
+
+// Every response in the cache, the most recently used first.
+pub(crate) fn list(config: &CacheConfig) -> Vec<Listing> {
+    let now = SystemTime::now();
+    store::open(config).list().into_iter()
+        .map(|(entry, size, hits)| Listing {
+            age: policy::current_age(&entry.headers, entry.request_time, entry.response_time, now),
+            tags: entry.tags(),
+            status: entry.status,
+            key: entry.key,
+            size,
+            hits,
+        })
+        .collect()
+}
+
+// Removes responses from the cache, returns how many.
+pub(crate) fn purge(config: &CacheConfig, purge: &Purge) -> usize {
+    store::open(config).purge(purge)
+}
+
+// Fetches without involving the cache.
+fn pass<U: Upstream>(request: &HttpRequest, upstream: &U) -> io::Result<HttpResponse> {
+    Ok(upstream.fetch(request, request.headers().clone())?.into_response().header("X-Cache", "MISS"))
+}
+
+enum State {
+    // can be used as it is
+    Fresh,
+    // can be used once more while it is refreshed in the background
+    Revalidate,
+    // has to be validated with the origin first
+    Stale,
+}
+
+// https://www.rfc-editor.org/rfc/rfc9111#section-4.2
+fn state(entry: &Entry, directives: &CacheControl, now: SystemTime) -> State {
+    let stored = CacheControl::parse(&entry.headers);
+    if stored.no_cache || directives.no_cache {
+        return State::Stale;
+    }
+    let lifetime = policy::freshness_lifetime(entry.status, &entry.headers, entry.response_time);
+    let age = policy::current_age(&entry.headers, entry.request_time, entry.response_time, now);
+    if directives.max_age.is_some_and(|max_age| age > Duration::from_secs(max_age)) {
+        return State::Stale;
+    }
+    let min_fresh = Duration::from_secs(directives.min_fresh.unwrap_or(0));
+    if age.saturating_add(min_fresh) <= lifetime {
+        return State::Fresh;
+    }
+    if stored.must_revalidate {
+        return State::Stale;
+    }
+    let stale_for = age.saturating_sub(lifetime);
+    let tolerated = match directives.max_stale {
+        Some(None) => true,
+        Some(Some(max_stale)) => stale_for <= Duration::from_secs(max_stale),
+        None => false,
+    };
+    if tolerated {
+        State::Fresh
+    } else if stored.stale_while_revalidate.is_some_and(|window| stale_for <= Duration::from_secs(window)) {
+        State::Revalidate
+    } else {
+        State::Stale
+    }
+}
+
+// Whether a stored response too stale to use may still stand in for an
+// origin that failed.
+// https://www.rfc-editor.org/rfc/rfc5861#section-4
+fn usable_on_error(entry: &Entry, directives: &CacheControl, now: SystemTime) -> bool {
+    let stored = CacheControl::parse(&entry.headers);
+    let window = match directives.stale_if_error.or(stored.stale_if_error) {
+        Some(window) if !stored.must_revalidate => Duration::from_secs(window),
+        _ => return false,
+    };
+    let lifetime = policy::freshness_lifetime(entry.status, &entry.headers, entry.response_time);
+    let age = policy::current_age(&entry.headers, entry.request_time, entry.response_time, now);
+    age.saturating_sub(lifetime) <= window
+}
+
+// Gets the response from the origin, validating `entry` if there is one,
+// and stores it if it may be reused.
+// https://www.rfc-editor.org/rfc/rfc9111#section-4.3
+fn fetch<U: Upstream>(
+    store: &Arc<Store>,
+    claim: Claim,
+    upstream: &U,
+    request: &HttpRequest,
+    entry: Option<&Entry>,
+    directives: &CacheControl,
+) -> io::Result<HttpResponse> {
+    let mut headers = request.headers().clone();
+    for name in CONDITIONAL {
+        headers.remove(name);
+    }
+    if let Some(entry) = entry {
+        if let Some(etag) = entry.headers.get("etag") {
+            headers.append("If-None-Match", etag);
+        }
+        if let Some(modified) = entry.headers.get("last-modified") {
+            headers.append("If-Modified-Since", modified);
+        }
+    }
+
+    let request_time = SystemTime::now();
+    let fetched = upstream.fetch(request, headers);
+    let now = SystemTime::now();
+    let stale = entry.filter(|entry| usable_on_error(entry, directives, now));
+    let fetched = match (fetched, stale) {
+        (Ok(fetched), Some(stale)) if fetched.status.is_server_error() => return respond(store, stale, request, now, "STALE"),
+        (Err(err), Some(stale)) => {
+            log::debug!("serving {} stale: {}", stale.key, err);
+            return respond(store, stale, request, now, "STALE");
+        }
+        (fetched, _) => fetched?,
+    };
+
+    match entry {
+        Some(entry) if fetched.status == HttpStatusCode::NotModified => {
+            let entry = match store.freshen(entry, &fetched.headers, request_time, now) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    log::error!("failed to update {} in the cache: {}", entry.key, err);
+                    entry.clone()
+                }
+            };
+            respond(store, &entry, request, now, "HIT")
+        }
+        _ => Ok(fill(store, claim, request, fetched, request_time, now).header("X-Cache", "MISS")),
+    }
+}
+
+// Stores `fetched` as it is sent to the client, if it may be reused.
+fn fill(store: &Arc<Store>, claim: Claim, request: &HttpRequest, fetched: Fetched, request_time: SystemTime, response_time: SystemTime) -> HttpResponse {
+    if *request.method() != HttpMethod::Get || !policy::storable(request.headers(), fetched.status, &fetched.headers) {
+        return fetched.into_response();
+    }
+    let mut headers = fetched.headers.clone();
+    headers.remove("content-length");
+    let vary = store::vary_values(&headers, request.headers());
+    let entry = Entry::new(request.effective_uri().to_string(), vary, fetched.status, headers, request_time, response_time);
+    let writer = match store.writer(entry) {
+        Ok(writer) => writer,
+        Err(err) => {
+            log::error!("failed to store {}: {}", request.effective_uri(), err);
+            return fetched.into_response();
+        }
+    };
+    match fetched.body {
+        Some(body) => {
+            let body = Filling { body, writer, store: store.clone(), claim };
+            Fetched { body: Some(Box::new(body)), ..fetched }.into_response()
+        }
+        None => {
+            store.commit(writer);
+            fetched.into_response()
+        }
+    }
+}
+
+fn revalidate_in_background<U: Upstream>(store: Arc<Store>, claim: Claim, upstream: U, request: HttpRequest, entry: Entry) {
+    let spawned = thread::Builder::new().name("cache-revalidate".to_string()).spawn(move || {
+        let directives = CacheControl::parse(request.headers());
+        match fetch(&store, claim, &upstream, &request, Some(&entry), &directives) {
+            // storing happens as the body is written
+            Ok(response) => {
+                let _ = response.send(&mut io::sink());
+            }
+            Err(err) => log::debug!("failed to revalidate {}: {}", entry.key, err),
+        }
+    });
+    if let Err(err) = spawned {
+        log::error!("failed to start revalidating: {}", err);
+    }
+}
+
+// Answers from a stored response. `outcome` goes into X-Cache, HIT or
+// STALE.
+fn respond(store: &Store, entry: &Entry, request: &HttpRequest, now: SystemTime, outcome: &str) -> io::Result<HttpResponse> {
+    store.hit(entry);
+    let age = policy::current_age(&entry.headers, entry.request_time, entry.response_time, now);
+    if entry.status == HttpStatusCode::OK && not_modified(request, &entry.headers) {
+        let mut response = HttpResponse::new(HttpStatusCode::NotModified);
+        for (name, value) in entry.headers.iter() {
+            if NOT_MODIFIED_FIELDS.iter().any(|field| name.eq_ignore_ascii_case(field)) {
+                response = response.append_header(name, value);
+            }
+        }
+        return Ok(response.header("Age", &age.as_secs().to_string()).header("X-Cache", outcome));
+    }
+    let body = store.body(entry)?;
+    let mut response = HttpResponse::new(entry.status);
+    for (name, value) in entry.headers.iter() {
+        if !name.eq_ignore_ascii_case("age") {
+            response = response.append_header(name, value);
+        }
+    }
+    Ok(response.header("Age", &age.as_secs().to_string()).header("X-Cache", outcome).with_body(body))
+}
+
+// Whether the client's conditional GET is satisfied by what it already has.
+// https://www.rfc-editor.org/rfc/rfc9110#section-13.2.2
+fn not_modified(request: &HttpRequest, headers: &HeaderMap) -> bool {
+    let header = |name| headers.get(name).and_then(|value| std::str::from_utf8(value).ok());
+    if let Some(if_none_match) = request.headers().get_combined("if-none-match") {
+        // the weak comparison
+        let etag = match header("etag") {
+            Some(etag) => etag.trim_start_matches("W/"),
+            None => return false,
+        };
+        return String::from_utf8_lossy(&if_none_match)
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag);
+    }
+    let since = request.header_str("if-modified-since").and_then(parse_http_date);
+    let modified = header("last-modified").and_then(parse_http_date);
+    matches!((since, modified), (Some(since), Some(modified)) if modified <= since)
+}
+
+// The body of a response being stored, copied to the store as it goes to the
+// client. The response is stored once all of it went through, and whoever
+// waits for it is let go only then.
+struct Filling {
+    body: Box<dyn Body>,
+    writer: Writer,
+    store: Arc<Store>,
+    claim: Claim,
+}
+
+impl Body for Filling {
+    fn size(&self) -> Option<u64> {
+        self.body.size()
+    }
+
+    fn write(self: Box<Self>, stream: &mut dyn Write) -> Result<(), io::Error> {
+        let Filling { body, mut writer, store, claim } = *self;
+        body.write(&mut Tee { client: stream, writer: &mut writer })?;
+        store.commit(writer);
+        drop(claim);
+        Ok(())
+    }
+}
+
+struct Tee<'a> {
+    client: &'a mut dyn Write,
+    writer: &'a mut Writer,
+}
+
+impl Write for Tee<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.client.write(buf)?;
+        self.writer.write(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.client.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use crate::cache::{list, purge, serve, Fetched, Purge, Upstream};
+    use crate::config::CacheConfig;
+    use crate::headers::HeaderMap;
+    use crate::request::HttpRequest;
+    use crate::response::HttpResponse;
+    use crate::test_util::request;
+    use crate::util::HttpStatusCode;
+
+    // status, fields and body
+    type Canned = (u16, Vec<(&'static str, &'static str)>, &'static str);
+
+    // Answers with the next of its responses and records what it was sent.
+    #[derive(Clone)]
+    struct Scripted {
+        responses: Arc<Mutex<Vec<Canned>>>,
+        received: Arc<Mutex<Vec<HeaderMap>>>,
+        calls: Arc<AtomicUsize>,
+        delay: Duration,
+    }
+
+    impl Scripted {
+        fn new(responses: Vec<Canned>) -> Self {
+            Scripted {
+                responses: Arc::new(Mutex::new(responses)),
+                received: Arc::default(),
+                calls: Arc::default(),
+                delay: Duration::ZERO,
+            }
+        }
+    }
+
+    impl Upstream for Scripted {
+        fn fetch(&self, _request: &HttpRequest, headers: HeaderMap) -> io::Result<Fetched> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(self.delay);
+            self.received.lock().unwrap().push(headers);
+            let mut responses = self.responses.lock().unwrap();
+            if responses.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "origin down"));
+            }
+            let (status, fields, body) = responses.remove(0);
+            let mut headers = HeaderMap::new();
+            for (name, value) in fields {
+                headers.append(name, value);
+            }
+            Ok(Fetched { status: HttpStatusCode::from_code(status), headers, body: Some(Box::new(body.to_string())) })
+        }
+    }
+
+    fn get_request(uri: &str, fields: &str) -> HttpRequest {
+        request(&format!("GET {} HTTP/1.1\r\nHost: example.com\r\n{}\r\n", uri, fields))
+    }
+
+    fn body(response: HttpResponse) -> (HttpStatusCode, String) {
+        let status = *response.status();
+        let mut out = Vec::new();
+        response.send(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        (status, out.split_once("\r\n\r\n").unwrap().1.to_string())
+    }
+
+    #[test]
+    fn test_fresh_and_vary() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CacheConfig::new(dir.path());
+        let upstream = Scripted::new(vec![
+            (200, vec![("Cache-Control", "max-age=60"), ("Vary", "Accept-Language")], "hallo"),
+            (200, vec![("Cache-Control", "max-age=60"), ("Vary", "Accept-Language")], "hello"),
+        ]);
+        let german = "Accept-Language: de\r\n";
+        let english = "Accept-Language: en\r\n";
+        let get = |fields| body(serve(&config, &get_request("http://example.com/a", fields), upstream.clone()).unwrap());
+        assert_eq!(get(german).1, "hallo");
+        assert_eq!(get(german).1, "hallo");
+        assert_eq!(get(english).1, "hello");
+        assert_eq!(get(english).1, "hello");
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 2);
+
+        // a new store over the same directory has the same responses
+        let reopened = CacheConfig::new(dir.path().join("."));
+        let response = serve(&reopened, &get_request("http://example.com/a", german), upstream.clone()).unwrap();
+        assert_eq!(body(response).1, "hallo");
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_oversized_delta_seconds() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CacheConfig::new(dir.path());
+        let upstream = Scripted::new(vec![
+            (200, vec![("Cache-Control", "max-age=60"), ("Age", "5")], "one"),
+            (200, vec![("Cache-Control", "max-age=60"), ("Age", "18446744073709551616")], "two"),
+            (200, vec![("Cache-Control", "max-age=60")], "three"),
+        ]);
+        let get = |fields| body(serve(&config, &get_request("http://example.com/d", fields), upstream.clone()).unwrap());
+        assert_eq!(get("").1, "one");
+        // can't be fresh for that long, so it is fetched again
+        assert_eq!(get("Cache-Control: min-fresh=18446744073709551615\r\n").1, "two");
+        // and that one is older than any lifetime
+        assert_eq!(get("").1, "three");
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_revalidation() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CacheConfig::new(dir.path());
+        let upstream = Scripted::new(vec![
+            (200, vec![("Cache-Control", "no-cache"), ("ETag", "\"v1\"")], "one"),
+            (304, vec![("ETag", "\"v1\""), ("X-Checked", "yes")], ""),
+            (500, vec![], "oops"),
+        ]);
+        let get = |fields| serve(&config, &get_request("http://example.com/b", fields), upstream.clone()).unwrap();
+        assert_eq!(body(get("")).1, "one");
+        let validated = get("");
+        let mut out = Vec::new();
+        validated.send(&mut out).unwrap();
+        assert!(String::from_utf8_lossy(&out).contains("X-Checked: yes"));
+        assert!(String::from_utf8_lossy(&out).ends_with("\r\n\r\none"));
+        assert_eq!(upstream.received.lock().unwrap()[1].get("if-none-match"), Some(&b"\"v1\""[..]));
+
+        // the client's own condition is answered from the cache
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(body(get("Cache-Control: stale-if-error=60\r\nIf-None-Match: \"v1\"\r\n")).0, HttpStatusCode::NotModified);
+        // neither the 500 nor the origin being down reach the client while
+        // the stored response may stand in
+        assert_eq!(body(get("Cache-Control: stale-if-error=60\r\n")).1, "one");
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_coalescing() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(CacheConfig::new(dir.path()));
+        let mut upstream = Scripted::new(vec![(200, vec![("Cache-Control", "max-age=60")], "slow")]);
+        upstream.delay = Duration::from_millis(200);
+        let clients: Vec<_> = (0..4).map(|_| {
+            let (config, upstream) = (config.clone(), upstream.clone());
+            thread::spawn(move || body(serve(&config, &get_request("http://example.com/c", ""), upstream).unwrap()).1)
+        }).collect();
+        for client in clients {
+            assert_eq!(client.join().unwrap(), "slow");
+        }
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_purge() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CacheConfig::new(dir.path());
+        let tagged = |tags| (200, vec![("Cache-Control", "max-age=60"), ("Cache-Tag", tags)], "body");
+        let upstream = Scripted::new(vec![tagged("blog, home"), tagged("blog"), tagged("shop"), tagged("shop")]);
+        let get = |path: &str| {
+            let response = serve(&config, &get_request(&format!("http://example.com{}", path), ""), upstream.clone()).unwrap();
+            let mut out = Vec::new();
+            response.send(&mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            out.split("X-Cache: ").nth(1).and_then(|rest| rest.split("\r\n").next()).unwrap().to_string()
+        };
+        assert_eq!(get("/blog/1"), "MISS");
+        assert_eq!(get("/blog/1"), "HIT");
+        assert_eq!(get("/blog/2"), "MISS");
+        assert_eq!(get("/shop/1"), "MISS");
+
+        let listed = list(&config);
+        assert_eq!(listed.len(), 3);
+        assert_eq!(listed[2].key, "http://example.com/blog/1");
+        assert_eq!(listed[2].hits, 1);
+        assert_eq!(listed[2].tags, ["blog", "home"]);
+
+        assert_eq!(purge(&config, &Purge::Tag("blog".to_string())), 2);
+        assert_eq!(purge(&config, &Purge::Prefix("http://example.com/shop/".to_string())), 1);
+        assert_eq!(purge(&config, &Purge::Key("http://example.com/shop/1".to_string())), 0);
+        assert_eq!(get("/shop/1"), "MISS");
+    }
+
+    #[test]
+    fn test_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = CacheConfig::new(dir.path());
+        config.max_size = 250;
+        let response = (200, vec![("Cache-Control", "max-age=60")], "0123456789");
+        let upstream = Scripted::new(vec![response.clone(), response.clone(), response.clone(), response]);
+        let get = |path: &str| body(serve(&config, &get_request(&format!("http://example.com/{}", path), ""), upstream.clone()).unwrap());
+        get("one");
+        get("two");
+        get("one");
+        get("three");
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 3);
+        // "two" was used least recently
+        get("one");
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 3);
+        get("two");
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,222 @@
+use std::time::{Duration, SystemTime};
+use crate::headers::HeaderMap;
+use crate::util::{parse_http_date, HttpStatusCode};
+
+// Heuristic freshness is this fraction of the time since the last
+// modification, but no more than a day.
+// https://www.rfc-editor.org/rfc/rfc9111#section-4.2.2
+const HEURISTIC_FRACTION: u32 = 10;
+const MAX_HEURISTIC_LIFETIME: Duration = Duration::from_secs(24 * 3600);
+
+// Statuses that may be cached without explicit freshness information.
+// https://www.rfc-editor.org/rfc/rfc9110#section-15.1
+const HEURISTICALLY_CACHEABLE: [u16; 10] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 501];
+
+// Larger delta-seconds, even those too large for a u64, count as this many.
+// https://www.rfc-editor.org/rfc/rfc9111#section-1.2.2
+const MAX_DELTA_SECONDS: u64 = 1 << 31;
+
+// The directives of the Cache-Control fields a shared cache acts on.
+// https://www.rfc-editor.org/rfc/rfc9111#section-5.2
+#[derive(Debug, Default)]
+pub(super) struct CacheControl {
+    pub(super) no_store: bool,
+    pub(super) no_cache: bool,
+    pub(super) private: bool,
+    pub(super) public: bool,
+    pub(super) must_revalidate: bool,
+    pub(super) only_if_cached: bool,
+    pub(super) max_age: Option<u64>,
+    pub(super) s_maxage: Option<u64>,
+    // `Some(None)` when the client takes a response stale by any amount
+    pub(super) max_stale: Option<Option<u64>>,
+    pub(super) min_fresh: Option<u64>,
+    // https://www.rfc-editor.org/rfc/rfc5861
+    pub(super) stale_while_revalidate: Option<u64>,
+    pub(super) stale_if_error: Option<u64>,
+}
+
+impl CacheControl {
+    pub(super) fn parse(headers: &HeaderMap) -> Self {
+        let mut directives = CacheControl::default();
+        let value = match headers.get_combined("cache-control") {
+            Some(value) => String::from_utf8_lossy(&value).into_owned(),
+            None => {
+                // HTTP/1.0 clients only send this
+                directives.no_cache = headers.has_token("pragma", "no-cache");
+                return directives;
+            }
+        };
+        for directive in value.split(',') {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name.trim(), Some(argument.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let seconds = argument.and_then(delta_seconds);
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                // the qualified forms name fields, which are treated like
+                // the whole response
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "public" => directives.public = true,
+                "must-revalidate" | "proxy-revalidate" => directives.must_revalidate = true,
+                "only-if-cached" => directives.only_if_cached = true,
+                "max-age" => directives.max_age = seconds,
+                "s-maxage" => directives.s_maxage = seconds,
+                "max-stale" => directives.max_stale = Some(seconds),
+                "min-fresh" => directives.min_fresh = seconds,
+                "stale-while-revalidate" => directives.stale_while_revalidate = seconds,
+                "stale-if-error" => directives.stale_if_error = seconds,
+                _ => {}
+            }
+        }
+        directives
+    }
+}
+
+// Whether a shared cache may store the response to a GET.
+// https://www.rfc-editor.org/rfc/rfc9111#section-3
+pub(super) fn storable(request_headers: &HeaderMap, status: HttpStatusCode, headers: &HeaderMap) -> bool {
+    let request = CacheControl::parse(request_headers);
+    let response = CacheControl::parse(headers);
+    if request.no_store || response.no_store || response.private || status.is_informational() {
+        return false;
+    }
+    // "*" can never be matched by another request
+    if headers.get_all("vary").any(|value| value.split(|&b| b == b',').any(|name| name.trim_ascii() == b"*")) {
+        return false;
+    }
+    // https://www.rfc-editor.org/rfc/rfc9111#section-3.5
+    if request_headers.get("authorization").is_some()
+        && !(response.public || response.s_maxage.is_some() || response.must_revalidate)
+    {
+        return false;
+    }
+    response.public
+        || response.max_age.is_some()
+        || response.s_maxage.is_some()
+        || headers.get("expires").is_some()
+        || HEURISTICALLY_CACHEABLE.contains(&status.code())
+}
+
+// How long a response stays fresh after it was generated.
+// https://www.rfc-editor.org/rfc/rfc9111#section-4.2.1
+pub(super) fn freshness_lifetime(status: HttpStatusCode, headers: &HeaderMap, response_time: SystemTime) -> Duration {
+    let directives = CacheControl::parse(headers);
+    if let Some(seconds) = directives.s_maxage.or(directives.max_age) {
+        return Duration::from_secs(seconds);
+    }
+    let date = date(headers).unwrap_or(response_time);
+    if let Some(expires) = headers.get("expires") {
+        // an invalid date means already expired
+        let expires = std::str::from_utf8(expires).ok().and_then(parse_http_date);
+        return expires.and_then(|expires| expires.duration_since(date).ok()).unwrap_or_default();
+    }
+    if !directives.public && !HEURISTICALLY_CACHEABLE.contains(&status.code()) {
+        return Duration::ZERO;
+    }
+    let last_modified = headers.get("last-modified")
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .and_then(parse_http_date);
+    match last_modified.and_then(|modified| date.duration_since(modified).ok()) {
+        Some(unchanged_for) => (unchanged_for / HEURISTIC_FRACTION).min(MAX_HEURISTIC_LIFETIME),
+        None => Duration::ZERO,
+    }
+}
+
+// How old the stored response is now, counting the time it spent in caches
+// before this one.
+// https://www.rfc-editor.org/rfc/rfc9111#section-4.2.3
+pub(super) fn current_age(headers: &HeaderMap, request_time: SystemTime, response_time: SystemTime, now: SystemTime) -> Duration {
+    let apparent_age = date(headers)
+        .and_then(|date| response_time.duration_since(date).ok())
+        .unwrap_or_default();
+    let age_value = headers.get("age")
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .and_then(|value| delta_seconds(value.trim()))
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+    let response_delay = response_time.duration_since(request_time).unwrap_or_default();
+    let corrected_initial_age = apparent_age.max(age_value.saturating_add(response_delay));
+    let resident_time = now.duration_since(response_time).unwrap_or_default();
+    corrected_initial_age.saturating_add(resident_time)
+}
+
+// A number of seconds as sent in a field, capped so that adding a few of
+// them up can't overflow.
+fn delta_seconds(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(value.parse::<u64>().map_or(MAX_DELTA_SECONDS, |seconds| seconds.min(MAX_DELTA_SECONDS)))
+}
+
+fn date(headers: &HeaderMap) -> Option<SystemTime> {
+    headers.get("date")
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .and_then(parse_http_date)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+    use crate::cache::policy::{current_age, delta_seconds, freshness_lifetime, storable, CacheControl};
+    use crate::headers::HeaderMap;
+    use crate::util::{format_http_date, HttpStatusCode};
+
+    fn headers(fields: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in fields {
+            headers.append(*name, *value);
+        }
+        headers
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let directives = CacheControl::parse(&headers(&[
+            ("Cache-Control", "public, max-age=\"60\""),
+            ("cache-control", "stale-while-revalidate=30, max-stale, no-cache=\"Set-Cookie\""),
+        ]));
+        assert!(directives.public && directives.no_cache);
+        assert_eq!(directives.max_age, Some(60));
+        assert_eq!(directives.stale_while_revalidate, Some(30));
+        assert_eq!(directives.max_stale, Some(None));
+        assert!(CacheControl::parse(&headers(&[("Pragma", "no-cache")])).no_cache);
+    }
+
+    #[test]
+    fn test_freshness() {
+        let now = SystemTime::now();
+        let date = format_http_date(now);
+        let empty = HeaderMap::new();
+        assert!(storable(&empty, HttpStatusCode::OK, &empty));
+        assert!(!storable(&empty, HttpStatusCode::Created, &empty));
+        assert!(!storable(&empty, HttpStatusCode::OK, &headers(&[("Cache-Control", "private")])));
+        assert!(!storable(&empty, HttpStatusCode::OK, &headers(&[("Vary", "Accept, *")])));
+        assert!(!storable(&headers(&[("Authorization", "Basic eDp5")]), HttpStatusCode::OK, &empty));
+
+        let explicit = headers(&[("Date", &date), ("Cache-Control", "max-age=10, s-maxage=20"), ("Expires", "0")]);
+        assert_eq!(freshness_lifetime(HttpStatusCode::OK, &explicit, now), Duration::from_secs(20));
+        let expires = headers(&[("Date", &date), ("Expires", &format_http_date(now + Duration::from_secs(90)))]);
+        assert_eq!(freshness_lifetime(HttpStatusCode::OK, &expires, now), Duration::from_secs(90));
+        let invalid = headers(&[("Expires", "0")]);
+        assert_eq!(freshness_lifetime(HttpStatusCode::OK, &invalid, now), Duration::ZERO);
+
+        let modified = format_http_date(now - Duration::from_secs(1000));
+        let heuristic = headers(&[("Date", &date), ("Last-Modified", &modified)]);
+        assert_eq!(freshness_lifetime(HttpStatusCode::OK, &heuristic, now), Duration::from_secs(100));
+        assert_eq!(freshness_lifetime(HttpStatusCode::Created, &heuristic, now), Duration::ZERO);
+
+        let aged = headers(&[("Age", "30")]);
+        let age = current_age(&aged, now - Duration::from_secs(2), now, now + Duration::from_secs(5));
+        assert_eq!(age, Duration::from_secs(37));
+
+        let ancient = headers(&[("Age", "184467440737095516150")]);
+        let age = current_age(&ancient, now - Duration::from_secs(2), now, now + Duration::from_secs(5));
+        assert_eq!(age, Duration::from_secs((1 << 31) + 7));
+        assert_eq!(delta_seconds("18446744073709551615"), Some(1 << 31));
+        assert_eq!(delta_seconds("+5"), None);
+    }
+}
@@ -0,0 +1,491 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use lazy_static::lazy_static;
+use tempfile::NamedTempFile;
+use uuid::Uuid;
+use crate::config::CacheConfig;
+use crate::headers::HeaderMap;
+use crate::response::Body;
+use crate::util::HttpStatusCode;
+
+// First line of every stored response, changed whenever the layout does.
+const MAGIC: &[u8] = b"HTTP-CACHE 1";
+// Files being written, removed when found on startup.
+const TEMP_PREFIX: &str = ".tmp-";
+
+lazy_static! {
+    // One store per directory, opened on first use.
+    static ref STORES: Mutex<HashMap<PathBuf, Arc<Store>>> = Mutex::new(HashMap::new());
+}
+
+pub(super) fn open(config: &CacheConfig) -> Arc<Store> {
+    let mut stores = STORES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    stores.entry(config.dir.clone()).or_insert_with(|| Arc::new(Store::load(config))).clone()
+}
+
+// A stored response. Everything but the body is kept in memory, the body
+// follows this same head in the entry's file.
+#[derive(Clone)]
+pub(super) struct Entry {
+    // the effective URI of the request
+    pub(super) key: String,
+    // the request's values of the fields Vary names, names lowercased
+    pub(super) vary: Vec<(String, String)>,
+    pub(super) status: HttpStatusCode,
+    // without Content-Length, which comes from the body
+    pub(super) headers: HeaderMap,
+    pub(super) request_time: SystemTime,
+    pub(super) response_time: SystemTime,
+    file: String,
+    body_offset: u64,
+    body_len: u64,
+}
+
+impl Entry {
+    pub(super) fn new(
+        key: String,
+        vary: Vec<(String, String)>,
+        status: HttpStatusCode,
+        headers: HeaderMap,
+        request_time: SystemTime,
+        response_time: SystemTime,
+    ) -> Self {
+        let mut entry = Entry {
+            key,
+            vary,
+            status,
+            headers,
+            request_time,
+            response_time,
+            file: Uuid::new_v4().simple().to_string(),
+            body_offset: 0,
+            body_len: 0,
+        };
+        entry.body_offset = entry.head().len() as u64;
+        entry
+    }
+
+    fn size(&self) -> u64 {
+        self.body_offset + self.body_len
+    }
+
+    fn head(&self) -> Vec<u8> {
+        let seconds = |time: SystemTime| time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+        let mut head = MAGIC.to_vec();
+        head.extend_from_slice(format!(
+            "\n{}\n{} {}\n{}\n{}\n",
+            self.key,
+            seconds(self.request_time),
+            seconds(self.response_time),
+            self.status.code(),
+            self.vary.len()
+        ).as_bytes());
+        for (name, value) in &self.vary {
+            head.extend_from_slice(format!("{}: {}\n", name, value).as_bytes());
+        }
+        for (name, value) in self.headers.iter() {
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.push(b'\n');
+        }
+        head.push(b'\n');
+        head
+    }
+
+    // Reads back what `head` wrote, `None` if the file isn't a stored
+    // response.
+    fn parse(file: String, reader: &mut impl BufRead, file_len: u64) -> Option<Entry> {
+        let mut offset = 0;
+        let mut line = || -> Option<Vec<u8>> {
+            let mut line = Vec::new();
+            reader.read_until(b'\n', &mut line).ok()?;
+            offset += line.len() as u64;
+            line.pop().filter(|&end| end == b'\n')?;
+            Some(line)
+        };
+        let text = |line: Vec<u8>| String::from_utf8(line).ok();
+        let field = |line: Vec<u8>| -> Option<(String, Vec<u8>)> {
+            let colon = line.iter().position(|&b| b == b':')?;
+            let name = String::from_utf8(line[..colon].to_vec()).ok()?;
+            Some((name, line.get(colon + 2..).unwrap_or(&[]).to_vec()))
+        };
+
+        if line()? != MAGIC {
+            return None;
+        }
+        let key = text(line()?)?;
+        let times = text(line()?)?;
+        let (request_time, response_time) = times.split_once(' ')?;
+        let time = |seconds: &str| seconds.parse().ok().map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds));
+        let (request_time, response_time) = (time(request_time)?, time(response_time)?);
+        let status = HttpStatusCode::from_code(text(line()?)?.parse().ok()?);
+        let vary_count: usize = text(line()?)?.parse().ok()?;
+        let mut vary = Vec::with_capacity(vary_count);
+        for _ in 0..vary_count {
+            let (name, value) = field(line()?)?;
+            vary.push((name, String::from_utf8(value).ok()?));
+        }
+        let mut headers = HeaderMap::new();
+        loop {
+            let line = line()?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = field(line)?;
+            headers.append(name, value);
+        }
+        Some(Entry {
+            key,
+            vary,
+            status,
+            headers,
+            request_time,
+            response_time,
+            file,
+            body_offset: offset,
+            body_len: file_len.checked_sub(offset)?,
+        })
+    }
+
+    fn same_variant(&self, other: &Entry) -> bool {
+        self.key == other.key && self.vary == other.vary
+    }
+
+    // The tags of the Cache-Tag field, which purges can go by.
+    pub(super) fn tags(&self) -> Vec<String> {
+        self.headers.get_all("cache-tag")
+            .flat_map(|value| value.split(|&b| b == b','))
+            .map(|tag| String::from_utf8_lossy(tag.trim_ascii()).into_owned())
+            .filter(|tag| !tag.is_empty())
+            .collect()
+    }
+}
+
+// Which stored responses a purge removes.
+pub(crate) enum Purge {
+    // every variant of the key
+    Key(String),
+    // every key starting with the prefix
+    Prefix(String),
+    // every response with the tag in Cache-Tag
+    Tag(String),
+}
+
+impl Purge {
+    fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            Purge::Key(key) => entry.key == *key,
+            Purge::Prefix(prefix) => entry.key.starts_with(prefix.as_str()),
+            Purge::Tag(tag) => entry.tags().contains(tag),
+        }
+    }
+}
+
+// The request's values of the fields `headers`, those of a response, name
+// in Vary.
+pub(super) fn vary_values(headers: &HeaderMap, request_headers: &HeaderMap) -> Vec<(String, String)> {
+    let mut names: Vec<String> = headers.get_all("vary")
+        .flat_map(|value| value.split(|&b| b == b','))
+        .map(|name| String::from_utf8_lossy(name.trim_ascii()).to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names.into_iter()
+        .map(|name| {
+            let value = request_headers.get_combined(&name).map(|value| String::from_utf8_lossy(&value).trim().to_string());
+            (name, value.unwrap_or_default())
+        })
+        .collect()
+}
+
+struct Indexed {
+    entry: Entry,
+    last_used: SystemTime,
+    // since the store was loaded
+    hits: u64,
+}
+
+#[derive(Default)]
+struct State {
+    // the variants stored for each key
+    entries: HashMap<String, Vec<Indexed>>,
+    size: u64,
+    // keys a response is being fetched for
+    filling: HashSet<String>,
+}
+
+// Stored responses in a directory, one file each, kept below a total size by
+// evicting the least recently used. When a response was last used is kept
+// as the modification time of its file, so the order survives restarts.
+pub(super) struct Store {
+    dir: PathBuf,
+    max_size: u64,
+    max_entry_size: u64,
+    state: Mutex<State>,
+    // signalled whenever a fill ends
+    filled: Condvar,
+}
+
+impl Store {
+    fn load(config: &CacheConfig) -> Store {
+        let store = Store {
+            dir: config.dir.clone(),
+            max_size: config.max_size,
+            max_entry_size: config.max_entry_size,
+            state: Mutex::new(State::default()),
+            filled: Condvar::new(),
+        };
+        if let Err(err) = fs::create_dir_all(&store.dir).and_then(|()| store.scan()) {
+            log::error!("cache {} unusable: {}", store.dir.display(), err);
+        }
+        store
+    }
+
+    fn scan(&self) -> io::Result<()> {
+        let mut state = self.state();
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            let name = dir_entry.file_name().to_string_lossy().into_owned();
+            let path = dir_entry.path();
+            let loaded = if name.starts_with(TEMP_PREFIX) {
+                None
+            } else {
+                File::open(&path).and_then(|file| {
+                    let metadata = file.metadata()?;
+                    let entry = Entry::parse(name, &mut BufReader::new(file), metadata.len());
+                    Ok(entry.map(|entry| Indexed { entry, last_used: metadata.modified().unwrap_or(UNIX_EPOCH), hits: 0 }))
+                })?
+            };
+            match loaded {
+                Some(indexed) => {
+                    state.size += indexed.entry.size();
+                    state.entries.entry(indexed.entry.key.clone()).or_default().push(indexed);
+                }
+                // left over from a crash, or not ours
+                None => {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        self.evict(&mut state);
+        Ok(())
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn path(&self, entry: &Entry) -> PathBuf {
+        self.dir.join(&entry.file)
+    }
+
+    // The variant of `key` stored for a request with `request_headers`.
+    pub(super) fn lookup(&self, key: &str, request_headers: &HeaderMap) -> Option<Entry> {
+        let mut state = self.state();
+        let indexed = state.entries.get_mut(key)?.iter_mut().find(|indexed| {
+            let wanted = vary_values(&indexed.entry.headers, request_headers);
+            wanted == indexed.entry.vary
+        })?;
+        indexed.last_used = SystemTime::now();
+        let entry = indexed.entry.clone();
+        drop(state);
+        if let Ok(file) = File::options().write(true).open(self.path(&entry)) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(entry)
+    }
+
+    // Counts a response served from the store.
+    pub(super) fn hit(&self, entry: &Entry) {
+        let mut state = self.state();
+        let indexed = state.entries.get_mut(&entry.key)
+            .and_then(|variants| variants.iter_mut().find(|indexed| indexed.entry.file == entry.file));
+        if let Some(indexed) = indexed {
+            indexed.hits += 1;
+        }
+    }
+
+    // Every stored response with its size and hit count, the most recently
+    // used first.
+    pub(super) fn list(&self) -> Vec<(Entry, u64, u64)> {
+        let state = self.state();
+        let mut indexed: Vec<&Indexed> = state.entries.values().flatten().collect();
+        indexed.sort_by_key(|indexed| std::cmp::Reverse(indexed.last_used));
+        indexed.into_iter().map(|indexed| (indexed.entry.clone(), indexed.entry.size(), indexed.hits)).collect()
+    }
+
+    // Removes what `purge` matches, returns how many responses that were.
+    pub(super) fn purge(&self, purge: &Purge) -> usize {
+        let mut state = self.state();
+        let before: usize = state.entries.values().map(Vec::len).sum();
+        self.forget(&mut state, |stored| purge.matches(stored));
+        before - state.entries.values().map(Vec::len).sum::<usize>()
+    }
+
+    // Makes the caller the one fetching for `key`, `None` if someone else
+    // already is. Others wait for the claim to be dropped.
+    pub(super) fn claim(self: &Arc<Self>, key: &str) -> Option<Claim> {
+        let mut state = self.state();
+        if !state.filling.insert(key.to_string()) {
+            return None;
+        }
+        Some(Claim { store: self.clone(), key: key.to_string() })
+    }
+
+    // Waits until nobody is fetching for `key`, `false` if that took longer
+    // than `timeout`.
+    pub(super) fn wait(&self, key: &str, timeout: Duration) -> bool {
+        let state = self.state();
+        let waited = self.filled.wait_timeout_while(state, timeout, |state| state.filling.contains(key));
+        let (_state, result) = waited.unwrap_or_else(|poisoned| poisoned.into_inner());
+        !result.timed_out()
+    }
+
+    // Starts storing `entry`, whose body is then written to the writer.
+    pub(super) fn writer(&self, entry: Entry) -> io::Result<Writer> {
+        let mut file = tempfile::Builder::new().prefix(TEMP_PREFIX).tempfile_in(&self.dir)?;
+        file.write_all(&entry.head())?;
+        Ok(Writer { file: Some(file), entry, limit: self.max_entry_size })
+    }
+
+    // Stores what went through the writer unless it was abandoned, replacing
+    // the variant stored so far.
+    pub(super) fn commit(&self, writer: Writer) {
+        let Writer { file, entry, .. } = writer;
+        let Some(file) = file else { return };
+        if let Err(err) = file.persist(self.path(&entry)) {
+            log::error!("failed to store {}: {}", entry.key, err);
+            return;
+        }
+        let mut state = self.state();
+        self.forget(&mut state, |stored| stored.same_variant(&entry));
+        state.size += entry.size();
+        let key = entry.key.clone();
+        state.entries.entry(key).or_default().push(Indexed { entry, last_used: SystemTime::now(), hits: 0 });
+        self.evict(&mut state);
+    }
+
+    // Takes the fields of a 304 into a stored response, which is fresh again.
+    // https://www.rfc-editor.org/rfc/rfc9111#section-4.3.4
+    pub(super) fn freshen(&self, entry: &Entry, fields: &HeaderMap, request_time: SystemTime, response_time: SystemTime) -> io::Result<Entry> {
+        let mut headers = entry.headers.clone();
+        for (name, _) in fields.iter() {
+            if !name.eq_ignore_ascii_case("content-length") {
+                headers.remove(name);
+            }
+        }
+        for (name, value) in fields.iter() {
+            if !name.eq_ignore_ascii_case("content-length") {
+                headers.append(name, value);
+            }
+        }
+        let updated = Entry::new(entry.key.clone(), entry.vary.clone(), entry.status, headers, request_time, response_time);
+        let mut writer = self.writer(updated)?;
+        let mut body = self.body(entry)?;
+        if let Some(file) = writer.file.as_mut() {
+            io::copy(&mut (&mut body.file).take(body.len), file)?;
+        }
+        writer.entry.body_len = body.len;
+        let updated = writer.entry.clone();
+        self.commit(writer);
+        Ok(updated)
+    }
+
+    // The body of a stored response.
+    pub(super) fn body(&self, entry: &Entry) -> io::Result<CachedBody> {
+        let mut file = File::open(self.path(entry))?;
+        if file.metadata()?.len() != entry.size() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "stored response changed"));
+        }
+        file.seek(SeekFrom::Start(entry.body_offset))?;
+        Ok(CachedBody { file, len: entry.body_len })
+    }
+
+    fn forget(&self, state: &mut State, matching: impl Fn(&Entry) -> bool) {
+        let mut removed = Vec::new();
+        for variants in state.entries.values_mut() {
+            variants.retain(|indexed| {
+                let keep = !matching(&indexed.entry);
+                if !keep {
+                    removed.push((self.path(&indexed.entry), indexed.entry.size()));
+                }
+                keep
+            });
+        }
+        state.entries.retain(|_, variants| !variants.is_empty());
+        for (path, size) in removed {
+            state.size -= size;
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    fn evict(&self, state: &mut State) {
+        while state.size > self.max_size {
+            let oldest = state.entries.values()
+                .flatten()
+                .min_by_key(|indexed| indexed.last_used)
+                .map(|indexed| indexed.entry.file.clone());
+            match oldest {
+                Some(file) => self.forget(state, |stored| stored.file == file),
+                None => break,
+            }
+        }
+    }
+}
+
+// The right to fetch for a key, given up when dropped.
+pub(super) struct Claim {
+    store: Arc<Store>,
+    key: String,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.store.state().filling.remove(&self.key);
+        self.store.filled.notify_all();
+    }
+}
+
+// Where a body goes while it is being stored. A body growing past the limit
+// or failing to write is abandoned, the response is then not stored.
+pub(super) struct Writer {
+    file: Option<NamedTempFile>,
+    entry: Entry,
+    limit: u64,
+}
+
+impl Writer {
+    pub(super) fn write(&mut self, data: &[u8]) {
+        let Some(file) = self.file.as_mut() else { return };
+        self.entry.body_len += data.len() as u64;
+        if self.entry.body_len > self.limit || file.write_all(data).is_err() {
+            self.file = None;
+        }
+    }
+}
+
+// The body of a stored response, read from its file.
+pub(super) struct CachedBody {
+    file: File,
+    len: u64,
+}
+
+impl Body for CachedBody {
+    fn size(&self) -> Option<u64> {
+        Some(self.len)
+    }
+
+    fn write(self: Box<Self>, stream: &mut dyn Write) -> Result<(), io::Error> {
+        let CachedBody { file, len } = *self;
+        if io::copy(&mut file.take(len), stream)? < len {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "stored response cut short"));
+        }
+        Ok(())
+    }
+}
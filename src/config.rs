@@ -0,0 +1,454 @@
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Selects how accepted connections are driven.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// Every connection occupies a pool thread for its whole lifetime.
+    Threaded,
+    /// `threads` epoll event loops multiplex all connections and only hand
+    /// complete requests to the worker pool.
+    EventLoop { threads: usize },
+    /// A single io_uring drives accept, reads and writes, serving files with
+    /// splice. Requests are still handled on the worker pool.
+    #[cfg(feature = "io-uring")]
+    IoUring { entries: u32 },
+}
+
+/// What to do with a header field value continued on the next line
+/// (obs-fold), a deprecated form RFC 9112 lets a server either reject or
+/// unfold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ObsFold {
+    /// Answer with `400 Bad Request`.
+    #[default]
+    Reject,
+    /// Replace each fold with a single space.
+    Replace,
+}
+
+/// What a static location lets clients change below its root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteAccess {
+    #[default]
+    ReadOnly,
+    /// `PUT` replaces files and `DELETE` removes them. Uploading into a
+    /// missing directory is answered with `409 Conflict`.
+    Write,
+    /// Like `Write`, but missing parent directories are created.
+    WriteCreateDirs,
+}
+
+/// What to do with a `%2F` in a request path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncodedSlash {
+    /// Answer with `400 Bad Request`.
+    #[default]
+    Reject,
+    /// Treat it as a path separator.
+    Decode,
+    /// Leave it encoded, so it stays part of the segment it is in.
+    Keep,
+}
+
+/// How request paths are canonicalized before routing. Percent-decoding and
+/// removing `.` and `..` segments always happen, an encoded NUL is always
+/// refused.
+#[derive(Debug, Clone, Copy)]
+pub struct PathNormalization {
+    /// Collapse runs of `/` into one.
+    pub merge_slashes: bool,
+    pub encoded_slash: EncodedSlash,
+}
+
+impl Default for PathNormalization {
+    fn default() -> Self {
+        PathNormalization {
+            merge_slashes: true,
+            encoded_slash: EncodedSlash::default(),
+        }
+    }
+}
+
+/// Bounds on how long a client may take. Expiring while a request is being
+/// received is answered with `408 Request Timeout`, everything else closes the
+/// connection silently.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Time allowed to receive the complete request line and headers,
+    /// counted from the first byte. Also bounds how long a fresh connection
+    /// may stay silent.
+    pub header: Duration,
+    /// Longest gap allowed between two reads of a request body.
+    pub body: Duration,
+    /// How long a connection may stay idle between requests.
+    pub keep_alive: Duration,
+    /// Longest a response write may stall.
+    pub send: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            header: Duration::from_secs(20),
+            body: Duration::from_secs(20),
+            keep_alive: Duration::from_secs(60),
+            send: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Bounds on the size of a request, enforced while it is being parsed.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Longest accepted request line, answered with `414 URI Too Long`.
+    pub request_line: usize,
+    /// Longest accepted single header line, answered with
+    /// `431 Request Header Fields Too Large` like the two limits below.
+    pub header_size: usize,
+    /// Longest accepted header section as a whole.
+    pub headers_total: usize,
+    /// Most header fields accepted in one request.
+    pub header_count: usize,
+    /// Largest accepted body, answered with `413 Content Too Large`.
+    pub body_size: usize,
+    /// Largest urlencoded form body that is decoded, answered with
+    /// `413 Content Too Large` like the limit below.
+    pub form_size: usize,
+    /// Most fields decoded from one urlencoded form.
+    pub form_fields: usize,
+    /// Largest multipart/form-data body that is parsed, answered with
+    /// `413 Content Too Large` like the two limits below. Such bodies are
+    /// split into parts as they arrive and may be larger than `body_size`,
+    /// only the parts are kept of those.
+    pub multipart_size: usize,
+    /// Largest single part of a multipart body.
+    pub part_size: usize,
+    /// Most parts in one multipart body.
+    pub multipart_parts: usize,
+    /// Parts growing past this are spooled to a temporary file instead of
+    /// being kept in memory.
+    pub part_memory: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            request_line: 8 * 1024,
+            header_size: 8 * 1024,
+            headers_total: 32 * 1024,
+            header_count: 100,
+            body_size: 1024 * 1024,
+            form_size: 64 * 1024,
+            form_fields: 1000,
+            multipart_size: 64 * 1024 * 1024,
+            part_size: 64 * 1024 * 1024,
+            multipart_parts: 100,
+            part_memory: 64 * 1024,
+        }
+    }
+}
+
+/// What serves the requests routed to a location.
+pub enum Handler {
+    /// Files below `root`. The full request path is appended to it.
+    Static { root: PathBuf },
+    /// Lists the responses in the forward proxy's cache on `GET` and removes
+    /// them on `PURGE` (or `POST`) with a `key`, `prefix` or `tag` query
+    /// parameter, the latter matching the `Cache-Tag` of responses. A `POST`
+    /// may send the parameter as an urlencoded form instead.
+    CacheAdmin { auth: BasicAuth },
+}
+
+/// Requests are routed to the location with the longest matching prefix.
+pub struct Location {
+    pub prefix: String,
+    pub handler: Handler,
+    /// Checked before the server wide error pages.
+    pub error_pages: Vec<ErrorPage>,
+    /// Whether a static location accepts `PUT` and `DELETE`, and
+    /// `multipart/form-data` uploads on `POST` that save the files sent into
+    /// the directory the path names.
+    pub write_access: WriteAccess,
+    /// Answer the WebDAV methods on a static location. Those changing files
+    /// also need `write_access`.
+    pub webdav: bool,
+}
+
+impl Location {
+    pub fn new(prefix: &str, handler: Handler) -> Self {
+        Location {
+            prefix: prefix.to_string(),
+            handler,
+            error_pages: Vec::new(),
+            write_access: WriteAccess::default(),
+            webdav: false,
+        }
+    }
+}
+
+/// How an error response is replaced.
+pub enum ErrorPageAction {
+    /// Serve this file as the body.
+    File(PathBuf),
+    /// Handle the request again as a `GET` for this path.
+    Internal(String),
+    /// Redirect the client to this URL with `302 Found`.
+    Redirect(String),
+}
+
+pub struct ErrorPage {
+    /// The status codes this page replaces, e.g. `404..=404` or `500..=599`.
+    pub statuses: RangeInclusive<u16>,
+    pub action: ErrorPageAction,
+    /// Answer with this status instead of the original one (or the 302 of a
+    /// redirect).
+    pub status_override: Option<u16>,
+}
+
+impl ErrorPage {
+    pub fn new(statuses: RangeInclusive<u16>, action: ErrorPageAction) -> Self {
+        ErrorPage { statuses, action, status_override: None }
+    }
+}
+
+/// A destination `CONNECT` or the forward proxy may reach.
+pub struct DestinationRule {
+    /// A host name or IP address, "*.example.com" for any subdomain of
+    /// example.com, or "*" for any host. Compared case-insensitively. An IP
+    /// address also matches every host name that resolves to it.
+    pub host: String,
+    pub ports: RangeInclusive<u16>,
+}
+
+impl DestinationRule {
+    pub fn new(host: &str, ports: RangeInclusive<u16>) -> Self {
+        DestinationRule { host: host.to_string(), ports }
+    }
+}
+
+/// Tunnels for `CONNECT`, which is how HTTPS passes through a forward proxy.
+pub struct ConnectConfig {
+    /// Only destinations matching one of these are reachable.
+    pub allow: Vec<DestinationRule>,
+    /// How long to wait for the destination to accept the connection.
+    pub connect_timeout: Duration,
+    /// A tunnel without any traffic in either direction for this long is
+    /// closed.
+    pub idle_timeout: Duration,
+}
+
+impl Default for ConnectConfig {
+    fn default() -> Self {
+        ConnectConfig {
+            allow: Vec::new(),
+            connect_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Forwarding of absolute-form requests (`GET http://host/path`), which is
+/// what plain HTTP clients configured with a proxy send. Only `http` targets
+/// are forwarded, HTTPS goes through `CONNECT`.
+pub struct ForwardProxyConfig {
+    /// Only destinations matching one of these are reachable.
+    pub allow: Vec<DestinationRule>,
+    /// Destinations matching one of these are refused even if allowed.
+    pub deny: Vec<DestinationRule>,
+    /// How long to wait for the origin to accept the connection.
+    pub connect_timeout: Duration,
+    /// How long to wait for the origin on each read and write.
+    pub read_timeout: Duration,
+    /// Responses are fetched every time without this.
+    pub cache: Option<CacheConfig>,
+    /// Checked before the server wide error pages for forwarded requests.
+    pub error_pages: Vec<ErrorPage>,
+    /// Also apply error pages to error responses coming back from origins
+    /// instead of passing them through.
+    pub intercept_errors: bool,
+}
+
+impl Default for ForwardProxyConfig {
+    fn default() -> Self {
+        ForwardProxyConfig {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(60),
+            cache: None,
+            error_pages: Vec::new(),
+            intercept_errors: false,
+        }
+    }
+}
+
+/// A shared HTTP cache for the responses of the forward proxy, kept on disk
+/// so it survives restarts.
+pub struct CacheConfig {
+    /// Where the responses are stored, one file each.
+    pub dir: PathBuf,
+    /// The least recently used responses are evicted to stay below this many
+    /// bytes.
+    pub max_size: u64,
+    /// Larger responses are passed on without being stored.
+    pub max_entry_size: u64,
+}
+
+impl CacheConfig {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        CacheConfig {
+            dir: dir.into(),
+            max_size: 1024 * 1024 * 1024,
+            max_entry_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// `Basic` credentials clients have to send, in `Proxy-Authorization` to the
+/// proxy and in `Authorization` to anything else.
+pub struct BasicAuth {
+    /// Shown to the user when asking for credentials.
+    pub realm: String,
+    /// User names and their passwords.
+    pub users: Vec<(String, String)>,
+}
+
+/// Keeps recently served static files open in memory, along with their
+/// metadata and validators, and the contents of small ones.
+pub struct OpenFileCacheConfig {
+    /// The least recently served files are dropped to stay below this many.
+    pub max_entries: usize,
+    /// The least recently served files are dropped to keep at most this many
+    /// bytes of contents in memory.
+    pub max_bytes: u64,
+    /// Files up to this size are read into memory, larger ones are kept open.
+    pub max_file_size: u64,
+    /// How long a file is served as it was found before checking whether it
+    /// changed. Changes made through the server itself are seen right away.
+    pub valid_for: Duration,
+}
+
+impl Default for OpenFileCacheConfig {
+    fn default() -> Self {
+        OpenFileCacheConfig {
+            max_entries: 10_000,
+            max_bytes: 64 * 1024 * 1024,
+            max_file_size: 256 * 1024,
+            valid_for: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A protocol version TLS connections may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+/// A certificate and the host names it is presented for.
+pub struct TlsCertificate {
+    /// Matched against the name the client asks for (SNI), `*.example.com`
+    /// matches exactly one label in place of the `*`.
+    pub server_names: Vec<String>,
+    /// PEM file with the certificate followed by its chain.
+    pub cert_chain: PathBuf,
+    /// PEM file with the private key.
+    pub private_key: PathBuf,
+}
+
+impl TlsCertificate {
+    pub fn new(server_names: &[&str], cert_chain: impl Into<PathBuf>, private_key: impl Into<PathBuf>) -> Self {
+        TlsCertificate {
+            server_names: server_names.iter().map(|name| name.to_string()).collect(),
+            cert_chain: cert_chain.into(),
+            private_key: private_key.into(),
+        }
+    }
+}
+
+/// HTTPS instead of plain HTTP on the listener. The io_uring engine can't
+/// serve it.
+pub struct TlsConfig {
+    /// The first one is presented to clients asking for no name or one none
+    /// of them is for.
+    pub certificates: Vec<TlsCertificate>,
+    pub versions: Vec<TlsVersion>,
+    /// IANA names, like `TLS13_AES_256_GCM_SHA384`, of the cipher suites to
+    /// offer in order of preference. Empty offers the defaults.
+    pub cipher_suites: Vec<String>,
+    /// Protocols offered through ALPN in order of preference. A client
+    /// offering none of them is refused, one not using ALPN is not.
+    pub alpn_protocols: Vec<String>,
+    /// Sessions remembered for clients resuming them by ID, 0 turns this off.
+    pub session_cache_size: usize,
+    /// Also resume sessions from tickets kept by the client.
+    pub session_tickets: bool,
+}
+
+impl TlsConfig {
+    pub fn new(certificates: Vec<TlsCertificate>) -> Self {
+        TlsConfig {
+            certificates,
+            versions: vec![TlsVersion::Tls12, TlsVersion::Tls13],
+            cipher_suites: Vec::new(),
+            alpn_protocols: vec!["http/1.1".to_string()],
+            session_cache_size: 256,
+            session_tickets: true,
+        }
+    }
+}
+
+pub struct ServerConfig {
+    pub worker_threads: usize,
+    pub engine: Engine,
+    pub timeouts: Timeouts,
+    pub limits: Limits,
+    pub obs_fold: ObsFold,
+    pub path_normalization: PathNormalization,
+    /// Requests no location matches get `404 Not Found`, so nothing is
+    /// served until a location names its root.
+    pub locations: Vec<Location>,
+    /// Used when the matching location, or the forward proxy, has no page
+    /// for a status.
+    pub error_pages: Vec<ErrorPage>,
+    /// `CONNECT` is answered with `501 Not Implemented` without this.
+    pub connect: Option<ConnectConfig>,
+    /// Absolute-form requests are served like any other without this.
+    pub forward_proxy: Option<ForwardProxyConfig>,
+    /// Credentials both `connect` and `forward_proxy` require.
+    pub proxy_auth: Option<BasicAuth>,
+    /// Static files are opened for every request without this.
+    pub open_file_cache: Option<OpenFileCacheConfig>,
+    /// Static files at least this large are written from a memory mapping,
+    /// shared by the responses serving the same file, instead of being read.
+    /// Without this they are always read.
+    pub mmap_min_size: Option<u64>,
+    /// The listener speaks plain HTTP without this.
+    pub tls: Option<TlsConfig>,
+}
+
+const DEFAULT_WORKER_THREADS: usize = 10;
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            worker_threads: DEFAULT_WORKER_THREADS,
+            engine: Engine::Threaded,
+            timeouts: Timeouts::default(),
+            limits: Limits::default(),
+            obs_fold: ObsFold::default(),
+            path_normalization: PathNormalization::default(),
+            locations: Vec::new(),
+            error_pages: Vec::new(),
+            connect: None,
+            forward_proxy: None,
+            proxy_auth: None,
+            open_file_cache: None,
+            mmap_min_size: None,
+            tls: None,
+        }
+    }
+}
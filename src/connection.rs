@@ -1,52 +1,218 @@
-use crate::response;
-use std::io::Read;
-use std::net::{Shutdown, TcpStream};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::config::{ServerConfig, Timeouts};
+use crate::handler;
 use crate::response::HttpResponse;
-use crate::util::HttpStatusCode;
+use crate::tls::{Stream, TlsInfo};
 use super::parser::Parser;
 const BUFFER_SIZE: usize = 4096;
 
+// Tracks which timeout applies to the next read. Shared by every engine so
+// they agree on when a client is too slow.
+pub(crate) struct ReadTimer {
+    timeouts: Timeouts,
+    request_started: Option<Instant>,
+    last_activity: Instant,
+    served: bool,
+}
+
+pub(crate) struct ReadDeadline {
+    pub(crate) at: Instant,
+    // a request is partially received and gets a 408, otherwise the
+    // connection is closed without a word
+    pub(crate) respond: bool,
+}
+
+impl ReadTimer {
+    pub(crate) fn new(timeouts: Timeouts) -> Self {
+        ReadTimer {
+            timeouts,
+            request_started: None,
+            last_activity: Instant::now(),
+            served: false,
+        }
+    }
+
+    pub(crate) fn on_read(&mut self, parser: &Parser) {
+        self.last_activity = Instant::now();
+        if self.request_started.is_none() && parser.is_started() {
+            self.request_started = Some(self.last_activity);
+        }
+    }
+
+    pub(crate) fn on_response_sent(&mut self) {
+        self.request_started = None;
+        self.last_activity = Instant::now();
+        self.served = true;
+    }
+
+    pub(crate) fn deadline(&self, parser: &Parser) -> ReadDeadline {
+        if parser.in_body() {
+            ReadDeadline { at: self.last_activity + self.timeouts.body, respond: true }
+        } else if let Some(started) = self.request_started {
+            ReadDeadline { at: started + self.timeouts.header, respond: true }
+        } else if self.served {
+            ReadDeadline { at: self.last_activity + self.timeouts.keep_alive, respond: false }
+        } else {
+            ReadDeadline { at: self.last_activity + self.timeouts.header, respond: false }
+        }
+    }
+}
+
 pub(crate) struct HttpConnection {
     buffer: [u8; BUFFER_SIZE],
-    tcp_stream: TcpStream,
+    stream: Stream,
+    peer_addr: SocketAddr,
+    // known once the handshake is complete
+    tls: Option<Arc<TlsInfo>>,
     parser: Parser,
+    timer: ReadTimer,
+    config: Arc<ServerConfig>,
 }
 
 impl HttpConnection {
     fn read_from_socket(mut self) {
         loop {
-            match self.tcp_stream.read(&mut self.buffer) {
+            let deadline = self.timer.deadline(&self.parser);
+            let remaining = deadline.at.saturating_duration_since(Instant::now());
+            if remaining == Duration::ZERO {
+                self.timed_out(deadline.respond);
+                break;
+            }
+            if let Err(err) = self.stream.tcp().set_read_timeout(Some(remaining)) {
+                log::warn!("{} failed to set read timeout: {}", self.peer_addr, err);
+                break;
+            }
+
+            match self.stream.read(&mut self.buffer) {
                 Ok(bytes_read) => {
                     if bytes_read == 0 {
                         break;
                     }
-                match self.parser.feed(&self.buffer[..bytes_read]){
-                    Ok(res) => {
-                        if res {
-                            let request = self.parser.finish().unwrap();
-                            let response = response::HttpResponse::ok();
-                            response.send();
-                            self.parser = Parser::new();
-                        }
-                    },
-                    Err(e) => {
-                        println!("{:?}", e);
-                        self.tcp_stream.shutdown(Shutdown::Both).unwrap();
+                    if !self.process(bytes_read) {
                         break;
                     }
                 }
+                // the deadline is re-checked at the top of the loop
+                Err(err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {}
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => {
+                    log::debug!("Error reading from {}: {}", self.peer_addr, err);
+                    break;
+                }
+            }
+        }
+    }
+
+    // Feeds freshly read bytes to the parser and answers every request they
+    // complete. Returns false once the connection should be closed.
+    fn process(&mut self, bytes_read: usize) -> bool {
+        let mut pos = 0;
+        while pos < bytes_read {
+            match self.parser.feed(&self.buffer[pos..bytes_read]) {
+                Ok(consumed) => pos += consumed,
+                Err(e) => {
+                    let response = handler::parse_error(&e, self.parser.error_offset(), self.peer_addr);
+                    let _ = response.send(&mut self.stream);
+                    self.stream.shutdown();
+                    return false;
+                }
+            }
+            self.timer.on_read(&self.parser);
+
+            if self.parser.is_done() {
+                let mut request = self.parser.take().finish().unwrap();
+                request.set_src_addr(self.peer_addr);
+                if self.tls.is_none() {
+                    self.tls = self.stream.tls_info().map(Arc::new);
+                }
+                if let Some(tls) = &self.tls {
+                    request.set_tls(Arc::clone(tls));
+                }
+                let mut response = handler::handle(&mut request, &self.config);
+                let tunnel = response.take_tunnel();
+                let keep_alive = response.keep_alive();
+                if let Err(err) = response.send(&mut self.stream).and_then(|()| self.stream.flush()) {
+                    log::debug!("Error writing to {}: {}", self.peer_addr, err);
+                    return false;
+                }
+                if let Some(tunnel) = tunnel {
+                    // handler::handle answers CONNECT over TLS with a 501, the
+                    // tunnel would have to be encrypted as well
+                    let plain = self.stream.plain().expect("tunnel opened on a TLS connection");
+                    // whatever follows the request already belongs to the tunnel
+                    match plain.try_clone() {
+                        Ok(client) => tunnel.run(client, self.peer_addr, &self.buffer[pos..bytes_read]),
+                        Err(err) => log::warn!("{} failed to clone socket for tunnel: {}", self.peer_addr, err),
+                    }
+                    return false;
+                }
+                if !keep_alive {
+                    return false;
                 }
-                Err(err) => println!("Error reading from socket {}", err)
+                self.timer.on_response_sent();
             }
         }
+        true
+    }
+
+    fn timed_out(&mut self, respond: bool) {
+        if respond {
+            let _ = HttpResponse::request_timeout().send(&mut self.stream);
+        }
+        self.stream.shutdown();
     }
-    pub(crate) fn init(tcp_stream: TcpStream) {
+
+    pub(crate) fn init(
+        tcp_stream: TcpStream,
+        peer_addr: SocketAddr,
+        config: Arc<ServerConfig>,
+        tls: Option<Arc<rustls::ServerConfig>>,
+    ) {
+        if let Err(err) = tcp_stream.set_write_timeout(Some(config.timeouts.send)) {
+            log::warn!("{} failed to set write timeout: {}", peer_addr, err);
+            return;
+        }
+
+        // keep a second handle around so a panicking handler can still be
+        // answered after `self` has been unwound, or at least hung up on if
+        // the answer would have to be encrypted
+        let fallback = tcp_stream.try_clone().ok();
+        let respond_on_panic = tls.is_none();
+        let stream = match Stream::new(tcp_stream, tls.as_ref()) {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::error!("failed to set up TLS for {}: {}", peer_addr, err);
+                return;
+            }
+        };
         let conn = HttpConnection {
             buffer: [0; BUFFER_SIZE],
-            tcp_stream,
-            parser: Parser::new()
+            stream,
+            peer_addr,
+            tls: None,
+            parser: Parser::with_limits(config.limits)
+                .with_obs_fold(config.obs_fold)
+                .with_path_normalization(config.path_normalization),
+            timer: ReadTimer::new(config.timeouts),
+            config,
         };
-        conn.read_from_socket();
+
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| conn.read_from_socket())) {
+            if let Some(mut stream) = fallback {
+                if respond_on_panic {
+                    let _ = HttpResponse::internal_server_error()
+                        .header("Connection", "close")
+                        .send(&mut stream);
+                }
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            // let the pool log it
+            panic::resume_unwind(payload);
+        }
     }
 
 }
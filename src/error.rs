@@ -1,9 +1,12 @@
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("invalid addr")]
     InvalidAddr(#[from] std::net::AddrParseError),
     #[error("io error")]
-    IOError(#[from] std::io::Error)
+    IOError(#[from] std::io::Error),
+    #[error("tls error: {0}")]
+    Tls(String),
 }
\ No newline at end of file
@@ -0,0 +1,594 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::fd::OwnedFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use log::{debug, error};
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Token, Waker};
+use rustls::ServerConnection;
+use crate::config::ServerConfig;
+use crate::connection::ReadTimer;
+use crate::handler;
+use crate::parser::{Parser, ParserError};
+use crate::request::HttpRequest;
+use crate::response::{ChunkWriter, HttpResponse};
+use crate::threadpool::{panic_message, ThreadPool};
+use crate::tls::{self, TlsInfo};
+use crate::tunnel::Tunnel;
+
+const WAKER: Token = Token(0);
+const BUFFER_SIZE: usize = 4096;
+const EVENTS_CAPACITY: usize = 1024;
+// Bodies larger than this are passed from the worker to the event loop in
+// chunks of this size rather than whole.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Used by the accept loop to hand sockets over to an event loop thread.
+pub(crate) struct EventLoopHandle {
+    incoming: Sender<(std::net::TcpStream, SocketAddr)>,
+    waker: Arc<Waker>,
+}
+
+impl EventLoopHandle {
+    pub(crate) fn register(&self, stream: std::net::TcpStream, addr: SocketAddr) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        self.incoming.send((stream, addr))
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "event loop is gone"))?;
+        self.waker.wake()
+    }
+}
+
+// A serialized response travelling from a worker back to the event loop.
+// A large body follows through `body`, `None` marking its end.
+struct Completion {
+    token: Token,
+    bytes: Vec<u8>,
+    body: Option<Receiver<Option<Vec<u8>>>>,
+    keep_alive: bool,
+    tunnel: Option<Tunnel>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    // `write_buf` and `written` are plaintext, what rustls hasn't sent yet
+    // is kept by it
+    tls: Option<ServerConnection>,
+    tls_info: Option<Arc<TlsInfo>>,
+    parser: Parser,
+    // what followed a request in the same read, nothing more is read from
+    // the socket while the request is being handled by a worker
+    pending: Vec<u8>,
+    write_buf: Vec<u8>,
+    written: usize,
+    // the rest of a large body, taken a chunk at a time as `write_buf` drains
+    body: Option<Receiver<Option<Vec<u8>>>>,
+    // whether the connection stays open once that body is complete
+    keep_alive: bool,
+    in_flight: bool,
+    close_after_write: bool,
+    read_closed: bool,
+    timer: ReadTimer,
+    // set while a response write is stalled on a full socket buffer
+    write_deadline: Option<Instant>,
+}
+
+impl Connection {
+    fn is_finished(&self) -> bool {
+        let idle = !self.in_flight && !self.has_unsent();
+        idle && (self.close_after_write || self.read_closed)
+    }
+
+    fn has_unsent(&self) -> bool {
+        self.written < self.write_buf.len() || self.tls.as_ref().is_some_and(|tls| tls.wants_write())
+    }
+}
+
+pub(crate) struct EventLoop {
+    id: usize,
+    poll: Poll,
+    waker: Arc<Waker>,
+    incoming: Receiver<(std::net::TcpStream, SocketAddr)>,
+    completed_tx: Sender<Completion>,
+    completed_rx: Receiver<Completion>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    pool: Arc<ThreadPool>,
+    config: Arc<ServerConfig>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl EventLoop {
+    pub(crate) fn spawn(
+        id: usize,
+        pool: Arc<ThreadPool>,
+        config: Arc<ServerConfig>,
+        tls: Option<Arc<rustls::ServerConfig>>,
+    ) -> io::Result<EventLoopHandle> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (incoming_tx, incoming) = mpsc::channel();
+        let (completed_tx, completed_rx) = mpsc::channel();
+
+        let event_loop = EventLoop {
+            id,
+            poll,
+            waker: Arc::clone(&waker),
+            incoming,
+            completed_tx,
+            completed_rx,
+            connections: HashMap::new(),
+            next_token: WAKER.0 + 1,
+            pool,
+            config,
+            tls,
+        };
+
+        thread::Builder::new().name(format!("event-loop-{}", id)).spawn(move || {
+            if let Err(err) = event_loop.run() {
+                error!("event loop {} stopped: {}", id, err);
+            }
+        })?;
+
+        Ok(EventLoopHandle { incoming: incoming_tx, waker })
+    }
+
+    fn run(mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        let mut buffer = [0; BUFFER_SIZE];
+
+        loop {
+            let timeout = self.next_deadline().map(|at| at.saturating_duration_since(Instant::now()));
+            if let Err(err) = self.poll.poll(&mut events, timeout) {
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => {
+                        self.accept_incoming();
+                        self.collect_completed();
+                    }
+                    token => {
+                        if event.is_readable() || event.is_read_closed() {
+                            self.on_readable(token, &mut buffer);
+                        }
+                        if event.is_writable() {
+                            self.flush(token);
+                        }
+                    }
+                }
+            }
+
+            self.expire_timeouts();
+        }
+    }
+
+    fn current_deadline(conn: &Connection) -> Option<Instant> {
+        if conn.has_unsent() {
+            conn.write_deadline
+        } else if conn.in_flight {
+            None
+        } else {
+            Some(conn.timer.deadline(&conn.parser).at)
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.connections.values().filter_map(Self::current_deadline).min()
+    }
+
+    fn expire_timeouts(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<Token> = self.connections.iter()
+            .filter(|(_, conn)| Self::current_deadline(conn).is_some_and(|at| at <= now))
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in expired {
+            let conn = match self.connections.get_mut(&token) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            // a stalled write or a silent idle connection is simply dropped,
+            // a half received request gets told why
+            let stalled = conn.has_unsent();
+            if stalled || conn.close_after_write || !conn.timer.deadline(&conn.parser).respond {
+                self.close(token);
+                continue;
+            }
+            conn.parser.take();
+            Self::respond_and_close(conn, HttpResponse::request_timeout());
+            self.flush(token);
+        }
+    }
+
+    fn accept_incoming(&mut self) {
+        while let Ok((stream, peer_addr)) = self.incoming.try_recv() {
+            let token = Token(self.next_token);
+            self.next_token += 1;
+
+            let tls = match self.tls.as_ref().map(|config| ServerConnection::new(Arc::clone(config))).transpose() {
+                Ok(tls) => tls,
+                Err(err) => {
+                    error!("event loop {} failed to set up TLS for {}: {}", self.id, peer_addr, err);
+                    continue;
+                }
+            };
+            let mut stream = TcpStream::from_std(stream);
+            // mio is edge triggered, so interest in both directions can stay
+            // registered for the whole lifetime of the connection
+            if let Err(err) = self.poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+                error!("event loop {} failed to register {}: {}", self.id, peer_addr, err);
+                continue;
+            }
+
+            self.connections.insert(token, Connection {
+                stream,
+                peer_addr,
+                tls,
+                tls_info: None,
+                parser: Parser::with_limits(self.config.limits)
+                .with_obs_fold(self.config.obs_fold)
+                .with_path_normalization(self.config.path_normalization),
+                pending: Vec::new(),
+                write_buf: Vec::new(),
+                written: 0,
+                body: None,
+                keep_alive: true,
+                in_flight: false,
+                close_after_write: false,
+                read_closed: false,
+                timer: ReadTimer::new(self.config.timeouts),
+                write_deadline: None,
+            });
+        }
+    }
+
+    fn collect_completed(&mut self) {
+        while let Ok(completion) = self.completed_rx.try_recv() {
+            let token = completion.token;
+            if let Some(tunnel) = completion.tunnel {
+                self.start_tunnel(token, tunnel, completion.bytes);
+                continue;
+            }
+            let conn = match self.connections.get_mut(&token) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            conn.write_buf.extend_from_slice(&completion.bytes);
+            match completion.body {
+                // the response is still in flight until its body is complete
+                Some(body) => {
+                    conn.body = Some(body);
+                    conn.keep_alive = completion.keep_alive;
+                    self.flush(token);
+                }
+                None => self.complete(token, completion.keep_alive),
+            }
+        }
+
+        // workers wake the loop for every chunk they pass on
+        let streaming: Vec<Token> = self.connections.iter()
+            .filter(|(_, conn)| conn.body.is_some() && !conn.has_unsent())
+            .map(|(token, _)| *token)
+            .collect();
+        for token in streaming {
+            self.flush(token);
+        }
+    }
+
+    // The worker is done with the response, the requests that arrived in the
+    // meantime are next.
+    fn complete(&mut self, token: Token, keep_alive: bool) {
+        let pending = match self.connections.get_mut(&token) {
+            Some(conn) => {
+                conn.in_flight = false;
+                conn.close_after_write |= !keep_alive;
+                mem::take(&mut conn.pending)
+            }
+            None => return,
+        };
+
+        self.flush(token);
+        if !pending.is_empty() {
+            self.feed(token, &pending);
+        }
+        self.resume_reading(token);
+    }
+
+    // Reads what arrived while the last request was in flight. Readiness
+    // is edge-triggered, so the socket isn't reported again for data that
+    // was already there.
+    fn resume_reading(&mut self, token: Token) {
+        if self.connections.get(&token).is_some_and(|conn| !conn.in_flight && !conn.close_after_write) {
+            self.on_readable(token, &mut [0; BUFFER_SIZE]);
+        }
+    }
+
+    // The connection leaves the event loop for a thread of its own, taking
+    // what is still unsent in either direction along.
+    fn start_tunnel(&mut self, token: Token, tunnel: Tunnel, response: Vec<u8>) {
+        let mut conn = match self.connections.remove(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let _ = self.poll.registry().deregister(&mut conn.stream);
+        if conn.tls.is_some() {
+            debug!("tunnels over TLS aren't supported");
+            return;
+        }
+        let mut to_client = conn.write_buf.split_off(conn.written);
+        to_client.extend_from_slice(&response);
+        let client = std::net::TcpStream::from(OwnedFd::from(conn.stream));
+        tunnel.spawn(client, conn.peer_addr, to_client, conn.pending);
+    }
+
+    fn on_readable(&mut self, token: Token, buffer: &mut [u8]) {
+        loop {
+            let conn = match self.connections.get_mut(&token) {
+                Some(conn) => conn,
+                None => return,
+            };
+            // a client pipelining requests waits in its socket buffer until
+            // the response is out, instead of piling up here
+            if conn.in_flight || conn.close_after_write {
+                break;
+            }
+            let read = match conn.tls.as_mut() {
+                Some(tls) => tls::read_plaintext(tls, &mut conn.stream, buffer),
+                None => conn.stream.read(buffer),
+            };
+            match read {
+                Ok(0) => {
+                    conn.read_closed = true;
+                    break;
+                }
+                Ok(bytes_read) => self.feed(token, &buffer[..bytes_read]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    debug!("Error reading from socket {}", err);
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+
+        // the handshake has rustls answer on its own
+        if self.connections.get(&token).is_some_and(Connection::has_unsent) {
+            self.flush(token);
+        }
+        self.close_if_finished(token);
+    }
+
+    fn feed(&mut self, token: Token, mut data: &[u8]) {
+        while !data.is_empty() {
+            let conn = match self.connections.get_mut(&token) {
+                Some(conn) => conn,
+                None => return,
+            };
+            if conn.in_flight || conn.close_after_write {
+                conn.pending.extend_from_slice(data);
+                return;
+            }
+
+            // parsing happens on the event loop thread, where a panic would take
+            // every connection of this loop down with it
+            let parsed = match panic::catch_unwind(AssertUnwindSafe(|| Self::parse(conn, data))) {
+                Ok(parsed) => parsed,
+                Err(payload) => {
+                    error!("event loop {} recovered from panicking parser: {}", self.id, panic_message(&*payload));
+                    conn.parser.take();
+                    Self::respond_and_close(conn, HttpResponse::internal_server_error());
+                    self.flush(token);
+                    return;
+                }
+            };
+
+            match parsed {
+                Ok((consumed, request)) => {
+                    data = &data[consumed..];
+                    if let Some(request) = request {
+                        self.dispatch(token, request);
+                    }
+                }
+                Err(e) => {
+                    let response = handler::parse_error(&e, conn.parser.error_offset(), conn.peer_addr);
+                    Self::respond_and_close(conn, response);
+                    self.flush(token);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn dispatch(&mut self, token: Token, mut request: HttpRequest) {
+        let conn = match self.connections.get_mut(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        conn.in_flight = true;
+        request.set_src_addr(conn.peer_addr);
+        if conn.tls_info.is_none() {
+            conn.tls_info = conn.tls.as_ref().map(|tls| Arc::new(TlsInfo::new(tls)));
+        }
+        if let Some(tls_info) = &conn.tls_info {
+            request.set_tls(Arc::clone(tls_info));
+        }
+
+        let completed_tx = self.completed_tx.clone();
+        let waker = Arc::clone(&self.waker);
+        let config = Arc::clone(&self.config);
+        self.pool.execute(move || {
+            let (mut response, payload) = handler::handle_or_500(&mut request, &config);
+
+            let tunnel = response.take_tunnel();
+            let keep_alive = response.keep_alive();
+            let (mut bytes, body) = response.into_parts();
+            match body {
+                // a large body is passed on while it is being written, so no
+                // more than a chunk of it is held at a time
+                Some(body) if body.size().is_none_or(|size| size > CHUNK_SIZE as u64) => {
+                    let wake_loop = Arc::clone(&waker);
+                    let (mut writer, chunks) = ChunkWriter::new(CHUNK_SIZE, Box::new(move || {
+                        let _ = wake_loop.wake();
+                    }));
+                    let _ = completed_tx.send(Completion { token, bytes, body: Some(chunks), keep_alive, tunnel });
+                    let _ = waker.wake();
+                    // the connection is closed once the chunks stop without an end
+                    if let Err(err) = body.write(&mut writer).and_then(|()| writer.finish()) {
+                        debug!("Error writing response body {}", err);
+                    }
+                }
+                body => {
+                    let keep_alive = match body.map_or(Ok(()), |body| body.write(&mut bytes)) {
+                        Ok(()) => keep_alive,
+                        Err(err) => {
+                            error!("Error serializing response {}", err);
+                            bytes.clear();
+                            false
+                        }
+                    };
+                    let _ = completed_tx.send(Completion { token, bytes, body: None, keep_alive, tunnel });
+                    let _ = waker.wake();
+                }
+            }
+
+            // let the pool log it
+            if let Some(payload) = payload {
+                panic::resume_unwind(payload);
+            }
+        });
+    }
+
+    fn respond_and_close(conn: &mut Connection, response: HttpResponse) {
+        conn.close_after_write = true;
+        let _ = response.header("Connection", "close").send(&mut conn.write_buf);
+    }
+
+    fn parse(conn: &mut Connection, data: &[u8]) -> Result<(usize, Option<HttpRequest>), ParserError> {
+        let consumed = conn.parser.feed(data)?;
+        conn.timer.on_read(&conn.parser);
+        if !conn.parser.is_done() {
+            return Ok((consumed, None));
+        }
+        conn.parser.take().finish().map(|request| (consumed, Some(request)))
+    }
+
+    fn flush(&mut self, token: Token) {
+        if let Some(pending) = self.write_out(token) {
+            if !pending.is_empty() {
+                self.feed(token, &pending);
+            }
+            self.resume_reading(token);
+        }
+    }
+
+    // Sends as much as the socket takes. Returns what followed the request
+    // if this completed a streamed response.
+    fn write_out(&mut self, token: Token) -> Option<Vec<u8>> {
+        let conn = self.connections.get_mut(&token)?;
+
+        let mut released = None;
+        let mut sent_response = false;
+        loop {
+            if conn.written == conn.write_buf.len() {
+                conn.write_buf.clear();
+                conn.written = 0;
+                // the next chunk is only taken once the last one is out, so
+                // a connection holds one at a time however large the body
+                match conn.body.as_ref().map(Receiver::try_recv) {
+                    Some(Ok(Some(chunk))) => conn.write_buf = chunk,
+                    Some(Ok(None)) => {
+                        conn.body = None;
+                        conn.in_flight = false;
+                        conn.close_after_write |= !conn.keep_alive;
+                        released = Some(mem::take(&mut conn.pending));
+                        sent_response = true;
+                    }
+                    Some(Err(TryRecvError::Empty)) | None => {}
+                    // the body broke off, all the client can be told is that
+                    // it ends short
+                    Some(Err(TryRecvError::Disconnected)) => {
+                        self.close(token);
+                        return None;
+                    }
+                }
+            }
+            if let Some(tls) = conn.tls.as_mut() {
+                // rustls takes no more than its buffer limit, the rest waits
+                // until that has been sent
+                match tls.writer().write(&conn.write_buf[conn.written..]) {
+                    Ok(n) => {
+                        conn.written += n;
+                        sent_response |= n > 0;
+                    }
+                    Err(err) => {
+                        debug!("Error writing to TLS connection {}", err);
+                        self.close(token);
+                        return None;
+                    }
+                }
+            }
+            if !conn.has_unsent() {
+                break;
+            }
+
+            let sent = match conn.tls.as_mut() {
+                Some(tls) => tls.write_tls(&mut conn.stream),
+                None => conn.stream.write(&conn.write_buf[conn.written..]).inspect(|n| conn.written += n),
+            };
+            match sent {
+                Ok(0) => {
+                    self.close(token);
+                    return None;
+                }
+                Ok(_) => {
+                    conn.write_deadline = None;
+                    sent_response |= conn.tls.is_none();
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if conn.write_deadline.is_none() {
+                        conn.write_deadline = Some(Instant::now() + self.config.timeouts.send);
+                    }
+                    return released;
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    debug!("Error writing to socket {}", err);
+                    self.close(token);
+                    return None;
+                }
+            }
+        }
+
+        // everything is out unless the worker has more of the body to come
+        if sent_response && !conn.in_flight {
+            conn.timer.on_response_sent();
+        }
+        self.close_if_finished(token);
+        released
+    }
+
+    fn close_if_finished(&mut self, token: Token) {
+        if self.connections.get(&token).is_some_and(Connection::is_finished) {
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut conn) = self.connections.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut conn.stream);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt::{Display, Formatter};
+use crate::path::hex_value;
+use crate::util::HttpStatusCode;
+
+// Decoded `name=value` pairs from a query string or an urlencoded body, in
+// the order they were sent. Names may repeat.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Params {
+    pairs: Vec<(String, String)>,
+}
+
+impl Params {
+    // the first value of `name`
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    pub(crate) fn get_all<'a, 'n>(&'a self, name: &'n str) -> impl Iterator<Item = &'a str> + use<'a, 'n> {
+        self.pairs.iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pairs.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FormError {
+    UnsupportedMediaType,
+    TooLarge,
+    TooManyFields,
+}
+
+impl FormError {
+    pub(crate) fn status(&self) -> HttpStatusCode {
+        match self {
+            FormError::UnsupportedMediaType => HttpStatusCode::UnsupportedMediaType,
+            FormError::TooLarge | FormError::TooManyFields => HttpStatusCode::ContentTooLarge,
+        }
+    }
+}
+
+impl Display for FormError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormError::UnsupportedMediaType => write!(f, "not an urlencoded form"),
+            FormError::TooLarge => write!(f, "form too large"),
+            FormError::TooManyFields => write!(f, "too many form fields"),
+        }
+    }
+}
+
+// application/x-www-form-urlencoded, which is also how query strings are
+// read. Malformed escapes are kept as they are and invalid UTF-8 is
+// replaced rather than rejected, like browsers do.
+// https://url.spec.whatwg.org/#urlencoded-parsing
+pub(crate) fn parse_urlencoded(input: &[u8]) -> Params {
+    let pairs = input.split(|&b| b == b'&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = match pair.iter().position(|&b| b == b'=') {
+                Some(pos) => (&pair[..pos], &pair[pos + 1..]),
+                None => (pair, &[][..]),
+            };
+            (decode(name), decode(value))
+        })
+        .collect();
+    Params { pairs }
+}
+
+fn decode(input: &[u8]) -> String {
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        let escaped = match (input[i], input.get(i + 1..i + 3)) {
+            (b'%', Some(&[hi, lo])) => hex_value(hi).zip(hex_value(lo)).map(|(hi, lo)| hi << 4 | lo),
+            _ => None,
+        };
+        match (escaped, input[i]) {
+            (Some(byte), _) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (None, b'+') => decoded.push(b' '),
+            (None, byte) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Limits;
+    use crate::form::{parse_urlencoded, FormError};
+    use crate::test_util::request_with_body;
+
+    #[test]
+    fn test_parse_urlencoded() {
+        let params = parse_urlencoded(b"tag=a&name=J%C3%B6rg+M&&tag=b%2Bc&flag&bad=%zz%4&tag=");
+        assert_eq!(params.get("name"), Some("Jörg M"));
+        assert_eq!(params.get_all("tag").collect::<Vec<_>>(), ["a", "b+c", ""]);
+        assert_eq!(params.get("flag"), Some(""));
+        assert_eq!(params.get("bad"), Some("%zz%4"));
+        assert_eq!(params.get("missing"), None);
+
+        let names: Vec<&str> = params.pairs.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["tag", "name", "tag", "flag", "bad", "tag"]);
+        assert_eq!(parse_urlencoded(b"%ff=1").get("\u{fffd}"), Some("1"));
+    }
+
+    #[test]
+    fn test_request_form() {
+        let request = request_with_body("POST /?q=a+b&q=c HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded; charset=utf-8", "a=1&b=2");
+        assert_eq!(request.query_params().get_all("q").collect::<Vec<_>>(), ["a b", "c"]);
+
+        let limits = Limits::default();
+        assert_eq!(request.form(&limits).unwrap().get("b"), Some("2"));
+        assert_eq!(request.form(&Limits { form_size: 6, ..limits }), Err(FormError::TooLarge));
+        assert_eq!(request.form(&Limits { form_fields: 1, ..limits }), Err(FormError::TooManyFields));
+
+        let request = request_with_body("POST / HTTP/1.1\r\nContent-Type: text/plain", "a=1");
+        assert_eq!(request.form(&limits), Err(FormError::UnsupportedMediaType));
+    }
+}
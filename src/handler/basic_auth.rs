@@ -0,0 +1,70 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use crate::config::BasicAuth;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::util::HttpStatusCode;
+
+// Whom the credentials are for. A proxy has fields of its own so they don't
+// get mixed up with those for the origin.
+// https://www.rfc-editor.org/rfc/rfc9110#section-11.7
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(super) enum Target {
+    Origin,
+    Proxy,
+}
+
+// The answer for a client without valid credentials, `None` if it may go on.
+// https://www.rfc-editor.org/rfc/rfc7617
+pub(super) fn check(auth: &BasicAuth, target: Target, request: &HttpRequest) -> Option<HttpResponse> {
+    let (field, status, challenge_field) = match target {
+        Target::Origin => ("authorization", HttpStatusCode::Unauthorized, "WWW-Authenticate"),
+        Target::Proxy => ("proxy-authorization", HttpStatusCode::ProxyAuthenticationRequired, "Proxy-Authenticate"),
+    };
+    let credentials = request.header_str(field)
+        .and_then(|value| value.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, encoded)| STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+    let valid = credentials.as_deref()
+        .and_then(|credentials| credentials.split_once(':'))
+        .is_some_and(|(user, password)| auth.users.iter().any(|(name, secret)| name == user && secret == password));
+    if valid {
+        return None;
+    }
+    log::debug!("{:?} {} authentication failed", request.src_addr(), request.effective_uri());
+    let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", auth.realm.replace(['\\', '"'], ""));
+    Some(HttpResponse::new(status).header(challenge_field, &challenge))
+}
+
+#[cfg(test)]
+mod test {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use crate::config::BasicAuth;
+    use crate::handler::basic_auth::{check, Target};
+    use crate::request::HttpRequest;
+    use crate::test_util::request;
+    use crate::util::HttpStatusCode;
+
+    fn get(fields: &str) -> HttpRequest {
+        request(&format!("GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n{}\r\n", fields))
+    }
+
+    #[test]
+    fn test_check() {
+        let auth = BasicAuth { realm: "proxy".to_string(), users: vec![("alice".to_string(), "s:cret".to_string())] };
+        let with = |field: &str, credentials: &str| get(&format!("{}: Basic {}\r\n", field, STANDARD.encode(credentials)));
+        assert!(check(&auth, Target::Proxy, &with("Proxy-Authorization", "alice:s:cret")).is_none());
+        assert!(check(&auth, Target::Origin, &with("Authorization", "alice:s:cret")).is_none());
+        // credentials for the origin aren't meant for the proxy
+        assert!(check(&auth, Target::Proxy, &with("Authorization", "alice:s:cret")).is_some());
+
+        let denied = check(&auth, Target::Proxy, &with("Proxy-Authorization", "alice:wrong")).unwrap();
+        assert_eq!(*denied.status(), HttpStatusCode::ProxyAuthenticationRequired);
+        let (head, _) = denied.into_parts();
+        assert!(String::from_utf8(head).unwrap().contains("Proxy-Authenticate: Basic realm=\"proxy\""));
+        let missing = check(&auth, Target::Origin, &get("")).unwrap();
+        assert_eq!(*missing.status(), HttpStatusCode::Unauthorized);
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt::Write;
+use crate::cache::{self, Purge};
+use crate::config::{BasicAuth, CacheConfig, ServerConfig};
+use crate::handler::basic_auth::{self, Target};
+use crate::handler::method_not_allowed;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::util::{HttpMethod, HttpStatusCode};
+
+// Lists and purges what the forward proxy's cache holds. Purges name a key,
+// the effective URI of the cached request, a prefix of keys, or a Cache-Tag,
+// in the query or in a form posted with them.
+pub(super) fn serve(config: &ServerConfig, auth: &BasicAuth, request: &HttpRequest) -> HttpResponse {
+    if let Some(denied) = basic_auth::check(auth, Target::Origin, request) {
+        return denied;
+    }
+    let cache = match config.forward_proxy.as_ref().and_then(|proxy| proxy.cache.as_ref()) {
+        Some(cache) => cache,
+        None => return HttpResponse::not_found(),
+    };
+    match request.method() {
+        HttpMethod::Get | HttpMethod::Head => json(HttpStatusCode::OK, list(&cache::list(cache))),
+        HttpMethod::Post => purge(config, cache, request),
+        HttpMethod::Extension(name) if name == "PURGE" => purge(config, cache, request),
+        method => method_not_allowed(method, "GET, HEAD, POST, PURGE"),
+    }
+}
+
+fn list(listings: &[cache::Listing]) -> String {
+    let mut out = String::from("{\"entries\":[");
+    for (index, listing) in listings.iter().enumerate() {
+        if index > 0 {
+            out.push(',');
+        }
+        let tags: Vec<String> = listing.tags.iter().map(|tag| json_string(tag)).collect();
+        let _ = write!(
+            out,
+            "{{\"key\":{},\"status\":{},\"size\":{},\"age\":{},\"hits\":{},\"tags\":[{}]}}",
+            json_string(&listing.key),
+            listing.status.code(),
+            listing.size,
+            listing.age.as_secs(),
+            listing.hits,
+            tags.join(",")
+        );
+    }
+    out.push_str("]}\n");
+    out
+}
+
+fn purge(config: &ServerConfig, cache: &CacheConfig, request: &HttpRequest) -> HttpResponse {
+    let params = match request.header("content-type") {
+        Some(_) if *request.method() == HttpMethod::Post => match request.form(&config.limits) {
+            Ok(params) => params,
+            Err(err) => return HttpResponse::new(err.status()),
+        },
+        _ => request.query_params(),
+    };
+    let selectors = [
+        params.get("key").map(|key| Purge::Key(key.to_string())),
+        params.get("prefix").map(|prefix| Purge::Prefix(prefix.to_string())),
+        params.get("tag").map(|tag| Purge::Tag(tag.to_string())),
+    ];
+    let mut selectors = selectors.into_iter().flatten();
+    let selector = match (selectors.next(), selectors.next()) {
+        (Some(selector), None) => selector,
+        _ => {
+            return HttpResponse::new(HttpStatusCode::BadRequest)
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .with_body("exactly one of key, prefix or tag is needed\n".to_string())
+        }
+    };
+    let purged = cache::purge(cache, &selector);
+    log::info!("{:?} purged {} cached responses", request.src_addr(), purged);
+    json(HttpStatusCode::OK, format!("{{\"purged\":{}}}\n", purged))
+}
+
+fn json(status: HttpStatusCode, body: String) -> HttpResponse {
+    HttpResponse::new(status)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .with_body(body)
+}
+
+// https://www.rfc-editor.org/rfc/rfc8259#section-7
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::cache::Listing;
+    use crate::config::{CacheConfig, ServerConfig};
+    use crate::handler::cache_admin::{json_string, list, purge};
+    use crate::test_util::request_with_body;
+    use crate::util::HttpStatusCode;
+
+    #[test]
+    fn test_list() {
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+        let listing = Listing {
+            key: "http://example.com/a".to_string(),
+            status: HttpStatusCode::OK,
+            size: 120,
+            age: Duration::from_millis(2500),
+            hits: 3,
+            tags: vec!["blog".to_string(), "home".to_string()],
+        };
+        assert_eq!(
+            list(&[listing]),
+            "{\"entries\":[{\"key\":\"http://example.com/a\",\"status\":200,\"size\":120,\"age\":2,\"hits\":3,\"tags\":[\"blog\",\"home\"]}]}\n"
+        );
+    }
+
+    #[test]
+    fn test_purge_params() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheConfig::new(dir.path());
+        let config = ServerConfig::default();
+        let status = |head: &str, body: &str| *purge(&config, &cache, &request_with_body(head, body)).status();
+
+        let form = "POST / HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded";
+        assert_eq!(status(form, "tag=blog"), HttpStatusCode::OK);
+        assert_eq!(status(form, "tag=blog&key=x"), HttpStatusCode::BadRequest);
+        assert_eq!(status("POST /?tag=blog HTTP/1.1", ""), HttpStatusCode::OK);
+        assert_eq!(status("PURGE /?tag=blog HTTP/1.1\r\nContent-Type: text/plain", ""), HttpStatusCode::OK);
+        assert_eq!(status("POST /?tag=blog HTTP/1.1\r\nContent-Type: text/plain", "tag=blog"), HttpStatusCode::UnsupportedMediaType);
+    }
+}
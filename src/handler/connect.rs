@@ -0,0 +1,171 @@
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use crate::config::{ConnectConfig, DestinationRule};
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::tunnel::Tunnel;
+use crate::util::HttpStatusCode;
+
+// Connects to the destination of a CONNECT and answers with the tunnel the
+// engine runs once the response is out.
+// https://www.rfc-editor.org/rfc/rfc9110#section-9.3.6
+pub(crate) fn open(settings: &ConnectConfig, request: &HttpRequest) -> HttpResponse {
+    let target = request.target();
+    // the parser only lets authority-form through, which always has a port
+    let (host, port) = match (target.host(), target.port()) {
+        (Some(host), Some(port)) => (host, port),
+        _ => return HttpResponse::new(HttpStatusCode::BadRequest),
+    };
+    let addrs = match permitted(&settings.allow, &[], host, port) {
+        Ok(addrs) if addrs.is_empty() => {
+            log::debug!("{:?} CONNECT {} is not allowed", request.src_addr(), target);
+            return HttpResponse::new(HttpStatusCode::Forbidden);
+        }
+        Ok(addrs) => addrs,
+        Err(err) => {
+            log::debug!("{:?} CONNECT {} failed: {}", request.src_addr(), target, err);
+            return HttpResponse::new(HttpStatusCode::BadGateway);
+        }
+    };
+
+    match connect(&addrs, settings.connect_timeout) {
+        Ok(upstream) => HttpResponse::ok()
+            .with_reason("Connection Established")
+            .with_tunnel(Tunnel::new(upstream, target.to_string(), settings.idle_timeout)),
+        Err(err) => {
+            log::debug!("{:?} CONNECT {} failed: {}", request.src_addr(), target, err);
+            match err.kind() {
+                ErrorKind::TimedOut => HttpResponse::new(HttpStatusCode::GatewayTimeout),
+                _ => HttpResponse::new(HttpStatusCode::BadGateway),
+            }
+        }
+    }
+}
+
+// The addresses of `host` the rules let through, none if it isn't allowed at
+// all. A destination is allowed by its name or by its address, and denied by
+// either, so a name that merely resolves to a denied address (or an address
+// spelled in an unusual way) doesn't get past the rules. Connecting to these
+// rather than resolving the name again keeps the answer from changing after
+// the check.
+pub(super) fn permitted(allow: &[DestinationRule], deny: &[DestinationRule], host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if deny.iter().any(|rule| matches(rule, host, port)) {
+        return Ok(Vec::new());
+    }
+    let allowed_by_name = allow.iter().any(|rule| matches(rule, host, port));
+    let addrs = resolve(host, port)?.into_iter()
+        .filter(|addr| allowed_by_name || allow.iter().any(|rule| matches_addr(rule, addr)))
+        .filter(|addr| !deny.iter().any(|rule| matches_addr(rule, addr)))
+        .collect();
+    Ok(addrs)
+}
+
+fn matches(rule: &DestinationRule, host: &str, port: u16) -> bool {
+    if !rule.ports.contains(&port) {
+        return false;
+    }
+    match rule.host.strip_prefix("*.") {
+        _ if rule.host == "*" => true,
+        Some(domain) => host.len() > domain.len() + 1
+            && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain)
+            && host.as_bytes()[host.len() - domain.len() - 1] == b'.',
+        None => host.eq_ignore_ascii_case(&rule.host),
+    }
+}
+
+// Only rules naming an IP address, or any host, say anything about an address.
+fn matches_addr(rule: &DestinationRule, addr: &SocketAddr) -> bool {
+    rule.ports.contains(&addr.port())
+        && (rule.host == "*" || unbracketed(&rule.host).parse::<IpAddr>().is_ok_and(|ip| ip.to_canonical() == addr.ip().to_canonical()))
+}
+
+// IP literals keep their brackets in the authority
+fn unbracketed(host: &str) -> &str {
+    host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host)
+}
+
+fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    Ok((unbracketed(host), port).to_socket_addrs()?.collect())
+}
+
+// Tries every address, in order.
+pub(super) fn connect(addrs: &[SocketAddr], timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(ErrorKind::NotFound, "host has no address");
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread;
+    use crate::config::{ConnectConfig, DestinationRule};
+    use crate::handler::connect::{matches, open, permitted};
+    use crate::test_util;
+    use crate::util::HttpStatusCode;
+
+    #[test]
+    fn test_rules() {
+        let rule = DestinationRule::new("*.Example.com", 443..=443);
+        assert!(matches(&rule, "api.example.COM", 443));
+        assert!(!matches(&rule, "example.com", 443));
+        assert!(!matches(&rule, "badexample.com", 443));
+        assert!(!matches(&rule, "api.example.com", 80));
+        assert!(matches(&DestinationRule::new("*", 1..=65535), "[::1]", 22));
+        assert!(matches(&DestinationRule::new("127.0.0.1", 8000..=9000), "127.0.0.1", 8080));
+
+        // rules on addresses apply to whatever resolves to them
+        let loopback = [DestinationRule::new("127.0.0.1", 1..=65535), DestinationRule::new("[::1]", 1..=65535)];
+        let any = [DestinationRule::new("*", 1..=65535)];
+        for host in ["localhost", "127.1", "2130706433", "[::ffff:127.0.0.1]"] {
+            assert_eq!(permitted(&any, &loopback, host, 80).unwrap(), Vec::new(), "{}", host);
+            assert!(!permitted(&loopback, &[], host, 80).unwrap().is_empty(), "{}", host);
+        }
+    }
+
+    #[test]
+    fn test_tunnel() {
+        // an upstream that echoes everything back
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let mut buffer = [0; 64];
+            loop {
+                match stream.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => stream.write_all(&buffer[..n]).unwrap(),
+                }
+            }
+        });
+
+        let settings = ConnectConfig { allow: vec![DestinationRule::new("127.0.0.1", port..=port)], ..ConnectConfig::default() };
+        let request = |target: &str| test_util::request(&format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target));
+        let denied = open(&settings, &request(&format!("127.0.0.1:{}", port + 1)));
+        assert_eq!(*denied.status(), HttpStatusCode::Forbidden);
+
+        let mut response = open(&settings, &request(&format!("127.0.0.1:{}", port)));
+        let tunnel = response.take_tunnel().expect("tunnel should be open");
+        let (head, _) = response.into_parts();
+        assert_eq!(String::from_utf8(head).unwrap(), "HTTP/1.1 200 Connection Established\r\n\r\n");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_side, peer_addr) = listener.accept().unwrap();
+        let running = thread::spawn(move || tunnel.run(server_side, peer_addr, b"early "));
+
+        client.write_all(b"ping").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut echoed = String::new();
+        client.read_to_string(&mut echoed).unwrap();
+        assert_eq!(echoed, "early ping");
+        running.join().unwrap();
+    }
+}
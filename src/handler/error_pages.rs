@@ -0,0 +1,204 @@
+use std::fs::File;
+use crate::config::{ErrorPage, ErrorPageAction, Location, ServerConfig};
+use crate::handler::{route, run, static_files};
+use crate::path;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::uri::Uri;
+use crate::util::{HttpMethod, HttpStatusCode};
+
+// Fields an error status requires, kept when a page replaces the response.
+// https://www.rfc-editor.org/rfc/rfc9110#section-15.5.2
+// https://www.rfc-editor.org/rfc/rfc9110#section-15.5.6
+// https://www.rfc-editor.org/rfc/rfc9110#section-15.5.8
+const REQUIRED_FIELDS: [&str; 3] = ["Allow", "WWW-Authenticate", "Proxy-Authenticate"];
+
+// Replaces an error response with the configured page, if there is one.
+// Pages are only looked up once, an error while producing a page is not
+// handed to another page.
+pub(crate) fn apply(config: &ServerConfig, location: Option<&Location>, forwarded: bool, request: &mut HttpRequest, response: HttpResponse) -> HttpResponse {
+    let status = *response.status();
+    if !status.is_error() {
+        return response;
+    }
+    // the pages tried before the server wide ones
+    let (pages, intercept_upstream) = match (&config.forward_proxy, location) {
+        (Some(proxy), _) if forwarded => (&proxy.error_pages[..], proxy.intercept_errors),
+        (_, Some(location)) => (&location.error_pages[..], false),
+        _ => (&[][..], false),
+    };
+    if response.is_from_upstream() && !intercept_upstream {
+        return response;
+    }
+
+    let page = match find(config, pages, status.code()) {
+        Some(page) => page,
+        None => return response,
+    };
+    let new_status = page.status_override.map(HttpStatusCode::from_code);
+
+    let replacement = match &page.action {
+        ErrorPageAction::File(path) => match File::open(path) {
+            Ok(file) => HttpResponse::new(new_status.unwrap_or(status))
+                .header("Content-Type", static_files::content_type(path))
+                .with_body(file),
+            Err(err) => {
+                log::error!("failed to open error page {}: {}", path.display(), err);
+                return response;
+            }
+        },
+        ErrorPageAction::Internal(target) => {
+            let uri = match Uri::parse(target) {
+                Ok(uri) => uri,
+                Err(err) => {
+                    log::error!("error page {}: {}", target, err);
+                    return response;
+                }
+            };
+            match path::normalize(uri.path(), &config.path_normalization) {
+                Ok(path) => request.set_target(uri, path),
+                Err(err) => {
+                    log::error!("error page {}: {}", target, err);
+                    return response;
+                }
+            }
+            if *request.method() != HttpMethod::Head {
+                request.set_method(HttpMethod::Get);
+            }
+            let redirected = run(config, route(config, request.path()), request);
+            if redirected.status().is_error() {
+                log::error!("error page {} failed with {}", target, redirected.status());
+                return response;
+            }
+            redirected.with_status(new_status.unwrap_or(status))
+        }
+        ErrorPageAction::Redirect(url) => {
+            HttpResponse::new(new_status.unwrap_or(HttpStatusCode::Found)).header("Location", url)
+        }
+    };
+    REQUIRED_FIELDS.iter()
+        .flat_map(|&name| response.headers().get_all(name).map(move |value| (name, value)))
+        .fold(replacement, |replacement, (name, value)| replacement.append_header(name, value))
+}
+
+fn find<'a>(config: &'a ServerConfig, pages: &'a [ErrorPage], code: u16) -> Option<&'a ErrorPage> {
+    pages.iter()
+        .chain(config.error_pages.iter())
+        .find(|page| page.statuses.contains(&code))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::config::{DestinationRule, ErrorPage, ErrorPageAction, ForwardProxyConfig, Handler, Location, ServerConfig};
+    use crate::handler::handle;
+    use crate::test_util::request;
+    use crate::util::HttpStatusCode;
+
+    #[test]
+    fn test_error_pages() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("docs/errors")).unwrap();
+        fs::write(root.path().join("404.html"), "server page").unwrap();
+        fs::write(root.path().join("docs/errors/404.html"), "docs page").unwrap();
+        let location = |prefix: &str, error_pages| Location {
+            error_pages,
+            ..Location::new(prefix, Handler::Static { root: root.path().to_path_buf() })
+        };
+
+        let mut config = ServerConfig::default();
+        config.error_pages.push(ErrorPage::new(404..=405, ErrorPageAction::File(root.path().join("404.html"))));
+        config.locations.push(location("/", Vec::new()));
+        config.locations.push(location("/docs", vec![ErrorPage::new(404..=404, ErrorPageAction::Internal("/docs/errors/404.html".to_string()))]));
+        config.locations.push(location("/gone", vec![ErrorPage::new(404..=404, ErrorPageAction::File(root.path().join("missing.html")))]));
+        let mut moved = ErrorPage::new(404..=404, ErrorPageAction::Redirect("https://example.com/".to_string()));
+        moved.status_override = Some(301);
+        config.locations.push(location("/moved", vec![moved]));
+
+        let send = |method: &str, path: &str| {
+            let mut request = request(&format!("{} {} HTTP/1.1\r\nHost: x\r\n\r\n", method, path));
+            let response = handle(&mut request, &config);
+            let status = *response.status();
+            let mut out = Vec::new();
+            response.send(&mut out).unwrap();
+            (status, String::from_utf8(out).unwrap())
+        };
+        let get = |path: &str| send("GET", path);
+        let (status, out) = get("/nothing");
+        assert_eq!(status, HttpStatusCode::NotFound);
+        assert!(out.ends_with("\r\n\r\nserver page"), "{}", out);
+        // the location's own pages come first
+        let (status, out) = get("/docs/nothing");
+        assert_eq!(status, HttpStatusCode::NotFound);
+        assert!(out.ends_with("\r\n\r\ndocs page"), "{}", out);
+        // a page that can't be opened leaves the original response alone
+        let (status, out) = get("/gone/nothing");
+        assert_eq!(status, HttpStatusCode::NotFound);
+        assert!(!out.contains("page"), "{}", out);
+        let (status, out) = get("/moved/nothing");
+        assert_eq!(status, HttpStatusCode::MovedPermanently);
+        assert!(out.contains("\r\nLocation: https://example.com/\r\n"), "{}", out);
+        // pages only replace errors
+        fs::write(root.path().join("found.txt"), "found").unwrap();
+        assert_eq!(get("/found.txt").0, HttpStatusCode::OK);
+        // the fields the status requires are kept
+        let (status, out) = send("DELETE", "/found.txt");
+        assert_eq!(status, HttpStatusCode::MethodNotAllowed);
+        assert!(out.contains("\r\nAllow: GET, HEAD\r\n"), "{}", out);
+        assert!(out.ends_with("\r\n\r\nserver page"), "{}", out);
+    }
+
+    #[test]
+    fn test_upstream_errors() {
+        // an origin failing every request
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = origin.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in origin.incoming() {
+                let mut stream = stream.unwrap();
+                let mut received = Vec::new();
+                let mut buffer = [0; 1024];
+                while !received.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buffer).unwrap();
+                    received.extend_from_slice(&buffer[..n]);
+                }
+                stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 8\r\n\r\nupstream").unwrap();
+            }
+        });
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("50x.html"), "proxy page").unwrap();
+        let proxy = ForwardProxyConfig {
+            allow: vec![DestinationRule::new("127.0.0.1", 1..=65535)],
+            error_pages: vec![ErrorPage::new(500..=599, ErrorPageAction::File(root.path().join("50x.html")))],
+            ..ForwardProxyConfig::default()
+        };
+        let mut config = ServerConfig { forward_proxy: Some(proxy), ..ServerConfig::default() };
+        let get = |config: &ServerConfig, port: u16| {
+            let mut request = request(&format!("GET http://127.0.0.1:{}/ HTTP/1.1\r\nHost: x\r\n\r\n", port));
+            let response = handle(&mut request, config);
+            let status = *response.status();
+            let mut out = Vec::new();
+            response.send(&mut out).unwrap();
+            (status, String::from_utf8(out).unwrap())
+        };
+
+        // passed through unless asked otherwise
+        let (status, out) = get(&config, port);
+        assert_eq!(status, HttpStatusCode::ServiceUnavailable);
+        assert!(out.ends_with("\r\n\r\nupstream"), "{}", out);
+        // the proxy's own errors always get a page
+        let (status, out) = get(&config, closed);
+        assert_eq!(status, HttpStatusCode::BadGateway);
+        assert!(out.ends_with("\r\n\r\nproxy page"), "{}", out);
+
+        config.forward_proxy.as_mut().unwrap().intercept_errors = true;
+        let (status, out) = get(&config, port);
+        assert_eq!(status, HttpStatusCode::ServiceUnavailable);
+        assert!(out.ends_with("\r\n\r\nproxy page"), "{}", out);
+    }
+}
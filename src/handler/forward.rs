@@ -0,0 +1,437 @@
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use crate::cache::{self, Fetched, Upstream};
+use crate::config::ForwardProxyConfig;
+use crate::handler::connect::{connect, permitted};
+use crate::headers::HeaderMap;
+use crate::request::HttpRequest;
+use crate::response::{Body, HttpResponse};
+use crate::util::{HttpMethod, HttpStatusCode};
+
+// Largest response head accepted from an origin.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+// Fields that only concern one connection and are never passed on, along with
+// those the Connection field names.
+// https://www.rfc-editor.org/rfc/rfc9110#section-7.6.1
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// Sends an absolute-form request on to its origin and relays the answer. The
+// body of the answer is streamed to the client as it arrives.
+// https://www.rfc-editor.org/rfc/rfc9110#section-7.6
+pub(super) fn serve(settings: &ForwardProxyConfig, request: &HttpRequest) -> HttpResponse {
+    let target = request.target();
+    // a proxy that doesn't speak the scheme can't forward it
+    if target.scheme() != Some("http") {
+        return HttpResponse::new(HttpStatusCode::NotImplemented);
+    }
+    let host = match target.host() {
+        Some(host) if !host.is_empty() => host,
+        _ => return HttpResponse::new(HttpStatusCode::BadRequest),
+    };
+    let port = target.port().unwrap_or(80);
+    let addrs = match permitted(&settings.allow, &settings.deny, host, port) {
+        Ok(addrs) if addrs.is_empty() => {
+            log::debug!("{:?} {} {} is not allowed", request.src_addr(), request.method(), target);
+            return HttpResponse::new(HttpStatusCode::Forbidden);
+        }
+        Ok(addrs) => addrs,
+        Err(err) => {
+            log::debug!("{:?} {} {} failed: {}", request.src_addr(), request.method(), target, err);
+            return HttpResponse::new(HttpStatusCode::BadGateway);
+        }
+    };
+
+    let origin = Origin {
+        addrs,
+        connect_timeout: settings.connect_timeout,
+        read_timeout: settings.read_timeout,
+    };
+    let response = match &settings.cache {
+        Some(cache) => cache::serve(cache, request, origin),
+        None => origin.fetch(request, request.headers().clone()).map(Fetched::into_response),
+    };
+    match response {
+        Ok(response) => response.mark_upstream(),
+        Err(err) => {
+            log::debug!("{:?} {} {} failed: {}", request.src_addr(), request.method(), target, err);
+            match err.kind() {
+                ErrorKind::TimedOut | ErrorKind::WouldBlock => HttpResponse::new(HttpStatusCode::GatewayTimeout),
+                _ => HttpResponse::new(HttpStatusCode::BadGateway),
+            }
+        }
+    }
+}
+
+// The server an absolute-form target names, at the addresses the rules
+// allowed.
+#[derive(Clone)]
+struct Origin {
+    addrs: Vec<SocketAddr>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+}
+
+impl Upstream for Origin {
+    // An idempotent request is sent once more when the connection breaks
+    // before the response arrives, the origin may have dropped it while
+    // shutting down or restarting. One that timed out isn't, the origin would
+    // most likely be just as slow again.
+    // https://www.rfc-editor.org/rfc/rfc9110#section-9.2.2
+    fn fetch(&self, request: &HttpRequest, headers: HeaderMap) -> io::Result<Fetched> {
+        match self.fetch_once(request, &headers) {
+            Err(err) if request.method().is_idempotent() && is_dropped(&err) => {
+                log::debug!("{:?} {} {} failed, retrying: {}", request.src_addr(), request.method(), request.target(), err);
+                self.fetch_once(request, &headers)
+            }
+            result => result,
+        }
+    }
+}
+
+impl Origin {
+    fn fetch_once(&self, request: &HttpRequest, headers: &HeaderMap) -> io::Result<Fetched> {
+        let stream = connect(&self.addrs, self.connect_timeout)?;
+        stream.set_read_timeout(Some(self.read_timeout))?;
+        stream.set_write_timeout(Some(self.read_timeout))?;
+        (&stream).write_all(&request_head(request, headers))?;
+        (&stream).write_all(request.body())?;
+
+        let mut reader = BufReader::new(stream);
+        let (status, headers) = loop {
+            let (status, headers) = read_head(&mut reader)?;
+            // the request asked for no upgrade, the Upgrade field is
+            // hop-by-hop and never passed on, so whatever follows isn't HTTP
+            if status == HttpStatusCode::SwitchingProtocols {
+                return Err(invalid("origin switched protocols"));
+            }
+            // interim responses aren't relayed, the request was sent whole
+            if !status.is_informational() {
+                break (status, headers);
+            }
+        };
+
+        let framing = if *request.method() == HttpMethod::Head || !status.allows_body() {
+            // no body follows, and the Content-Length the origin announced is
+            // passed on as it is
+            None
+        } else if let Some(coding) = headers.get_combined("transfer-encoding") {
+            // other codings would reach the client without saying so
+            if !coding.trim_ascii().eq_ignore_ascii_case(b"chunked") {
+                return Err(invalid("unsupported transfer coding"));
+            }
+            Some(Framing::Chunked)
+        } else {
+            Some(content_length(&headers)?.map_or(Framing::Close, Framing::Length))
+        };
+        let body = framing.map(|framing| Box::new(UpstreamBody { reader, framing }) as Box<dyn Body>);
+        Ok(Fetched { status, headers: without_hop_by_hop(&headers), body })
+    }
+}
+
+// The request in origin-form with the proxy's own fields removed. The
+// connection to the origin is used for this request only.
+fn request_head(request: &HttpRequest, headers: &HeaderMap) -> Vec<u8> {
+    let target = request.target();
+    let mut head = format!("{} {}", request.method(), target.path()).into_bytes();
+    if let Some(query) = target.query() {
+        head.extend_from_slice(format!("?{}", query).as_bytes());
+    }
+    head.extend_from_slice(b" HTTP/1.1\r\n");
+    let mut headers = without_hop_by_hop(headers);
+    // the target's authority wins over whatever Host the client sent
+    // https://www.rfc-editor.org/rfc/rfc9112#section-3.2.2
+    headers.remove("host");
+    headers.remove("content-length");
+    headers.append("Host", target.authority().unwrap_or(""));
+    if !request.body().is_empty() {
+        headers.append("Content-Length", request.body().len().to_string());
+    }
+    headers.append("Via", format!("1.1 {}", env!("CARGO_PKG_NAME")));
+    headers.append("Connection", "close");
+    for (name, value) in headers.iter() {
+        head.extend_from_slice(name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value);
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+fn without_hop_by_hop(headers: &HeaderMap) -> HeaderMap {
+    let listed: Vec<String> = headers.get_all("connection")
+        .flat_map(|value| value.split(|&b| b == b','))
+        .map(|name| String::from_utf8_lossy(name.trim_ascii()).to_ascii_lowercase())
+        .collect();
+    let mut kept = HeaderMap::new();
+    for (name, value) in headers.iter() {
+        let name_lower = name.to_ascii_lowercase();
+        if !HOP_BY_HOP.contains(&name_lower.as_str()) && !listed.contains(&name_lower) {
+            kept.append(name, value);
+        }
+    }
+    kept
+}
+
+// Whether the origin went away rather than answered, timed out or sent
+// something broken.
+fn is_dropped(err: &io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+// One line of the response head without its line ending.
+fn read_line(reader: &mut BufReader<TcpStream>, budget: &mut usize) -> io::Result<String> {
+    let mut line = Vec::new();
+    (&mut *reader).take(*budget as u64 + 1).read_until(b'\n', &mut line)?;
+    if line.len() > *budget {
+        return Err(invalid("response head too large"));
+    }
+    *budget -= line.len();
+    if line.pop() != Some(b'\n') {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "response head cut short"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| invalid("response head is not UTF-8"))
+}
+
+// The status line and header fields of the origin's response.
+fn read_head(reader: &mut BufReader<TcpStream>) -> io::Result<(HttpStatusCode, HeaderMap)> {
+    let mut budget = MAX_HEAD_SIZE;
+    let status_line = read_line(reader, &mut budget)?;
+    let code = match status_line.split(' ').collect::<Vec<_>>().as_slice() {
+        [version, code, ..] if version.starts_with("HTTP/1.") && code.len() == 3 => code.parse::<u16>().ok(),
+        _ => None,
+    };
+    let status = match code {
+        Some(code @ 100..=599) => HttpStatusCode::from_code(code),
+        _ => return Err(invalid("invalid status line")),
+    };
+
+    let mut headers = HeaderMap::new();
+    loop {
+        let line = read_line(reader, &mut budget)?;
+        if line.is_empty() {
+            return Ok((status, headers));
+        }
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !name.contains([' ', '\t']) => {
+                headers.append(name, value.trim())
+            }
+            _ => return Err(invalid("invalid header field")),
+        }
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> io::Result<Option<u64>> {
+    match headers.get("content-length") {
+        Some(value) => std::str::from_utf8(value).ok()
+            .and_then(|value| value.trim().parse().ok())
+            .map(Some)
+            .ok_or_else(|| invalid("invalid Content-Length")),
+        None => Ok(None),
+    }
+}
+
+// How the end of the origin's body is found.
+enum Framing {
+    Length(u64),
+    Chunked,
+    // the origin closes the connection
+    Close,
+}
+
+// The body of the origin's response, read as it is written to the client.
+// Only a body of known length keeps the client connection open, the others
+// are delimited by closing it.
+struct UpstreamBody {
+    reader: BufReader<TcpStream>,
+    framing: Framing,
+}
+
+impl Body for UpstreamBody {
+    fn size(&self) -> Option<u64> {
+        match self.framing {
+            Framing::Length(length) => Some(length),
+            Framing::Chunked | Framing::Close => None,
+        }
+    }
+
+    fn write(mut self: Box<Self>, stream: &mut dyn Write) -> Result<(), io::Error> {
+        match self.framing {
+            Framing::Length(length) => copy_exactly(&mut self.reader, stream, length),
+            Framing::Chunked => dechunk(&mut self.reader, stream),
+            Framing::Close => io::copy(&mut self.reader, stream).map(|_| ()),
+        }
+    }
+}
+
+fn copy_exactly(reader: &mut BufReader<TcpStream>, stream: &mut dyn Write, length: u64) -> io::Result<()> {
+    if io::copy(&mut reader.take(length), stream)? < length {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "origin closed before the end of the body"));
+    }
+    Ok(())
+}
+
+// Passes on the data of a chunked body without the chunk framing. Trailer
+// fields are dropped.
+// https://www.rfc-editor.org/rfc/rfc9112#section-7.1
+fn dechunk(reader: &mut BufReader<TcpStream>, stream: &mut dyn Write) -> io::Result<()> {
+    loop {
+        let mut budget = MAX_HEAD_SIZE;
+        let line = read_line(reader, &mut budget)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+        if size == 0 {
+            while !read_line(reader, &mut budget)?.is_empty() {}
+            return Ok(());
+        }
+        copy_exactly(reader, stream, size)?;
+        if !read_line(reader, &mut budget)?.is_empty() {
+            return Err(invalid("chunk longer than its size"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::config::{DestinationRule, ForwardProxyConfig};
+    use crate::handler::forward::serve;
+    use crate::test_util::request;
+    use crate::util::HttpStatusCode;
+
+    #[test]
+    fn test_forward() {
+        // an origin answering once with a chunked body
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = origin.local_addr().unwrap().port();
+        let received = thread::spawn(move || {
+            let (mut stream, _) = origin.accept().unwrap();
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            while !received.ends_with(b"\r\n\r\nhello") {
+                let n = stream.read(&mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close, X-Hop\r\nX-Hop: 1\r\n\
+                X-Origin: yes\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n").unwrap();
+            String::from_utf8(received).unwrap()
+        });
+
+        let settings = ForwardProxyConfig {
+            allow: vec![DestinationRule::new("127.0.0.1", 1..=65535)],
+            deny: vec![DestinationRule::new("127.0.0.1", port + 1..=port + 1)],
+            ..ForwardProxyConfig::default()
+        };
+        let denied = serve(&settings, &request(&format!("GET http://127.0.0.1:{}/ HTTP/1.1\r\nHost: x\r\n\r\n", port + 1)));
+        assert_eq!(*denied.status(), HttpStatusCode::Forbidden);
+
+        let response = serve(&settings, &request(&format!(
+            "POST http://127.0.0.1:{}/echo?x=1 HTTP/1.1\r\nHost: wrong\r\nProxy-Connection: keep-alive\r\n\
+             Proxy-Authorization: Basic eDp5\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+            port
+        )));
+        assert!(response.is_from_upstream());
+        let mut out = Vec::new();
+        response.send(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.contains("X-Origin: yes\r\n"));
+        assert!(!out.contains("X-Hop") && !out.contains("chunked"));
+        assert!(out.ends_with("\r\n\r\nhello, world"));
+
+        let received = received.join().unwrap();
+        assert!(received.starts_with("POST /echo?x=1 HTTP/1.1\r\n"));
+        assert!(received.contains(&format!("Host: 127.0.0.1:{}\r\n", port)));
+        // the decoded body goes on with its length
+        assert!(received.contains("Content-Length: 5\r\n"));
+        assert!(!received.contains("Proxy-") && !received.contains("wrong") && !received.contains("chunked"));
+    }
+
+    // An origin answering one request with `answer`.
+    fn origin(answer: &'static [u8]) -> u16 {
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = origin.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = origin.accept().unwrap();
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            while !received.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..n]);
+            }
+            stream.write_all(answer).unwrap();
+        });
+        port
+    }
+
+    #[test]
+    fn test_head_and_upgrade() {
+        let settings = ForwardProxyConfig { allow: vec![DestinationRule::new("127.0.0.1", 1..=65535)], ..ForwardProxyConfig::default() };
+
+        let port = origin(b"HTTP/1.1 200 OK\r\nContent-Length: 1234\r\n\r\n");
+        let response = serve(&settings, &request(&format!("HEAD http://127.0.0.1:{}/ HTTP/1.1\r\nHost: x\r\n\r\n", port)));
+        let mut out = Vec::new();
+        response.omit_body().send(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "HTTP/1.1 200 OK\r\nContent-Length: 1234\r\n\r\n");
+
+        let port = origin(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+        let response = serve(&settings, &request(&format!("HEAD http://127.0.0.1:{}/ HTTP/1.1\r\nHost: x\r\n\r\n", port)));
+        let mut out = Vec::new();
+        response.omit_body().send(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "HTTP/1.1 200 OK\r\n\r\n");
+
+        // nothing asked for the switch
+        let port = origin(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: upgrade\r\n\r\nHTTP/1.1 200 OK\r\n\r\n");
+        let response = serve(&settings, &request(&format!("GET http://127.0.0.1:{}/ HTTP/1.1\r\nHost: x\r\n\r\n", port)));
+        assert_eq!(*response.status(), HttpStatusCode::BadGateway);
+    }
+
+    #[test]
+    fn test_retry() {
+        // an origin hanging up on every other request
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = origin.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for (i, stream) in origin.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut received = Vec::new();
+                let mut buffer = [0; 1024];
+                while !received.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buffer).unwrap();
+                    received.extend_from_slice(&buffer[..n]);
+                }
+                if i % 2 == 1 {
+                    stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+                }
+            }
+        });
+        let settings = ForwardProxyConfig { allow: vec![DestinationRule::new("127.0.0.1", 1..=65535)], ..ForwardProxyConfig::default() };
+        let send = |method: &str| *serve(&settings, &request(&format!("{} http://127.0.0.1:{}/ HTTP/1.1\r\nHost: x\r\n\r\n", method, port))).status();
+
+        assert_eq!(send("GET"), HttpStatusCode::NoContent);
+        // sending it again could do whatever it does twice
+        assert_eq!(send("POST"), HttpStatusCode::BadGateway);
+        assert_eq!(send("DELETE"), HttpStatusCode::NoContent);
+    }
+}
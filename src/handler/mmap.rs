@@ -0,0 +1,259 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once, OnceLock, Weak};
+use std::time::SystemTime;
+use lazy_static::lazy_static;
+use crate::response::Body;
+
+// Written at most this much at a time, so a truncated file is noticed soon.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+type Identity = (u64, u64, u64, Option<SystemTime>);
+
+// A whole file mapped read-only, shared by every response serving it.
+pub(super) struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+    // kept for engines that send straight from the file instead
+    file: File,
+    // set once part of the file turned out to be gone, the missing pages
+    // read as zeros from then on
+    truncated: AtomicBool,
+}
+
+// SAFETY: the pointer is only a read-only view of the file, mapped for as
+// long as the `Mapping` lives. Nothing writes through it, so any thread may
+// read it, and it is unmapped by whichever thread drops the last reference.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` are exactly what `mmap` returned, and with
+        // the last reference gone no slice into the range is left.
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+lazy_static! {
+    static ref MAPPINGS: Mutex<HashMap<Identity, Weak<Mapping>>> = Mutex::new(HashMap::new());
+}
+
+static INSTALL_HANDLER: Once = Once::new();
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+// whatever handled SIGBUS before, for the faults that aren't ours
+static PREVIOUS_HANDLER: OnceLock<libc::sigaction> = OnceLock::new();
+
+thread_local! {
+    // the mapped range this thread is reading from and the mapping it belongs to
+    static READING: Cell<(usize, usize, *const AtomicBool)> = const { Cell::new((0, 0, ptr::null())) };
+}
+
+// Maps `file`, or shares the mapping of the same file if another response is
+// already serving it.
+pub(super) fn map(file: &File, metadata: &Metadata) -> io::Result<Arc<Mapping>> {
+    if metadata.len() == 0 || !metadata.is_file() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "only non-empty regular files can be mapped"));
+    }
+    INSTALL_HANDLER.call_once(install_handler);
+    let identity = (metadata.dev(), metadata.ino(), metadata.len(), metadata.modified().ok());
+    let mut mappings = MAPPINGS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(mapping) = mappings.get(&identity).and_then(Weak::upgrade) {
+        if !mapping.truncated.load(Ordering::SeqCst) {
+            return Ok(mapping);
+        }
+    }
+
+    let len = metadata.len() as usize;
+    let file = file.try_clone()?;
+    // SAFETY: a fresh read-only mapping at an address of the kernel's
+    // choosing, so it can't alias any memory in use. The descriptor is open
+    // for the duration of the call and may be closed later without affecting
+    // the mapping.
+    let ptr = unsafe { libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: only a hint about the range just mapped, it doesn't change
+    // its contents.
+    unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+    let mapping = Arc::new(Mapping { ptr, len, file, truncated: AtomicBool::new(false) });
+    mappings.retain(|_, mapping| mapping.strong_count() > 0);
+    mappings.insert(identity, Arc::downgrade(&mapping));
+    Ok(mapping)
+}
+
+// Maps `file` if it is at least `min_size` bytes, for bodies that would
+// otherwise be read and copied.
+pub(super) fn map_large(min_size: Option<u64>, file: &File, metadata: &Metadata) -> Option<Arc<Mapping>> {
+    if min_size.is_none_or(|min_size| metadata.len() < min_size) {
+        return None;
+    }
+    map(file, metadata)
+        .map_err(|err| log::warn!("failed to map a file of {} bytes, reading it instead: {}", metadata.len(), err))
+        .ok()
+}
+
+// Reading a page of a mapping the file no longer covers raises SIGBUS. For
+// the range a response is being written from, the page is replaced with
+// zeros and the mapping flagged, so the response fails instead of the
+// process. Any other SIGBUS goes to the handler installed before, which is
+// looked up first so it is known by the time ours can run. Something that
+// replaces the handler between the two calls is overwritten.
+fn install_handler() {
+    // SAFETY: sysconf only reads a system constant.
+    PAGE_SIZE.store(unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize, Ordering::SeqCst);
+    // SAFETY: the structures are plain C data, valid when zeroed, and
+    // `on_sigbus` has the signature SA_SIGINFO requires.
+    unsafe {
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGBUS, ptr::null(), &mut previous) != 0 {
+            log::error!("failed to handle SIGBUS: {}", io::Error::last_os_error());
+            return;
+        }
+        PREVIOUS_HANDLER.get_or_init(|| previous);
+
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigbus as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGBUS, &action, ptr::null_mut()) != 0 {
+            log::error!("failed to handle SIGBUS: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+// Runs on the faulting thread with SIGBUS blocked. Everything it does has to
+// be async-signal-safe: it reads a thread-local and atomics and calls mmap,
+// sigaction and raise, which POSIX allows in a handler. `READING` is
+// const-initialized and has no destructor, so `try_with` is a plain read of
+// the thread's slot that neither allocates nor registers anything.
+extern "C" fn on_sigbus(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    // SAFETY: with SA_SIGINFO the kernel passes a valid siginfo_t.
+    let addr = unsafe { (*info).si_addr() } as usize;
+    let (start, end, truncated) = READING.try_with(Cell::get).unwrap_or((0, 0, ptr::null()));
+    if addr >= start && addr < end && !truncated.is_null() {
+        // flagged first, whoever reads the zeros checks the flag afterwards
+        // SAFETY: `truncated` points into the `Mapping` being written from,
+        // which `MappedBody::write` keeps alive while `READING` is set.
+        unsafe { (*truncated).store(true, Ordering::SeqCst) };
+        let page = addr & !(PAGE_SIZE.load(Ordering::Relaxed) - 1);
+        // SAFETY: the page lies within the mapping, which is only ever read.
+        // MAP_FIXED swaps it for a zero page in place, so the faulting read
+        // succeeds on return and sees zeros, while the rest of the mapping
+        // stays as it is and is unmapped as a whole later.
+        let zeros = unsafe {
+            libc::mmap(
+                page as *mut libc::c_void,
+                PAGE_SIZE.load(Ordering::Relaxed),
+                libc::PROT_READ,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                -1,
+                0,
+            )
+        };
+        if zeros != libc::MAP_FAILED {
+            return;
+        }
+    }
+    chain(signal, info, context);
+}
+
+// Hands a SIGBUS that isn't ours to the previous handler. The default action
+// is put back and the signal raised again, so it takes effect once this
+// handler returns, the same as without it. An ignored SIGBUS is put back as
+// well, a fault that comes again on return is fatal regardless.
+fn chain(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    // recorded before this handler was installed
+    let Some(previous) = PREVIOUS_HANDLER.get() else { return };
+    let handler = previous.sa_sigaction;
+    if handler == libc::SIG_DFL || handler == libc::SIG_IGN {
+        // SAFETY: reinstalls a disposition sigaction reported.
+        unsafe {
+            libc::sigaction(signal, previous, ptr::null_mut());
+            if handler == libc::SIG_DFL {
+                libc::raise(signal);
+            }
+        }
+    } else if previous.sa_flags & libc::SA_SIGINFO != 0 {
+        // SAFETY: the previous handler was installed with SA_SIGINFO, so it
+        // takes the same arguments as this one.
+        let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) = unsafe { std::mem::transmute(handler) };
+        handler(signal, info, context);
+    } else {
+        // SAFETY: a handler installed without SA_SIGINFO takes only the signal.
+        let handler: extern "C" fn(libc::c_int) = unsafe { std::mem::transmute(handler) };
+        handler(signal);
+    }
+}
+
+// A response body written straight from a mapping.
+pub(super) struct MappedBody(pub(super) Arc<Mapping>);
+
+impl Body for MappedBody {
+    fn size(&self) -> Option<u64> {
+        Some(self.0.len as u64)
+    }
+
+    // a file cut short makes the splice come up short as well
+    fn as_file(&self) -> Option<(&File, u64)> {
+        Some((&self.0.file, 0))
+    }
+
+    fn write(self: Box<Self>, stream: &mut dyn Write) -> Result<(), io::Error> {
+        let mapping = &self.0;
+        // SAFETY: the whole range is mapped readable for as long as `self`
+        // holds the mapping. A page the file no longer covers would fault,
+        // and the handler swaps in zeros before the read is retried.
+        let bytes = unsafe { slice::from_raw_parts(mapping.ptr as *const u8, mapping.len) };
+        for chunk in bytes.chunks(CHUNK_SIZE) {
+            let start = chunk.as_ptr() as usize;
+            READING.with(|reading| reading.set((start, start + chunk.len(), &mapping.truncated)));
+            let written = stream.write_all(chunk);
+            READING.with(|reading| reading.set((0, 0, ptr::null())));
+            if mapping.truncated.load(Ordering::SeqCst) {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "file truncated while being sent"));
+            }
+            written?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+    use crate::handler::mmap::{map, MappedBody};
+    use crate::response::Body;
+
+    #[test]
+    fn test_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large.bin");
+        fs::write(&path, vec![b'x'; 3 * 4096 + 10]).unwrap();
+
+        let file = File::open(&path).unwrap();
+        let first = map(&file, &file.metadata().unwrap()).unwrap();
+        let second = map(&file, &file.metadata().unwrap()).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        let mut out = Vec::new();
+        Box::new(MappedBody(first)).write(&mut out).unwrap();
+        assert_eq!(out.len(), 3 * 4096 + 10);
+        assert!(out.iter().all(|&byte| byte == b'x'));
+
+        // a truncated file fails the response, not the process
+        File::options().write(true).open(&path).unwrap().set_len(100).unwrap();
+        let mut out = Vec::new();
+        let err = Box::new(MappedBody(second)).write(&mut out).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,174 @@
+mod basic_auth;
+mod cache_admin;
+mod connect;
+mod error_pages;
+mod forward;
+mod mmap;
+mod open_files;
+mod static_files;
+mod webdav;
+
+use std::any::Any;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use crate::config::{Handler, Location, ServerConfig, WriteAccess};
+use crate::handler::basic_auth::Target;
+use crate::parser::ParserError;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::uri::TargetForm;
+use crate::util::{HttpMethod, HttpStatusCode};
+
+// Shared by every connection engine, so a request is answered the same way no
+// matter how its bytes arrived.
+pub(crate) fn handle(request: &mut HttpRequest, config: &ServerConfig) -> HttpResponse {
+    log::debug!("{:?} {} {}", request.src_addr(), request.method(), request.effective_uri());
+    if let Some(tls) = request.tls() {
+        log::debug!("{:?} {} {}, server name {:?}, ALPN {:?}", request.src_addr(), tls.protocol, tls.cipher, tls.server_name, tls.alpn);
+    }
+
+    // forwarded requests are about another server's paths
+    let forwarded = is_forwarded(config, request);
+    let location = if forwarded { None } else { route(config, request.path()) };
+    let response = run(config, location, request);
+    let response = error_pages::apply(config, location, forwarded, request, response);
+
+    let response = response.prefer_plain_error_body(wants_plain_text(request));
+    // tells the client the connection ends here, which engines go by as well
+    let response = if request.keep_alive() { response } else { response.header("Connection", "close") };
+    if *request.method() == HttpMethod::Head {
+        response.omit_body()
+    } else {
+        response
+    }
+}
+
+// Runs the handler, turning a panic into a 500 response. The payload is handed
+// back so the caller can re-raise it once the response is on its way.
+pub(crate) fn handle_or_500(request: &mut HttpRequest, config: &ServerConfig) -> (HttpResponse, Option<Box<dyn Any + Send>>) {
+    match panic::catch_unwind(AssertUnwindSafe(|| handle(request, config))) {
+        Ok(response) => (response, None),
+        Err(payload) => (HttpResponse::internal_server_error().header("Connection", "close"), Some(payload)),
+    }
+}
+
+// The answer to a request the parser gave up on. Nothing after the bad byte
+// can be trusted, so the connection is closed once it has been sent.
+pub(crate) fn parse_error(err: &ParserError, offset: usize, peer_addr: SocketAddr) -> HttpResponse {
+    log::debug!("{} sent a bad request, byte {}: {}", peer_addr, offset, err);
+    let status = err.status();
+    HttpResponse::new(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .header("Connection", "close")
+        .with_body(format!("{} {}: {}\n", status.code(), status.reason(), err))
+}
+
+// The location with the longest prefix matching `path`. Prefixes match
+// whole segments, `/docs` is not a prefix of `/docsfoo`.
+fn route<'a>(config: &'a ServerConfig, path: &str) -> Option<&'a Location> {
+    config.locations.iter()
+        .filter(|location| matches_prefix(&location.prefix, path))
+        .max_by_key(|location| location.prefix.len())
+}
+
+fn matches_prefix(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+fn run(config: &ServerConfig, location: Option<&Location>, request: &mut HttpRequest) -> HttpResponse {
+    let upload = *request.method() == HttpMethod::Post && location.is_some_and(takes_uploads);
+    // only the parts are left of multipart bodies larger than `body_size`,
+    // which nothing but an upload takes
+    if request.body_dropped() && !upload {
+        return HttpResponse::new(HttpStatusCode::ContentTooLarge);
+    }
+    match location {
+        Some(location) => match &location.handler {
+            Handler::Static { root } if upload => static_files::upload(root, location.write_access, request),
+            Handler::Static { root } if location.webdav => webdav::serve(config, location, root, request),
+            Handler::Static { root } => static_files::serve(config, root, location.write_access, request),
+            Handler::CacheAdmin { auth } => cache_admin::serve(config, auth, request),
+        },
+        // CONNECT has no path and never matches a location
+        None if *request.method() == HttpMethod::Connect => match &config.connect {
+            // the tunnel would have to be encrypted as well
+            Some(_) if request.tls().is_some() => HttpResponse::new(HttpStatusCode::NotImplemented),
+            Some(settings) => authorize_proxy(config, request).unwrap_or_else(|| connect::open(settings, request)),
+            None => HttpResponse::new(HttpStatusCode::NotImplemented),
+        },
+        None => match &config.forward_proxy {
+            Some(settings) if is_forwarded(config, request) => {
+                authorize_proxy(config, request).unwrap_or_else(|| forward::serve(settings, request))
+            }
+            _ => HttpResponse::not_found(),
+        },
+    }
+}
+
+// Writable static locations take multipart/form-data uploads on POST, unless
+// they speak WebDAV.
+fn takes_uploads(location: &Location) -> bool {
+    matches!(location.handler, Handler::Static { .. }) && !location.webdav && location.write_access != WriteAccess::ReadOnly
+}
+
+// With the forward proxy enabled absolute-form targets name the server the
+// request is for, without it they are served here like origin-form ones.
+fn is_forwarded(config: &ServerConfig, request: &HttpRequest) -> bool {
+    config.forward_proxy.is_some() && request.target().form() == TargetForm::Absolute
+}
+
+fn authorize_proxy(config: &ServerConfig, request: &HttpRequest) -> Option<HttpResponse> {
+    config.proxy_auth.as_ref().and_then(|auth| basic_auth::check(auth, Target::Proxy, request))
+}
+
+// The answer for a method the handler has nothing for. Registered methods
+// are known to the server and only not allowed here, an extension method it
+// doesn't know is not implemented at all.
+// https://www.rfc-editor.org/rfc/rfc9110#section-15.5.6
+fn method_not_allowed(method: &HttpMethod, allow: &str) -> HttpResponse {
+    match method {
+        HttpMethod::Extension(_) => HttpResponse::new(HttpStatusCode::NotImplemented),
+        _ => HttpResponse::new(HttpStatusCode::MethodNotAllowed).header("Allow", allow),
+    }
+}
+
+// Generated error bodies are HTML unless the client accepts text/plain but
+// no HTML.
+fn wants_plain_text(request: &HttpRequest) -> bool {
+    match request.headers().get_combined("accept") {
+        Some(accept) => {
+            let accept = String::from_utf8_lossy(&accept);
+            accept.contains("text/plain") && !accept.contains("html") && !accept.contains("*/*")
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use crate::config::{Handler, Location, ServerConfig};
+    use crate::handler::route;
+
+    #[test]
+    fn test_route() {
+        let mut config = ServerConfig::default();
+        for prefix in ["/", "/docs", "/static/"] {
+            config.locations.push(Location::new(prefix, Handler::Static { root: PathBuf::from(".") }));
+        }
+        fn routed<'a>(config: &'a ServerConfig, path: &str) -> Option<&'a str> {
+            route(config, path).map(|location| location.prefix.as_str())
+        }
+        assert_eq!(routed(&config, "/docs"), Some("/docs"));
+        assert_eq!(routed(&config, "/docs/a.html"), Some("/docs"));
+        assert_eq!(routed(&config, "/docsfoo"), Some("/"));
+        assert_eq!(routed(&config, "/static/a.css"), Some("/static/"));
+        assert_eq!(routed(&config, "/static"), Some("/"));
+
+        config.locations.remove(0);
+        assert_eq!(routed(&config, "/docsfoo"), None);
+    }
+}
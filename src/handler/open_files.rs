@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime};
+use lazy_static::lazy_static;
+use crate::config::OpenFileCacheConfig;
+use crate::handler::mmap::{self, MappedBody, Mapping};
+use crate::handler::static_files::{etag, resolve_index};
+use crate::response::Body;
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+// What serving a file takes, as it was when the file was opened.
+#[derive(Clone)]
+pub(super) struct OpenFile {
+    // the file actually served, the index file for a directory
+    pub(super) path: PathBuf,
+    pub(super) modified: Option<SystemTime>,
+    pub(super) etag: String,
+    content: Content,
+}
+
+#[derive(Clone)]
+enum Content {
+    Bytes(Arc<[u8]>),
+    Descriptor(Arc<File>, u64),
+    Mapped(Arc<Mapping>),
+}
+
+impl OpenFile {
+    pub(super) fn body(&self) -> Box<dyn Body> {
+        match &self.content {
+            Content::Bytes(bytes) => Box::new(SharedBytes(bytes.clone())),
+            Content::Descriptor(file, len) => Box::new(SharedFile { file: file.clone(), len: *len }),
+            Content::Mapped(mapping) => Box::new(MappedBody(mapping.clone())),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match &self.content {
+            Content::Bytes(bytes) => bytes.len() as u64,
+            Content::Descriptor(..) | Content::Mapped(_) => 0,
+        }
+    }
+}
+
+struct Entry {
+    file: OpenFile,
+    // what identifies the file served, a replaced file has another inode
+    identity: (u64, u64, u64, Option<SystemTime>),
+    checked: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    // by the path asked for
+    entries: HashMap<PathBuf, Entry>,
+    // of the contents kept in memory
+    bytes: u64,
+    // orders the entries by use
+    clock: u64,
+}
+
+lazy_static! {
+    static ref OPEN_FILES: Mutex<State> = Mutex::new(State::default());
+}
+
+fn lock_state() -> MutexGuard<'static, State> {
+    OPEN_FILES.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn identity(metadata: &Metadata) -> (u64, u64, u64, Option<SystemTime>) {
+    (metadata.dev(), metadata.ino(), metadata.len(), metadata.modified().ok())
+}
+
+// Opens the file at `path`, or the index file if it is a directory. A file
+// opened before is served as it was found, without touching the file system,
+// until `valid_for` has passed and it is checked for changes. Files too large
+// to keep in memory are mapped if at least `mmap_min_size`.
+pub(super) fn open(config: &OpenFileCacheConfig, mmap_min_size: Option<u64>, path: &Path) -> io::Result<OpenFile> {
+    let mut state = lock_state();
+    state.clock += 1;
+    let clock = state.clock;
+    let known = match state.entries.get_mut(path) {
+        Some(entry) if entry.checked.elapsed() < config.valid_for => {
+            entry.last_used = clock;
+            return Ok(entry.file.clone());
+        }
+        Some(entry) => Some(entry.identity),
+        None => None,
+    };
+    drop(state);
+
+    let resolved = resolve_index(path);
+    let metadata = match fs::metadata(&resolved) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            forget(path);
+            return Err(err);
+        }
+    };
+    if known == Some(identity(&metadata)) {
+        let mut state = lock_state();
+        if let Some(entry) = state.entries.get_mut(path) {
+            entry.checked = Instant::now();
+            entry.last_used = clock;
+            return Ok(entry.file.clone());
+        }
+    }
+
+    let mut file = File::open(&resolved)?;
+    let metadata = file.metadata()?;
+    let content = if metadata.len() <= config.max_file_size {
+        let mut bytes = Vec::with_capacity(metadata.len() as usize);
+        file.read_to_end(&mut bytes)?;
+        Content::Bytes(bytes.into())
+    } else if let Some(mapping) = mmap::map_large(mmap_min_size, &file, &metadata) {
+        Content::Mapped(mapping)
+    } else {
+        Content::Descriptor(Arc::new(file), metadata.len())
+    };
+    let opened = OpenFile { path: resolved, modified: metadata.modified().ok(), etag: etag(&metadata), content };
+
+    let mut state = lock_state();
+    if let Some(replaced) = state.entries.remove(path) {
+        state.bytes -= replaced.file.size();
+    }
+    state.bytes += opened.size();
+    let entry = Entry { file: opened.clone(), identity: identity(&metadata), checked: Instant::now(), last_used: clock };
+    state.entries.insert(path.to_path_buf(), entry);
+    while state.entries.len() > config.max_entries || state.bytes > config.max_bytes {
+        let oldest = state.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(path, _)| path.clone());
+        if let Some(evicted) = oldest.and_then(|oldest| state.entries.remove(&oldest)) {
+            state.bytes -= evicted.file.size();
+        }
+    }
+    Ok(opened)
+}
+
+// Drops what is known about `path` and everything below it, which the server
+// itself just changed.
+pub(super) fn forget(path: &Path) {
+    let mut state = lock_state();
+    let mut freed = 0;
+    state.entries.retain(|requested, entry| {
+        let keep = !requested.starts_with(path) && !entry.file.path.starts_with(path);
+        if !keep {
+            freed += entry.file.size();
+        }
+        keep
+    });
+    state.bytes -= freed;
+}
+
+// A file read into memory, shared by every response serving it.
+struct SharedBytes(Arc<[u8]>);
+
+impl Body for SharedBytes {
+    fn size(&self) -> Option<u64> {
+        Some(self.0.len() as u64)
+    }
+
+    fn write(self: Box<Self>, stream: &mut dyn Write) -> Result<(), io::Error> {
+        stream.write_all(&self.0)
+    }
+}
+
+// A descriptor shared by every response serving the file. It is read at
+// explicit offsets so they don't move each other's position.
+struct SharedFile {
+    file: Arc<File>,
+    len: u64,
+}
+
+impl Body for SharedFile {
+    fn size(&self) -> Option<u64> {
+        Some(self.len)
+    }
+
+    fn as_file(&self) -> Option<(&File, u64)> {
+        Some((&self.file, 0))
+    }
+
+    fn write(self: Box<Self>, stream: &mut dyn Write) -> Result<(), io::Error> {
+        let mut buf = vec![0; READ_BUFFER_SIZE];
+        let mut offset = 0;
+        while offset < self.len {
+            let wanted = (self.len - offset).min(READ_BUFFER_SIZE as u64) as usize;
+            let n = self.file.read_at(&mut buf[..wanted], offset)?;
+            if n == 0 {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "file shrank while being sent"));
+            }
+            stream.write_all(&buf[..n])?;
+            offset += n as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+    use crate::config::OpenFileCacheConfig;
+    use crate::handler::open_files::{forget, open};
+    use crate::response::Body;
+
+    fn read(body: Box<dyn Body>) -> String {
+        let mut out = Vec::new();
+        body.write(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_open_files() {
+        let root = tempfile::tempdir().unwrap();
+        let small = root.path().join("small.txt");
+        let large = root.path().join("large.txt");
+        fs::write(&small, "small").unwrap();
+        fs::write(&large, "large enough").unwrap();
+        let mut config = OpenFileCacheConfig { max_file_size: 8, valid_for: Duration::from_secs(60), ..OpenFileCacheConfig::default() };
+
+        let opened = open(&config, None, &small).unwrap();
+        assert_eq!(read(opened.body()), "small");
+        assert_eq!(read(open(&config, None, &large).unwrap().body()), "large enough");
+
+        // trusted until checked again
+        fs::write(&small, "changed").unwrap();
+        let cached = open(&config, None, &small).unwrap();
+        assert_eq!(read(cached.body()), "small");
+        assert_eq!(cached.etag, opened.etag);
+        forget(root.path());
+        assert_eq!(read(open(&config, None, &small).unwrap().body()), "changed");
+
+        // a replaced file is noticed once it is checked
+        config.valid_for = Duration::ZERO;
+        fs::write(root.path().join("new.txt"), "new").unwrap();
+        fs::rename(root.path().join("new.txt"), &small).unwrap();
+        assert_eq!(read(open(&config, None, &small).unwrap().body()), "new");
+        fs::remove_file(&small).unwrap();
+        assert!(open(&config, None, &small).is_err());
+    }
+}
@@ -0,0 +1,458 @@
+use std::ffi::OsStr;
+use std::fs::{self, File, Metadata, Permissions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::config::{ServerConfig, WriteAccess};
+use crate::handler::mmap::{self, MappedBody};
+use crate::handler::{method_not_allowed, open_files};
+use crate::multipart::Part;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::util::{format_http_date, parse_http_date, HttpMethod, HttpStatusCode};
+
+const INDEX_FILE: &str = "index.html";
+// Uploads are written to a file named like this before being renamed into
+// place. They are never served or listed.
+const UPLOAD_PREFIX: &str = ".upload-";
+// What an uploaded file that doesn't replace another one can be read by.
+const UPLOAD_MODE: u32 = 0o644;
+
+pub(crate) fn serve(config: &ServerConfig, root: &Path, write_access: WriteAccess, request: &HttpRequest) -> HttpResponse {
+    let path = match map_path(root, request.path()) {
+        Some(path) => path,
+        None => return HttpResponse::not_found(),
+    };
+
+    match (request.method(), write_access) {
+        (HttpMethod::Get | HttpMethod::Head, _) => get(config, &path),
+        (HttpMethod::Put, WriteAccess::Write | WriteAccess::WriteCreateDirs) => {
+            put(&path, write_access == WriteAccess::WriteCreateDirs, request)
+        }
+        (HttpMethod::Delete, WriteAccess::Write | WriteAccess::WriteCreateDirs) => delete(&path, request),
+        (method, WriteAccess::ReadOnly) => method_not_allowed(method, "GET, HEAD"),
+        (method, _) => method_not_allowed(method, "GET, HEAD, POST, PUT, DELETE"),
+    }
+}
+
+fn get(config: &ServerConfig, path: &Path) -> HttpResponse {
+    if let Some(files) = &config.open_file_cache {
+        return match open_files::open(files, config.mmap_min_size, path) {
+            Ok(file) => {
+                let response = HttpResponse::ok().header("Content-Type", content_type(&file.path));
+                validators(response, &file.etag, file.modified).with_boxed_body(file.body())
+            }
+            Err(err) => error_response(path, err),
+        };
+    }
+    let path = resolve_index(path);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(err) => return error_response(&path, err),
+    };
+    let mut response = HttpResponse::ok().header("Content-Type", content_type(&path));
+    if let Ok(metadata) = file.metadata() {
+        response = with_validators(response, &metadata);
+        if let Some(mapping) = mmap::map_large(config.mmap_min_size, &file, &metadata) {
+            return response.with_boxed_body(Box::new(MappedBody(mapping)));
+        }
+    }
+    response.with_body(file)
+}
+
+// The file served for `path`, the index file if it is a directory.
+pub(crate) fn resolve_index(path: &Path) -> PathBuf {
+    if path.is_dir() { path.join(INDEX_FILE) } else { path.to_path_buf() }
+}
+
+// Writes the body to a temporary file next to the target and renames it into
+// place, so readers never see a partially written file.
+fn put(path: &Path, create_dirs: bool, request: &HttpRequest) -> HttpResponse {
+    let current = fs::metadata(path).ok();
+    if request.path().ends_with('/') || current.as_ref().is_some_and(Metadata::is_dir) {
+        return HttpResponse::new(HttpStatusCode::Conflict);
+    }
+    if let Some(failed) = check_preconditions(request, current.as_ref()) {
+        return failed;
+    }
+
+    let parent = match path.parent() {
+        Some(parent) => parent,
+        None => return HttpResponse::new(HttpStatusCode::Conflict),
+    };
+    if let Some(failed) = ensure_dir(parent, create_dirs) {
+        return failed;
+    }
+
+    // temporary files are only readable by their owner, a replaced file
+    // keeps its mode
+    let mode = current.as_ref().map_or(UPLOAD_MODE, |metadata| metadata.permissions().mode());
+    let written = tempfile::Builder::new().prefix(UPLOAD_PREFIX).tempfile_in(parent).and_then(|mut file| {
+        file.write_all(request.body())?;
+        file.as_file().set_permissions(Permissions::from_mode(mode))?;
+        file.as_file().sync_all()?;
+        file.persist(path).map_err(|err| err.error)
+    });
+    open_files::forget(path);
+    let metadata = match written.and_then(|file| file.metadata()) {
+        Ok(metadata) => metadata,
+        Err(err) => return error_response(path, err),
+    };
+
+    let status = if current.is_some() { HttpStatusCode::NoContent } else { HttpStatusCode::Created };
+    with_validators(HttpResponse::new(status), &metadata)
+}
+
+// Saves the file parts of a multipart/form-data body into the directory the
+// path names, under the name the client gave each, written like PUT writes.
+// Fields without a file are ignored.
+pub(crate) fn upload(root: &Path, write_access: WriteAccess, request: &mut HttpRequest) -> HttpResponse {
+    let dir = match map_path(root, request.path()) {
+        Some(path) => path,
+        None => return HttpResponse::not_found(),
+    };
+    let parts = match request.take_parts() {
+        Ok(parts) => parts,
+        Err(err) => {
+            log::debug!("{:?} sent an unusable upload: {}", request.src_addr(), err);
+            return HttpResponse::new(err.status());
+        }
+    };
+    if let Some(failed) = ensure_dir(&dir, write_access == WriteAccess::WriteCreateDirs) {
+        return failed;
+    }
+
+    let mut saved = 0;
+    let mut created = false;
+    for part in parts {
+        let name = match part.filename() {
+            // what a browser sends for a file input left empty
+            None | Some("") => continue,
+            Some(filename) => match upload_name(filename) {
+                Some(name) => name,
+                None => return HttpResponse::new(HttpStatusCode::BadRequest),
+            },
+        };
+        let path = dir.join(name);
+        let current = fs::metadata(&path).ok();
+        if current.as_ref().is_some_and(Metadata::is_dir) {
+            return HttpResponse::new(HttpStatusCode::Conflict);
+        }
+        log::debug!(
+            "{:?} uploaded {} from field {:?}, {} bytes of {:?}",
+            request.src_addr(),
+            path.display(),
+            part.name(),
+            part.len(),
+            part.headers().get("content-type").map(String::from_utf8_lossy),
+        );
+        let mode = current.as_ref().map_or(UPLOAD_MODE, |metadata| metadata.permissions().mode());
+        let written = save(part, &dir, &path, mode);
+        open_files::forget(&path);
+        if let Err(err) = written {
+            return error_response(&path, err);
+        }
+        saved += 1;
+        created |= current.is_none();
+    }
+
+    match saved {
+        0 => HttpResponse::new(HttpStatusCode::BadRequest),
+        _ if created => HttpResponse::new(HttpStatusCode::Created),
+        _ => HttpResponse::new(HttpStatusCode::NoContent),
+    }
+}
+
+// The last segment of a client side file name, some browsers send the full
+// path. `None` for names that can't be stored as they are.
+fn upload_name(filename: &str) -> Option<&str> {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or(filename);
+    match name {
+        "" | "." | ".." => None,
+        name if name.contains('\0') || is_upload(OsStr::new(name)) => None,
+        name => Some(name),
+    }
+}
+
+fn save(part: Part, dir: &Path, path: &Path, mode: u32) -> io::Result<()> {
+    let temp = tempfile::Builder::new().prefix(UPLOAD_PREFIX).tempfile_in(dir)?.into_temp_path();
+    part.save(&temp)?;
+    fs::set_permissions(&temp, Permissions::from_mode(mode))?;
+    File::open(&temp)?.sync_all()?;
+    temp.persist(path).map_err(|err| err.error)
+}
+
+// Makes sure `dir` is a directory, creating it if allowed. Returns the
+// response to send instead if it can't be.
+fn ensure_dir(dir: &Path, create_dirs: bool) -> Option<HttpResponse> {
+    if dir.is_dir() {
+        return None;
+    }
+    if !create_dirs {
+        return Some(HttpResponse::new(HttpStatusCode::Conflict));
+    }
+    match fs::create_dir_all(dir) {
+        Ok(()) => None,
+        // some ancestor is a file
+        Err(err) if matches!(err.kind(), ErrorKind::NotADirectory | ErrorKind::AlreadyExists) => {
+            Some(HttpResponse::new(HttpStatusCode::Conflict))
+        }
+        Err(err) => Some(error_response(dir, err)),
+    }
+}
+
+fn delete(path: &Path, request: &HttpRequest) -> HttpResponse {
+    let current = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) => return error_response(path, err),
+    };
+    if current.is_dir() {
+        return HttpResponse::new(HttpStatusCode::Conflict);
+    }
+    if let Some(failed) = check_preconditions(request, Some(&current)) {
+        return failed;
+    }
+    let removed = fs::remove_file(path);
+    open_files::forget(path);
+    match removed {
+        Ok(()) => HttpResponse::new(HttpStatusCode::NoContent),
+        Err(err) => error_response(path, err),
+    }
+}
+
+// Evaluates the preconditions of a state changing request against the file
+// as it is now, `None` if it doesn't exist. Returns the response to send
+// instead if one fails.
+// https://www.rfc-editor.org/rfc/rfc9110#section-13.2.2
+pub(crate) fn check_preconditions(request: &HttpRequest, current: Option<&Metadata>) -> Option<HttpResponse> {
+    let headers = request.headers();
+    let etag = current.map(etag);
+    let failed = || Some(HttpResponse::new(HttpStatusCode::PreconditionFailed));
+
+    if let Some(if_match) = headers.get_combined("if-match") {
+        if !etag_matches(&String::from_utf8_lossy(&if_match), etag.as_deref(), false) {
+            return failed();
+        }
+    } else if let Some(since) = request.header_str("if-unmodified-since").and_then(parse_http_date) {
+        let modified = current.and_then(|metadata| metadata.modified().ok());
+        // HTTP dates only have a resolution of seconds
+        let modified = modified.and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|age| age.as_secs());
+        let since = since.duration_since(UNIX_EPOCH).map(|age| age.as_secs()).unwrap_or(0);
+        if modified.is_some_and(|modified| modified > since) {
+            return failed();
+        }
+    }
+    if let Some(if_none_match) = headers.get_combined("if-none-match") {
+        if etag_matches(&String::from_utf8_lossy(&if_none_match), etag.as_deref(), true) {
+            return failed();
+        }
+    }
+    None
+}
+
+// Whether `list`, "*" or comma separated entity tags, matches `etag`.
+// Weak tags only ever match with the weak comparison.
+fn etag_matches(list: &str, etag: Option<&str>, weak: bool) -> bool {
+    let etag = match etag {
+        Some(etag) => etag,
+        None => return false,
+    };
+    if list.trim() == "*" {
+        return true;
+    }
+    list.split(',').map(str::trim).any(|candidate| match candidate.strip_prefix("W/") {
+        Some(candidate) => weak && candidate == etag,
+        None => candidate == etag,
+    })
+}
+
+// A strong validator from size and modification time, which is what changes
+// when a file is replaced.
+pub(crate) fn etag(metadata: &Metadata) -> String {
+    let modified = metadata.modified().ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|age| age.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", metadata.len(), modified)
+}
+
+fn with_validators(response: HttpResponse, metadata: &Metadata) -> HttpResponse {
+    validators(response, &etag(metadata), metadata.modified().ok())
+}
+
+fn validators(response: HttpResponse, etag: &str, modified: Option<SystemTime>) -> HttpResponse {
+    let response = response.header("ETag", etag);
+    match modified {
+        Some(modified) => response.header("Last-Modified", &format_http_date(modified)),
+        None => response,
+    }
+}
+
+pub(crate) fn error_response(path: &Path, err: io::Error) -> HttpResponse {
+    match err.kind() {
+        ErrorKind::NotFound => HttpResponse::not_found(),
+        ErrorKind::PermissionDenied => HttpResponse::new(HttpStatusCode::Forbidden),
+        _ => {
+            log::error!("failed to access {}: {}", path.display(), err);
+            HttpResponse::internal_server_error()
+        }
+    }
+}
+
+// Maps a request path below `root`, refusing anything that could climb out
+// of it or name an unfinished upload.
+pub(crate) fn map_path(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for segment in request_path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            segment if segment.contains('\\') || segment.contains('\0') => return None,
+            segment if is_upload(OsStr::new(segment)) => return None,
+            segment => path.push(segment),
+        }
+    }
+    Some(path)
+}
+
+// Whether a directory entry is an upload still being written.
+pub(crate) fn is_upload(name: &OsStr) -> bool {
+    name.as_encoded_bytes().starts_with(UPLOAD_PREFIX.as_bytes())
+}
+
+pub(crate) fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use crate::config::{ServerConfig, WriteAccess};
+    use crate::handler::static_files::{is_upload, map_path, serve, upload};
+    use crate::test_util::request_with_body;
+    use crate::util::HttpStatusCode;
+
+    #[test]
+    fn test_map_path() {
+        let root = Path::new("/srv");
+        assert_eq!(map_path(root, "/a//b/./c.txt"), Some(PathBuf::from("/srv/a/b/c.txt")));
+        assert_eq!(map_path(root, "/a/../../etc/passwd"), None);
+    }
+
+    #[test]
+    fn test_put_and_delete() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let config = ServerConfig::default();
+        let status = |head: &str, body: &str, access| *serve(&config, root, access, &request_with_body(head, body)).status();
+
+        assert_eq!(status("PUT /a.txt HTTP/1.1", "one", WriteAccess::ReadOnly), HttpStatusCode::MethodNotAllowed);
+        assert_eq!(status("PATCH /a.txt HTTP/1.1", "one", WriteAccess::Write), HttpStatusCode::MethodNotAllowed);
+        assert_eq!(status("BREW /a.txt HTTP/1.1", "", WriteAccess::Write), HttpStatusCode::NotImplemented);
+        assert_eq!(status("PUT /dir/a.txt HTTP/1.1", "one", WriteAccess::Write), HttpStatusCode::Conflict);
+        assert_eq!(status("PUT /dir/a.txt HTTP/1.1", "one", WriteAccess::WriteCreateDirs), HttpStatusCode::Created);
+        assert_eq!(fs::read_to_string(root.join("dir/a.txt")).unwrap(), "one");
+
+        let response = serve(&config, root, WriteAccess::Write, &request_with_body("PUT /dir/a.txt HTTP/1.1", "two"));
+        assert_eq!(*response.status(), HttpStatusCode::NoContent);
+        let (head, _) = response.into_parts();
+        let head = String::from_utf8(head).unwrap();
+        let etag = head.lines().find_map(|line| line.strip_prefix("ETag: ")).unwrap().to_string();
+
+        let create_only = "PUT /dir/a.txt HTTP/1.1\r\nIf-None-Match: *";
+        assert_eq!(status(create_only, "three", WriteAccess::Write), HttpStatusCode::PreconditionFailed);
+        let stale = "PUT /dir/a.txt HTTP/1.1\r\nIf-Match: \"0-0\"";
+        assert_eq!(status(stale, "three", WriteAccess::Write), HttpStatusCode::PreconditionFailed);
+        let old = "DELETE /dir/a.txt HTTP/1.1\r\nIf-Unmodified-Since: Sun, 06 Nov 1994 08:49:37 GMT";
+        assert_eq!(status(old, "", WriteAccess::Write), HttpStatusCode::PreconditionFailed);
+        assert_eq!(fs::read_to_string(root.join("dir/a.txt")).unwrap(), "two");
+
+        let current = format!("DELETE /dir/a.txt HTTP/1.1\r\nIf-Match: {}", etag);
+        assert_eq!(status(&current, "", WriteAccess::Write), HttpStatusCode::NoContent);
+        assert!(!root.join("dir/a.txt").exists());
+        assert_eq!(status("DELETE /dir/a.txt HTTP/1.1", "", WriteAccess::Write), HttpStatusCode::NotFound);
+
+        // a file where a directory would have to be
+        assert_eq!(status("PUT /dir/b.txt HTTP/1.1", "one", WriteAccess::Write), HttpStatusCode::Created);
+        assert_eq!(status("PUT /dir/b.txt/c.txt HTTP/1.1", "one", WriteAccess::WriteCreateDirs), HttpStatusCode::Conflict);
+        assert_eq!(status("PUT /dir/b.txt/c/d.txt HTTP/1.1", "one", WriteAccess::WriteCreateDirs), HttpStatusCode::Conflict);
+    }
+
+    #[test]
+    fn test_upload_files() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let config = ServerConfig::default();
+        let status = |head: &str, body: &str| *serve(&config, root, WriteAccess::Write, &request_with_body(head, body)).status();
+        let mode = |name: &str| fs::metadata(root.join(name)).unwrap().permissions().mode() & 0o777;
+
+        assert_eq!(status("PUT /new.txt HTTP/1.1", "one"), HttpStatusCode::Created);
+        assert_eq!(mode("new.txt"), 0o644);
+        fs::set_permissions(root.join("new.txt"), Permissions::from_mode(0o640)).unwrap();
+        assert_eq!(status("PUT /new.txt HTTP/1.1", "two"), HttpStatusCode::NoContent);
+        assert_eq!(mode("new.txt"), 0o640);
+
+        // unfinished uploads can't be reached
+        fs::write(root.join(".upload-abc"), "partial").unwrap();
+        assert_eq!(status("GET /.upload-abc HTTP/1.1", ""), HttpStatusCode::NotFound);
+        assert_eq!(status("PUT /.upload-abc HTTP/1.1", "x"), HttpStatusCode::NotFound);
+        assert_eq!(fs::read_to_string(root.join(".upload-abc")).unwrap(), "partial");
+    }
+
+    #[test]
+    fn test_multipart_upload() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let post = |path: &str, files: &[(&str, &str)]| {
+            let mut body = String::from("--xyz\r\nContent-Disposition: form-data; name=\"comment\"\r\n\r\nignored\r\n");
+            for (filename, content) in files {
+                body += &format!("--xyz\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n{}\r\n", filename, content);
+            }
+            body += "--xyz--\r\n";
+            let head = format!("POST {} HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=xyz", path);
+            let mut request = request_with_body(&head, &body);
+            *upload(root, WriteAccess::Write, &mut request).status()
+        };
+
+        assert_eq!(post("/", &[("a.txt", "one"), ("../b.txt", "two"), ("", "")]), HttpStatusCode::Created);
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "one");
+        assert_eq!(fs::read_to_string(root.join("b.txt")).unwrap(), "two");
+        assert_eq!(fs::metadata(root.join("a.txt")).unwrap().permissions().mode() & 0o777, 0o644);
+        assert_eq!(post("/", &[("a.txt", "three")]), HttpStatusCode::NoContent);
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "three");
+
+        assert_eq!(post("/", &[]), HttpStatusCode::BadRequest);
+        assert_eq!(post("/", &[("..", "x")]), HttpStatusCode::BadRequest);
+        assert_eq!(post("/", &[(".upload-x", "x")]), HttpStatusCode::BadRequest);
+        assert_eq!(post("/missing/", &[("a.txt", "x")]), HttpStatusCode::Conflict);
+        assert!(fs::read_dir(root).unwrap().all(|entry| !is_upload(&entry.unwrap().file_name())));
+
+        let mut request = request_with_body("POST / HTTP/1.1\r\nContent-Type: text/plain", "a.txt");
+        assert_eq!(*upload(root, WriteAccess::Write, &mut request).status(), HttpStatusCode::UnsupportedMediaType);
+    }
+}
@@ -0,0 +1,243 @@
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+use roxmltree::Document;
+use uuid::Uuid;
+use crate::handler::static_files::error_response;
+use crate::handler::webdav::xml;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::util::HttpStatusCode;
+
+// Clients asking for longer, or "Infinite", get this. They refresh a lock
+// they still need.
+const MAX_TIMEOUT: Duration = Duration::from_secs(3600);
+
+// A write lock on a file or directory, for directories with depth infinity
+// also on everything below it.
+// https://www.rfc-editor.org/rfc/rfc4918#section-6
+struct Lock {
+    token: String,
+    root: PathBuf,
+    // the request path the lock was taken on
+    href: String,
+    deep: bool,
+    exclusive: bool,
+    owner: Option<String>,
+    expires: Instant,
+}
+
+impl Lock {
+    fn covers(&self, path: &Path) -> bool {
+        path == self.root || (self.deep && path.starts_with(&self.root))
+    }
+
+    // Whether changing `path`, and everything below it if `descendants`,
+    // runs into this lock.
+    fn affects(&self, path: &Path, descendants: bool) -> bool {
+        self.covers(path) || (descendants && self.root.starts_with(path))
+    }
+
+    fn active_lock(&self) -> String {
+        let scope = if self.exclusive { "exclusive" } else { "shared" };
+        let depth = if self.deep { "infinity" } else { "0" };
+        let owner = self.owner.as_deref().map(|owner| format!("<D:owner>{}</D:owner>", owner)).unwrap_or_default();
+        let remaining = self.expires.saturating_duration_since(Instant::now()).as_secs();
+        format!(
+            "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:{}/></D:lockscope>\
+             <D:depth>{}</D:depth>{}<D:timeout>Second-{}</D:timeout>\
+             <D:locktoken><D:href>{}</D:href></D:locktoken><D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+            scope, depth, owner, remaining, self.token, self.href
+        )
+    }
+}
+
+lazy_static! {
+    static ref LOCKS: Mutex<Vec<Lock>> = Mutex::new(Vec::new());
+}
+
+// The lock table without the locks that have timed out.
+fn active() -> MutexGuard<'static, Vec<Lock>> {
+    let mut locks = LOCKS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let now = Instant::now();
+    locks.retain(|lock| lock.expires > now);
+    locks
+}
+
+// Refuses to change `path` while a lock the request doesn't hold the token of
+// covers it. Returns the response to send instead.
+pub(super) fn check(path: &Path, descendants: bool, request: &HttpRequest) -> Option<HttpResponse> {
+    let tokens = submitted_tokens(request);
+    let locks = active();
+    let blocking = locks.iter().find(|lock| lock.affects(path, descendants) && !tokens.contains(&lock.token))?;
+    Some(xml::response(
+        HttpStatusCode::Locked,
+        &format!("<D:error xmlns:D=\"DAV:\"><D:lock-token-submitted><D:href>{}</D:href></D:lock-token-submitted></D:error>", blocking.href),
+    ))
+}
+
+// The lock tokens in the If header. Its conditions aren't evaluated, a
+// request holding the token of a lock may change what it covers.
+// https://www.rfc-editor.org/rfc/rfc4918#section-10.4
+fn submitted_tokens(request: &HttpRequest) -> Vec<String> {
+    let value = match request.headers().get_combined("if") {
+        Some(value) => String::from_utf8_lossy(&value).into_owned(),
+        None => return Vec::new(),
+    };
+    value.split('<')
+        .skip(1)
+        .filter_map(|rest| rest.split_once('>'))
+        .map(|(token, _)| token.to_string())
+        .collect()
+}
+
+// The <D:activelock> of every lock covering `path`.
+pub(super) fn discovery(path: &Path) -> String {
+    active().iter().filter(|lock| lock.covers(path)).map(Lock::active_lock).collect()
+}
+
+// Drops the locks on `path` and everything below it, which no longer exists.
+pub(super) fn forget(path: &Path) {
+    active().retain(|lock| !lock.root.starts_with(path));
+}
+
+// Locks `path`, creating an empty file if nothing is there yet. Without a
+// body the locks the request has tokens for are refreshed instead.
+// https://www.rfc-editor.org/rfc/rfc4918#section-9.10
+pub(super) fn lock(path: &Path, request: &HttpRequest) -> HttpResponse {
+    let timeout = timeout(request);
+    let document = match xml::parse(request.body()) {
+        Ok(Some(document)) => document,
+        Ok(None) => return refresh(path, timeout, request),
+        Err(()) => return HttpResponse::new(HttpStatusCode::BadRequest),
+    };
+    let (exclusive, owner) = match parse_lockinfo(&document) {
+        Some(info) => info,
+        None => return HttpResponse::new(HttpStatusCode::BadRequest),
+    };
+    let deep = match request.header_str("depth").map(str::trim) {
+        None | Some("infinity") => true,
+        Some("0") => false,
+        Some(_) => return HttpResponse::new(HttpStatusCode::BadRequest),
+    };
+
+    let mut locks = active();
+    if locks.iter().any(|lock| lock.affects(path, deep) && (exclusive || lock.exclusive)) {
+        return xml::error(HttpStatusCode::Locked, "no-conflicting-lock");
+    }
+    let created = match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(_) => true,
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => false,
+        Err(err) if err.kind() == ErrorKind::NotFound => return HttpResponse::new(HttpStatusCode::Conflict),
+        Err(err) => return error_response(path, err),
+    };
+
+    let lock = Lock {
+        token: format!("urn:uuid:{}", Uuid::new_v4()),
+        root: path.to_path_buf(),
+        href: xml::href(request.path(), path.is_dir()),
+        deep,
+        exclusive,
+        owner,
+        expires: Instant::now() + timeout,
+    };
+    let status = if created { HttpStatusCode::Created } else { HttpStatusCode::OK };
+    let response = discovery_response(status, &lock).header("Lock-Token", &format!("<{}>", lock.token));
+    locks.push(lock);
+    response
+}
+
+fn refresh(path: &Path, timeout: Duration, request: &HttpRequest) -> HttpResponse {
+    let tokens = submitted_tokens(request);
+    let mut locks = active();
+    match locks.iter_mut().find(|lock| lock.covers(path) && tokens.contains(&lock.token)) {
+        Some(lock) => {
+            lock.expires = Instant::now() + timeout;
+            discovery_response(HttpStatusCode::OK, lock)
+        }
+        None => xml::error(HttpStatusCode::PreconditionFailed, "lock-token-submitted"),
+    }
+}
+
+fn discovery_response(status: HttpStatusCode, lock: &Lock) -> HttpResponse {
+    xml::response(
+        status,
+        &format!("<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>", lock.active_lock()),
+    )
+}
+
+// The scope and the owner, as markup, of a <D:lockinfo>. Only write locks
+// exist.
+fn parse_lockinfo(document: &Document) -> Option<(bool, Option<String>)> {
+    let root = document.root_element();
+    if !xml::is_dav(root, "lockinfo") {
+        return None;
+    }
+    let mut exclusive = None;
+    let mut owner = None;
+    for child in xml::child_elements(root) {
+        if xml::is_dav(child, "lockscope") {
+            exclusive = xml::child_elements(child).find_map(|scope| {
+                if xml::is_dav(scope, "exclusive") {
+                    Some(true)
+                } else if xml::is_dav(scope, "shared") {
+                    Some(false)
+                } else {
+                    None
+                }
+            });
+        } else if xml::is_dav(child, "locktype") {
+            if !xml::child_elements(child).any(|kind| xml::is_dav(kind, "write")) {
+                return None;
+            }
+        } else if xml::is_dav(child, "owner") {
+            // usually a <D:href>, which is worth keeping as such
+            let text = xml::escape(&xml::text(child));
+            owner = Some(if xml::child_elements(child).any(|node| xml::is_dav(node, "href")) {
+                format!("<D:href>{}</D:href>", text.trim())
+            } else {
+                text
+            });
+        }
+    }
+    exclusive.map(|exclusive| (exclusive, owner))
+}
+
+// The first timeout of the Timeout header we can use, "Second-N" or
+// "Infinite".
+// https://www.rfc-editor.org/rfc/rfc4918#section-10.7
+fn timeout(request: &HttpRequest) -> Duration {
+    let value = request.header_str("timeout").unwrap_or("");
+    value.split(',')
+        .map(str::trim)
+        .find_map(|timeout| match timeout {
+            "Infinite" => Some(MAX_TIMEOUT),
+            timeout => timeout.strip_prefix("Second-")
+                .and_then(|seconds| seconds.parse().ok())
+                .map(|seconds| Duration::from_secs(seconds).min(MAX_TIMEOUT)),
+        })
+        .unwrap_or(MAX_TIMEOUT)
+}
+
+// https://www.rfc-editor.org/rfc/rfc4918#section-9.11
+pub(super) fn unlock(path: &Path, request: &HttpRequest) -> HttpResponse {
+    let token = request.header_str("lock-token")
+        .map(str::trim)
+        .and_then(|token| token.strip_prefix('<'))
+        .and_then(|token| token.strip_suffix('>'));
+    let token = match token {
+        Some(token) => token,
+        None => return HttpResponse::new(HttpStatusCode::BadRequest),
+    };
+    let mut locks = active();
+    match locks.iter().position(|lock| lock.token == token && lock.covers(path)) {
+        Some(index) => {
+            locks.remove(index);
+            HttpResponse::new(HttpStatusCode::NoContent)
+        }
+        None => xml::error(HttpStatusCode::Conflict, "lock-token-matches-request-uri"),
+    }
+}
@@ -0,0 +1,346 @@
+mod locks;
+mod props;
+mod xml;
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use crate::config::{Location, ServerConfig, WriteAccess};
+use crate::handler::{method_not_allowed, open_files, route};
+use crate::handler::static_files::{self, error_response, map_path};
+use crate::path;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::uri::{TargetForm, Uri};
+use crate::util::{HttpMethod, HttpStatusCode};
+
+const READ_METHODS: &str = "OPTIONS, GET, HEAD, PROPFIND";
+const ALL_METHODS: &str = "OPTIONS, GET, HEAD, PROPFIND, PUT, DELETE, PROPPATCH, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+
+// WebDAV class 1 and 2 on top of the static file tree. GET, HEAD and PUT are
+// the plain static ones, once the locks have been checked.
+// https://www.rfc-editor.org/rfc/rfc4918
+pub(crate) fn serve(config: &ServerConfig, location: &Location, root: &Path, request: &HttpRequest) -> HttpResponse {
+    let path = match map_path(root, request.path()) {
+        Some(path) => path,
+        None => return HttpResponse::not_found(),
+    };
+    let writable = location.write_access != WriteAccess::ReadOnly;
+
+    match request.method() {
+        HttpMethod::Options => HttpResponse::ok()
+            .header("DAV", "1, 2")
+            .header("MS-Author-Via", "DAV")
+            .header("Allow", if writable { ALL_METHODS } else { READ_METHODS }),
+        HttpMethod::Get | HttpMethod::Head => static_files::serve(config, root, location.write_access, request),
+        HttpMethod::Propfind => props::propfind(&path, request),
+        method if !writable && !method.is_safe() => method_not_allowed(method, READ_METHODS),
+        HttpMethod::Put => match locks::check(&path, false, request) {
+            Some(locked) => locked,
+            None => static_files::serve(config, root, location.write_access, request),
+        },
+        HttpMethod::Delete => delete(config, location, root, &path, request),
+        HttpMethod::Proppatch => props::proppatch(&path, request),
+        HttpMethod::Mkcol => mkcol(&path, request),
+        HttpMethod::Copy | HttpMethod::Move => copy_or_move(config, location, root, &path, request),
+        HttpMethod::Lock => locks::lock(&path, request),
+        HttpMethod::Unlock => locks::unlock(&path, request),
+        method => method_not_allowed(method, if writable { ALL_METHODS } else { READ_METHODS }),
+    }
+}
+
+// Unlike plain static DELETE, collections are removed with everything in
+// them.
+fn delete(config: &ServerConfig, location: &Location, root: &Path, path: &Path, request: &HttpRequest) -> HttpResponse {
+    if let Some(locked) = locks::check(path, true, request) {
+        return locked;
+    }
+    let response = if path.is_dir() {
+        match fs::remove_dir_all(path) {
+            Ok(()) => HttpResponse::new(HttpStatusCode::NoContent),
+            Err(err) => error_response(path, err),
+        }
+    } else {
+        static_files::serve(config, root, location.write_access, request)
+    };
+    if response.status().is_success() {
+        open_files::forget(path);
+        props::forget(path);
+        locks::forget(path);
+    }
+    response
+}
+
+// https://www.rfc-editor.org/rfc/rfc4918#section-9.3
+fn mkcol(path: &Path, request: &HttpRequest) -> HttpResponse {
+    if !request.body().is_empty() {
+        return HttpResponse::new(HttpStatusCode::UnsupportedMediaType);
+    }
+    if let Some(locked) = locks::check(path, false, request) {
+        return locked;
+    }
+    match fs::create_dir(path) {
+        Ok(()) => HttpResponse::new(HttpStatusCode::Created),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            HttpResponse::new(HttpStatusCode::MethodNotAllowed).header("Allow", ALL_METHODS)
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => HttpResponse::new(HttpStatusCode::Conflict),
+        Err(err) => error_response(path, err),
+    }
+}
+
+// Both only within the same location. Dead properties go along, locks stay
+// where they are.
+// https://www.rfc-editor.org/rfc/rfc4918#section-9.8
+fn copy_or_move(config: &ServerConfig, location: &Location, root: &Path, source: &Path, request: &HttpRequest) -> HttpResponse {
+    let moving = *request.method() == HttpMethod::Move;
+    let destination = match destination(config, request) {
+        Ok(destination) => destination,
+        Err(response) => return response,
+    };
+    if !route(config, &destination).is_some_and(|target| std::ptr::eq(target, location)) {
+        return HttpResponse::new(HttpStatusCode::BadGateway);
+    }
+    let target = match map_path(root, &destination) {
+        Some(target) => target,
+        None => return HttpResponse::new(HttpStatusCode::Forbidden),
+    };
+
+    let recursive = match request.header_str("depth").map(str::trim) {
+        None | Some("infinity") => true,
+        // a collection can only be moved as a whole
+        Some("0") if !moving => false,
+        Some(_) => return HttpResponse::new(HttpStatusCode::BadRequest),
+    };
+    let overwrite = match request.header_str("overwrite").map(str::trim) {
+        None | Some("T") => true,
+        Some("F") => false,
+        Some(_) => return HttpResponse::new(HttpStatusCode::BadRequest),
+    };
+    let is_dir = match fs::metadata(source) {
+        Ok(metadata) => metadata.is_dir(),
+        Err(err) => return error_response(source, err),
+    };
+    if target.starts_with(source) || (is_dir && source.starts_with(&target)) {
+        return HttpResponse::new(HttpStatusCode::Forbidden);
+    }
+    let existed = target.symlink_metadata().is_ok();
+    if existed && !overwrite {
+        return HttpResponse::new(HttpStatusCode::PreconditionFailed);
+    }
+    let locked = if moving { locks::check(source, true, request) } else { None };
+    if let Some(locked) = locked.or_else(|| locks::check(&target, true, request)) {
+        return locked;
+    }
+    if !target.parent().is_some_and(Path::is_dir) {
+        return HttpResponse::new(HttpStatusCode::Conflict);
+    }
+
+    let result = remove(&target, existed).and_then(|()| {
+        if moving { fs::rename(source, &target) } else { copy(source, &target, recursive) }
+    });
+    if let Err(err) = result {
+        return error_response(&target, err);
+    }
+    open_files::forget(&target);
+    props::forget(&target);
+    props::transfer(source, &target, moving);
+    if moving {
+        open_files::forget(source);
+        locks::forget(source);
+    }
+    HttpResponse::new(if existed { HttpStatusCode::NoContent } else { HttpStatusCode::Created })
+}
+
+// The normalized path of the Destination header. It has to be on this server.
+fn destination(config: &ServerConfig, request: &HttpRequest) -> Result<String, HttpResponse> {
+    let uri = request.header_str("destination")
+        .and_then(|destination| Uri::parse(destination.trim()).ok())
+        .filter(|uri| matches!(uri.form(), TargetForm::Origin | TargetForm::Absolute))
+        .ok_or_else(|| HttpResponse::new(HttpStatusCode::BadRequest))?;
+    if uri.form() == TargetForm::Absolute {
+        let here = request.effective_uri();
+        let same_host = uri.host().zip(here.host()).is_some_and(|(there, here)| there.eq_ignore_ascii_case(here));
+        if uri.scheme() != here.scheme() || !same_host || uri.port() != here.port() {
+            return Err(HttpResponse::new(HttpStatusCode::BadGateway));
+        }
+    }
+    path::normalize(uri.path(), &config.path_normalization)
+        .map_err(|_| HttpResponse::new(HttpStatusCode::BadRequest))
+}
+
+// A symbolic link is removed itself, never what it points to.
+fn remove(path: &Path, exists: bool) -> io::Result<()> {
+    if !exists {
+        Ok(())
+    } else if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+// A collection without `recursive` is copied as an empty one. Symbolic links
+// are copied as links, so following one can't pull in (or, for a link to
+// an ancestor, endlessly repeat) files from elsewhere.
+fn copy(from: &Path, to: &Path, recursive: bool) -> io::Result<()> {
+    let file_type = fs::symlink_metadata(from)?.file_type();
+    if file_type.is_symlink() {
+        return symlink(fs::read_link(from)?, to);
+    }
+    if !file_type.is_dir() {
+        return fs::copy(from, to).map(|_| ());
+    }
+    fs::create_dir(to)?;
+    if recursive {
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy(&entry.path(), &to.join(entry.file_name()), true)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+    use crate::config::{Handler, Location, ServerConfig, WriteAccess};
+    use crate::handler::webdav::serve;
+    use crate::test_util::request_with_body;
+    use crate::util::HttpStatusCode;
+
+    fn config(root: &Path) -> ServerConfig {
+        let mut location = Location::new("/", Handler::Static { root: root.to_path_buf() });
+        location.webdav = true;
+        location.write_access = WriteAccess::Write;
+        ServerConfig { locations: vec![location], ..ServerConfig::default() }
+    }
+
+    // status, headers and body of the response
+    fn send(config: &ServerConfig, head: &str, body: &str) -> (HttpStatusCode, String, String) {
+        let request = request_with_body(head, body);
+        let location = &config.locations[0];
+        let Handler::Static { root } = &location.handler else { unreachable!() };
+        let response = serve(config, location, root, &request);
+        let status = *response.status();
+        let mut output = Vec::new();
+        response.send(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let (head, body) = output.split_once("\r\n\r\n").unwrap();
+        (status, head.to_string(), body.to_string())
+    }
+
+    #[test]
+    fn test_propfind() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("a b.txt"), "hello").unwrap();
+        fs::create_dir(root.path().join("sub")).unwrap();
+        fs::write(root.path().join(".upload-abc"), "partial").unwrap();
+        let config = config(root.path());
+
+        let (status, _, body) = send(&config, "PROPFIND / HTTP/1.1\r\nDepth: 1", "");
+        assert_eq!(status, HttpStatusCode::MultiStatus);
+        assert!(body.contains("<D:href>/</D:href>"));
+        assert!(body.contains("<D:href>/a%20b.txt</D:href>"));
+        assert!(body.contains("<D:getcontentlength>5</D:getcontentlength>"));
+        assert!(body.contains("<D:href>/sub/</D:href>"));
+        assert!(body.contains("<D:resourcetype><D:collection/></D:resourcetype>"));
+        assert!(!body.contains(".upload-abc"));
+
+        let (status, _, body) = send(&config, "PROPFIND / HTTP/1.1", "");
+        assert_eq!(status, HttpStatusCode::Forbidden);
+        assert!(body.contains("propfind-finite-depth"));
+
+        let patch = r#"<?xml version="1.0"?><D:propertyupdate xmlns:D="DAV:" xmlns:Z="urn:z">
+            <D:set><D:prop><Z:author>Jörg &amp; co</Z:author></D:prop></D:set></D:propertyupdate>"#;
+        let (status, _, body) = send(&config, "PROPPATCH /a%20b.txt HTTP/1.1", patch);
+        assert_eq!(status, HttpStatusCode::MultiStatus);
+        assert!(body.contains("<x:author xmlns:x=\"urn:z\"/>"));
+        assert!(body.contains("HTTP/1.1 200 OK"));
+
+        let protected = r#"<D:propertyupdate xmlns:D="DAV:"><D:set><D:prop><D:getetag>x</D:getetag></D:prop></D:set></D:propertyupdate>"#;
+        let (_, _, body) = send(&config, "PROPPATCH /a%20b.txt HTTP/1.1", protected);
+        assert!(body.contains("HTTP/1.1 403 Forbidden"));
+
+        let find = r#"<D:propfind xmlns:D="DAV:"><D:prop><D:getcontenttype/><author xmlns="urn:z"/><D:missing/></D:prop></D:propfind>"#;
+        let (_, _, body) = send(&config, "PROPFIND /a%20b.txt HTTP/1.1\r\nDepth: 0", find);
+        assert!(body.contains("<D:getcontenttype>text/plain; charset=utf-8</D:getcontenttype>"));
+        assert!(body.contains("<x:author xmlns:x=\"urn:z\">Jörg &amp; co</x:author>"));
+        assert!(body.contains("<D:prop><D:missing/></D:prop><D:status>HTTP/1.1 404 Not Found</D:status>"));
+
+        let (status, _, _) = send(&config, "PROPFIND / HTTP/1.1\r\nDepth: 0", "<D:propfind");
+        assert_eq!(status, HttpStatusCode::BadRequest);
+    }
+
+    #[test]
+    fn test_collections() {
+        let root = tempfile::tempdir().unwrap();
+        let config = config(root.path());
+
+        assert_eq!(send(&config, "MKCOL /d HTTP/1.1", "").0, HttpStatusCode::Created);
+        assert_eq!(send(&config, "MKCOL /d HTTP/1.1", "").0, HttpStatusCode::MethodNotAllowed);
+        assert_eq!(send(&config, "MKCOL /x/y HTTP/1.1", "").0, HttpStatusCode::Conflict);
+        assert_eq!(send(&config, "PUT /d/a.txt HTTP/1.1", "one").0, HttpStatusCode::Created);
+        // links are copied as they are, not followed
+        let outside = tempfile::tempdir().unwrap();
+        symlink(outside.path(), root.path().join("d/outside")).unwrap();
+        symlink("..", root.path().join("d/up")).unwrap();
+
+        let copy = "COPY /d HTTP/1.1\r\nDestination: http://x/e";
+        assert_eq!(send(&config, copy, "").0, HttpStatusCode::Created);
+        assert_eq!(fs::read_to_string(root.path().join("e/a.txt")).unwrap(), "one");
+        assert_eq!(fs::read_link(root.path().join("e/outside")).unwrap(), outside.path());
+        assert_eq!(fs::read_link(root.path().join("e/up")).unwrap(), Path::new(".."));
+        assert_eq!(send(&config, &format!("{}\r\nOverwrite: F", copy), "").0, HttpStatusCode::PreconditionFailed);
+        assert_eq!(send(&config, copy, "").0, HttpStatusCode::NoContent);
+        assert_eq!(send(&config, "COPY /d HTTP/1.1\r\nDestination: /d/inside", "").0, HttpStatusCode::Forbidden);
+        assert_eq!(send(&config, "COPY /d HTTP/1.1\r\nDestination: http://elsewhere/f", "").0, HttpStatusCode::BadGateway);
+
+        assert_eq!(send(&config, "MOVE /e/a.txt HTTP/1.1\r\nDestination: /b.txt", "").0, HttpStatusCode::Created);
+        assert!(!root.path().join("e/a.txt").exists());
+        assert_eq!(fs::read_to_string(root.path().join("b.txt")).unwrap(), "one");
+
+        assert_eq!(send(&config, "DELETE /d HTTP/1.1", "").0, HttpStatusCode::NoContent);
+        assert!(!root.path().join("d").exists());
+        assert!(outside.path().is_dir());
+
+        let (status, head, _) = send(&config, "OPTIONS / HTTP/1.1", "");
+        assert_eq!(status, HttpStatusCode::OK);
+        assert!(head.contains("DAV: 1, 2"));
+    }
+
+    #[test]
+    fn test_locks() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("d")).unwrap();
+        let config = config(root.path());
+
+        let lockinfo = r#"<?xml version="1.0"?><D:lockinfo xmlns:D="DAV:"><D:lockscope><D:exclusive/></D:lockscope>
+            <D:locktype><D:write/></D:locktype><D:owner><D:href>mailto:a@b</D:href></D:owner></D:lockinfo>"#;
+        let (status, head, body) = send(&config, "LOCK /d HTTP/1.1\r\nTimeout: Second-60", lockinfo);
+        assert_eq!(status, HttpStatusCode::OK);
+        assert!(body.contains("<D:owner><D:href>mailto:a@b</D:href></D:owner>"));
+        assert!(body.contains("<D:depth>infinity</D:depth>"));
+        let token = head.lines().find_map(|line| line.strip_prefix("Lock-Token: ")).unwrap().to_string();
+
+        assert_eq!(send(&config, "PUT /d/a.txt HTTP/1.1", "one").0, HttpStatusCode::Locked);
+        assert_eq!(send(&config, "DELETE /d HTTP/1.1", "").0, HttpStatusCode::Locked);
+        assert_eq!(send(&config, "LOCK /d/a.txt HTTP/1.1", lockinfo).0, HttpStatusCode::Locked);
+        let with_token = format!("PUT /d/a.txt HTTP/1.1\r\nIf: ({})", token);
+        assert_eq!(send(&config, &with_token, "one").0, HttpStatusCode::Created);
+
+        let refresh = format!("LOCK /d HTTP/1.1\r\nIf: ({})", token);
+        assert_eq!(send(&config, &refresh, "").0, HttpStatusCode::OK);
+        assert_eq!(send(&config, "UNLOCK /d HTTP/1.1\r\nLock-Token: <urn:uuid:x>", "").0, HttpStatusCode::Conflict);
+        let unlock = format!("UNLOCK /d HTTP/1.1\r\nLock-Token: {}", token);
+        assert_eq!(send(&config, &unlock, "").0, HttpStatusCode::NoContent);
+        assert_eq!(send(&config, "PUT /d/a.txt HTTP/1.1", "two").0, HttpStatusCode::NoContent);
+
+        // locking an unmapped URL creates an empty file
+        assert_eq!(send(&config, "LOCK /new.txt HTTP/1.1", lockinfo).0, HttpStatusCode::Created);
+        assert_eq!(fs::read_to_string(root.path().join("new.txt")).unwrap(), "");
+    }
+}
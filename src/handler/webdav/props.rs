@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use lazy_static::lazy_static;
+use roxmltree::Document;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use crate::handler::static_files::{content_type, error_response, etag, is_upload};
+use crate::handler::webdav::locks;
+use crate::handler::webdav::xml::{self, Multistatus, PropName, DAV};
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::util::{format_http_date, HttpStatusCode};
+
+// The properties the server computes itself. Clients can't change them.
+const LIVE: [&str; 9] = [
+    "creationdate",
+    "displayname",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "resourcetype",
+    "supportedlock",
+    "lockdiscovery",
+];
+
+const SUPPORTED_LOCK: &str = "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+    <D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>";
+
+lazy_static! {
+    // Dead properties set with PROPPATCH, by file. They are kept in memory
+    // only and gone after a restart.
+    static ref DEAD: Mutex<HashMap<PathBuf, Vec<(PropName, String)>>> = Mutex::new(HashMap::new());
+}
+
+fn dead() -> MutexGuard<'static, HashMap<PathBuf, Vec<(PropName, String)>>> {
+    DEAD.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+enum Wanted {
+    All,
+    Names,
+    Some(Vec<PropName>),
+}
+
+// https://www.rfc-editor.org/rfc/rfc4918#section-9.1
+pub(super) fn propfind(path: &Path, request: &HttpRequest) -> HttpResponse {
+    let depth_one = match request.header_str("depth").map(str::trim) {
+        Some("0") => false,
+        Some("1") => true,
+        None | Some("infinity") => return xml::error(HttpStatusCode::Forbidden, "propfind-finite-depth"),
+        Some(_) => return HttpResponse::new(HttpStatusCode::BadRequest),
+    };
+    let wanted = match xml::parse(request.body()) {
+        Ok(None) => Wanted::All,
+        Ok(Some(document)) => match parse_propfind(&document) {
+            Some(wanted) => wanted,
+            None => return HttpResponse::new(HttpStatusCode::BadRequest),
+        },
+        Err(()) => return HttpResponse::new(HttpStatusCode::BadRequest),
+    };
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) => return error_response(path, err),
+    };
+
+    let mut multistatus = Multistatus::new();
+    let href = xml::href(request.path(), metadata.is_dir());
+    describe(&mut multistatus, &href, path, &metadata, &wanted);
+    if depth_one && metadata.is_dir() {
+        let mut members: Vec<_> = match fs::read_dir(path) {
+            Ok(entries) => entries.filter_map(Result::ok)
+                .map(|entry| entry.file_name())
+                .filter(|name| !is_upload(name))
+                .collect(),
+            Err(err) => return error_response(path, err),
+        };
+        members.sort();
+        for name in members {
+            let member = path.join(&name);
+            // gone in the meantime
+            let Ok(metadata) = fs::metadata(&member) else { continue };
+            let href = format!("{}{}", href, xml::href(&name.to_string_lossy(), metadata.is_dir()));
+            describe(&mut multistatus, &href, &member, &metadata, &wanted);
+        }
+    }
+    multistatus.finish()
+}
+
+fn parse_propfind(document: &Document) -> Option<Wanted> {
+    let root = document.root_element();
+    if !xml::is_dav(root, "propfind") {
+        return None;
+    }
+    xml::child_elements(root).find_map(|child| {
+        if xml::is_dav(child, "allprop") {
+            Some(Wanted::All)
+        } else if xml::is_dav(child, "propname") {
+            Some(Wanted::Names)
+        } else if xml::is_dav(child, "prop") {
+            Some(Wanted::Some(xml::child_elements(child).map(PropName::of).collect()))
+        } else {
+            None
+        }
+    })
+}
+
+fn describe(multistatus: &mut Multistatus, href: &str, path: &Path, metadata: &Metadata, wanted: &Wanted) {
+    let dead: Vec<(PropName, String)> = dead().get(path).into_iter().flatten()
+        .map(|(name, value)| (name.clone(), xml::escape(value)))
+        .collect();
+    let live = LIVE.iter()
+        .map(|name| PropName::dav(name))
+        .filter_map(|name| live_value(&name, path, metadata).map(|value| (name, value)));
+    let mut found = String::new();
+    let mut missing = String::new();
+    match wanted {
+        Wanted::All => {
+            for (name, value) in live.chain(dead) {
+                found.push_str(&name.element(&value));
+            }
+        }
+        Wanted::Names => {
+            for (name, _) in live.chain(dead) {
+                found.push_str(&name.element(""));
+            }
+        }
+        Wanted::Some(names) => {
+            for name in names {
+                let value = live_value(name, path, metadata)
+                    .or_else(|| dead.iter().find(|(dead, _)| dead == name).map(|(_, value)| value.clone()));
+                match value {
+                    Some(value) => found.push_str(&name.element(&value)),
+                    None => missing.push_str(&name.element("")),
+                }
+            }
+        }
+    }
+
+    let mut propstats = Vec::new();
+    if !found.is_empty() {
+        propstats.push((HttpStatusCode::OK, found));
+    }
+    if !missing.is_empty() {
+        propstats.push((HttpStatusCode::NotFound, missing));
+    }
+    multistatus.response(href, &propstats);
+}
+
+// The value of a live property as markup, `None` if it doesn't apply to the
+// resource.
+fn live_value(name: &PropName, path: &Path, metadata: &Metadata) -> Option<String> {
+    if name.namespace != DAV {
+        return None;
+    }
+    let file = metadata.is_file();
+    match name.name.as_str() {
+        "creationdate" => {
+            let created = OffsetDateTime::from(metadata.created().ok()?);
+            created.format(&Rfc3339).ok()
+        }
+        "displayname" => path.file_name().map(|name| xml::escape(&name.to_string_lossy())),
+        "getcontentlength" if file => Some(metadata.len().to_string()),
+        "getcontenttype" if file => Some(xml::escape(content_type(path))),
+        "getetag" if file => Some(xml::escape(&etag(metadata))),
+        "getlastmodified" => metadata.modified().ok().map(format_http_date),
+        "resourcetype" if metadata.is_dir() => Some("<D:collection/>".to_string()),
+        "resourcetype" => Some(String::new()),
+        "supportedlock" => Some(SUPPORTED_LOCK.to_string()),
+        "lockdiscovery" => Some(locks::discovery(path)),
+        _ => None,
+    }
+}
+
+fn is_live(name: &PropName) -> bool {
+    name.namespace == DAV && LIVE.contains(&name.name.as_str())
+}
+
+// Either all updates are applied or none is. Live properties are protected,
+// trying to change one fails the whole request.
+// https://www.rfc-editor.org/rfc/rfc4918#section-9.2
+pub(super) fn proppatch(path: &Path, request: &HttpRequest) -> HttpResponse {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) => return error_response(path, err),
+    };
+    let updates = match xml::parse(request.body()) {
+        Ok(Some(document)) => parse_proppatch(&document),
+        _ => None,
+    };
+    let updates = match updates {
+        Some(updates) => updates,
+        None => return HttpResponse::new(HttpStatusCode::BadRequest),
+    };
+    if let Some(locked) = locks::check(path, false, request) {
+        return locked;
+    }
+
+    let names = |protected: bool| -> String {
+        updates.iter()
+            .filter(|(name, _)| is_live(name) == protected)
+            .map(|(name, _)| name.element(""))
+            .collect()
+    };
+    let mut propstats = Vec::new();
+    if updates.iter().any(|(name, _)| is_live(name)) {
+        propstats.push((HttpStatusCode::Forbidden, names(true)));
+        let others = names(false);
+        if !others.is_empty() {
+            propstats.push((HttpStatusCode::FailedDependency, others));
+        }
+    } else {
+        let mut dead = dead();
+        let properties = dead.entry(path.to_path_buf()).or_default();
+        for (name, value) in &updates {
+            properties.retain(|(dead, _)| dead != name);
+            if let Some(value) = value {
+                properties.push((name.clone(), value.clone()));
+            }
+        }
+        propstats.push((HttpStatusCode::OK, names(false)));
+    }
+
+    let mut multistatus = Multistatus::new();
+    multistatus.response(&xml::href(request.path(), metadata.is_dir()), &propstats);
+    multistatus.finish()
+}
+
+// The updates in document order, a value of `None` removes the property.
+fn parse_proppatch(document: &Document) -> Option<Vec<(PropName, Option<String>)>> {
+    let root = document.root_element();
+    if !xml::is_dav(root, "propertyupdate") {
+        return None;
+    }
+    let mut updates = Vec::new();
+    for update in xml::child_elements(root) {
+        let set = xml::is_dav(update, "set");
+        if !set && !xml::is_dav(update, "remove") {
+            continue;
+        }
+        for prop in xml::child_elements(update).filter(|node| xml::is_dav(*node, "prop")) {
+            for property in xml::child_elements(prop) {
+                updates.push((PropName::of(property), set.then(|| xml::text(property))));
+            }
+        }
+    }
+    Some(updates)
+}
+
+// Drops the dead properties of `path` and everything below it.
+pub(super) fn forget(path: &Path) {
+    dead().retain(|stored, _| !stored.starts_with(path));
+}
+
+// Gives `to` the dead properties `from` has, recursively, and removes them
+// from `from` if `moved`.
+pub(super) fn transfer(from: &Path, to: &Path, moved: bool) {
+    let mut dead = dead();
+    let below: Vec<PathBuf> = dead.keys().filter(|stored| stored.starts_with(from)).cloned().collect();
+    for stored in below {
+        let properties = if moved { dead.remove(&stored) } else { dead.get(&stored).cloned() };
+        if let (Some(properties), Ok(relative)) = (properties, stored.strip_prefix(from)) {
+            dead.insert(to.join(relative), properties);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use roxmltree::{Document, Node};
+use crate::response::HttpResponse;
+use crate::util::HttpStatusCode;
+
+pub(super) const DAV: &str = "DAV:";
+
+const HEADER: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n";
+const CONTENT_TYPE: &str = "application/xml; charset=utf-8";
+
+// A property is named by its namespace and local name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct PropName {
+    pub(super) namespace: String,
+    pub(super) name: String,
+}
+
+impl PropName {
+    pub(super) fn of(node: Node) -> PropName {
+        PropName {
+            namespace: node.tag_name().namespace().unwrap_or("").to_string(),
+            name: node.tag_name().name().to_string(),
+        }
+    }
+
+    pub(super) fn dav(name: &str) -> PropName {
+        PropName { namespace: DAV.to_string(), name: name.to_string() }
+    }
+
+    // The property as an element around `content`, which must already be
+    // markup.
+    pub(super) fn element(&self, content: &str) -> String {
+        let (tag, declaration) = match self.namespace.as_str() {
+            DAV => (format!("D:{}", self.name), String::new()),
+            "" => (self.name.clone(), " xmlns=\"\"".to_string()),
+            namespace => (format!("x:{}", self.name), format!(" xmlns:x=\"{}\"", escape(namespace))),
+        };
+        match content {
+            "" => format!("<{}{}/>", tag, declaration),
+            content => format!("<{0}{1}>{2}</{0}>", tag, declaration, content),
+        }
+    }
+}
+
+// Request bodies are small documents, DTDs are refused by roxmltree so
+// entities can't be used to blow them up. Whitespace only counts as no body.
+pub(super) fn parse(body: &[u8]) -> Result<Option<Document<'_>>, ()> {
+    let text = std::str::from_utf8(body).map_err(|_| ())?;
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+    Document::parse(text).map(Some).map_err(|_| ())
+}
+
+pub(super) fn is_dav(node: Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().namespace() == Some(DAV) && node.tag_name().name() == name
+}
+
+pub(super) fn child_elements<'a, 'input>(node: Node<'a, 'input>) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(Node::is_element)
+}
+
+// All the text below `node`. Values are kept as text, markup inside them is
+// dropped.
+pub(super) fn text(node: Node) -> String {
+    node.descendants().filter_map(|node| node.text().filter(|_| node.is_text())).collect()
+}
+
+pub(super) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+// A decoded request path as it goes into an href, with a trailing slash for
+// collections.
+pub(super) fn href(path: &str, collection: bool) -> String {
+    let mut href = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~!$&'()*+,;=:@".contains(&byte) {
+            href.push(byte as char);
+        } else {
+            href.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    if collection && !href.ends_with('/') {
+        href.push('/');
+    }
+    escape(&href)
+}
+
+// A complete document with `body` as its root element.
+pub(super) fn response(status: HttpStatusCode, body: &str) -> HttpResponse {
+    HttpResponse::new(status)
+        .header("Content-Type", CONTENT_TYPE)
+        .with_body(format!("{}{}\n", HEADER, body))
+}
+
+// The body of an error with a precondition or postcondition code.
+// https://www.rfc-editor.org/rfc/rfc4918#section-16
+pub(super) fn error(status: HttpStatusCode, condition: &str) -> HttpResponse {
+    response(status, &format!("<D:error xmlns:D=\"DAV:\"><D:{}/></D:error>", condition))
+}
+
+// A 207 response, one <D:response> per resource.
+// https://www.rfc-editor.org/rfc/rfc4918#section-13
+pub(super) struct Multistatus {
+    body: String,
+}
+
+impl Multistatus {
+    pub(super) fn new() -> Self {
+        Multistatus { body: String::from("<D:multistatus xmlns:D=\"DAV:\">\n") }
+    }
+
+    // `propstats` pairs a status with the properties, already rendered,
+    // that got it.
+    pub(super) fn response(&mut self, href: &str, propstats: &[(HttpStatusCode, String)]) {
+        self.body.push_str(&format!("<D:response><D:href>{}</D:href>", href));
+        for (status, props) in propstats {
+            self.body.push_str(&format!(
+                "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {} {}</D:status></D:propstat>",
+                props, status, status.reason()
+            ));
+        }
+        self.body.push_str("</D:response>\n");
+    }
+
+    pub(super) fn finish(mut self) -> HttpResponse {
+        self.body.push_str("</D:multistatus>");
+        response(HttpStatusCode::MultiStatus, &self.body)
+    }
+}
@@ -0,0 +1,112 @@
+use std::borrow::Cow;
+
+// Header fields in the order they were added. Names keep the casing they
+// arrived with but are matched case-insensitively, values are kept as the
+// raw bytes. A handful of fields per message makes a linear scan cheaper
+// than hashing.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct HeaderMap {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl HeaderMap {
+    pub(crate) fn new() -> Self {
+        HeaderMap::default()
+    }
+
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        HeaderMap { entries: Vec::with_capacity(capacity) }
+    }
+
+    // Adds another value for `name`, keeping the ones already there.
+    pub(crate) fn append(&mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    // Replaces every value of `name` with `value`.
+    pub(crate) fn insert(&mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    pub(crate) fn remove(&mut self, name: &str) {
+        self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    // the first value of `name`
+    pub(crate) fn get(&self, name: &str) -> Option<&[u8]> {
+        self.get_all(name).next()
+    }
+
+    pub(crate) fn get_all<'a, 'n>(&'a self, name: &'n str) -> impl Iterator<Item = &'a [u8]> + use<'a, 'n> {
+        self.entries.iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    // All values of a list-based field joined into one, as if they had been
+    // sent on a single line. Cookie pairs are joined with "; " instead of ",
+    // ". Set-Cookie can't be combined at all and is only reachable through
+    // `get_all`.
+    pub(crate) fn get_combined(&self, name: &str) -> Option<Cow<'_, [u8]>> {
+        if name.eq_ignore_ascii_case("set-cookie") {
+            return None;
+        }
+        let separator: &[u8] = if name.eq_ignore_ascii_case("cookie") { b"; " } else { b", " };
+
+        let mut values = self.get_all(name);
+        let first = values.next()?;
+        let rest = match values.next() {
+            Some(second) => second,
+            None => return Some(Cow::Borrowed(first)),
+        };
+        let mut combined = first.to_vec();
+        for value in std::iter::once(rest).chain(values) {
+            combined.extend_from_slice(separator);
+            combined.extend_from_slice(value);
+        }
+        Some(Cow::Owned(combined))
+    }
+
+    // whether any value of the list-based field `name` has the token
+    // `token`, e.g. "close" in Connection
+    pub(crate) fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(|&b| b == b','))
+            .any(|item| item.trim_ascii().eq_ignore_ascii_case(token.as_bytes()))
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.entries.iter().map(|(name, value)| (name.as_str(), value.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::headers::HeaderMap;
+
+    #[test]
+    fn test_header_map() {
+        let mut headers = HeaderMap::new();
+        headers.append("Accept", "text/html");
+        headers.append("Set-Cookie", "a=1");
+        headers.append("accept", "text/plain");
+        headers.append("Set-Cookie", "b=2");
+        headers.append("Cookie", "x=1");
+        headers.append("COOKIE", "y=2");
+
+        assert_eq!(headers.get("ACCEPT"), Some(&b"text/html"[..]));
+        assert_eq!(headers.get_combined("accept").unwrap().as_ref(), b"text/html, text/plain");
+        assert_eq!(headers.get_combined("cookie").unwrap().as_ref(), b"x=1; y=2");
+        assert_eq!(headers.get_combined("set-cookie"), None);
+        assert_eq!(headers.get_all("set-cookie").collect::<Vec<_>>(), vec![&b"a=1"[..], &b"b=2"[..]]);
+
+        let names: Vec<&str> = headers.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["Accept", "Set-Cookie", "accept", "Set-Cookie", "Cookie", "COOKIE"]);
+
+        headers.insert("ACCEPT", "*/*");
+        assert_eq!(headers.get_all("accept").count(), 1);
+        assert_eq!(headers.iter().count(), 5);
+    }
+}
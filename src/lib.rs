@@ -6,6 +6,21 @@ mod parser;
 mod util;
 mod response;
 mod request;
+mod headers;
+mod uri;
+mod path;
+mod form;
+mod multipart;
 mod threadpool;
 pub mod server;
-mod middleware;
\ No newline at end of file
+mod middleware;
+mod handler;
+mod event_loop;
+mod tunnel;
+mod tls;
+mod cache;
+#[cfg(feature = "io-uring")]
+mod uring;
+#[cfg(test)]
+mod test_util;
+pub mod config;
\ No newline at end of file
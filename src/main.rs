@@ -1,9 +1,13 @@
 use std::error::Error;
+use std::path::PathBuf;
+use http_server::config::{Handler, Location, ServerConfig};
 use http_server::server::Server;
 
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
-    let mut server = Server::new("127.0.0.1:8080")?;
+    let mut config = ServerConfig::default();
+    config.locations.push(Location::new("/", Handler::Static { root: PathBuf::from(".") }));
+    let mut server = Server::with_config("127.0.0.1:8080", config)?;
     server.run()?;
     Ok(())
 }
@@ -1,13 +1,16 @@
 use crate::request::HttpRequest;
 
+#[allow(dead_code)]
 pub(crate) trait Middleware {
-    fn run(self: &Self, request: &mut HttpRequest) -> bool;
+    fn run(&self, request: &mut HttpRequest) -> bool;
 }
 
+#[allow(dead_code)]
 pub(crate) struct MiddlewareManager {
     middlewares: Vec<Box<dyn Middleware>>
 }
 
+#[allow(dead_code)]
 impl MiddlewareManager {
     pub(crate) fn new() -> Self {
         MiddlewareManager { middlewares: Vec::new() }
@@ -0,0 +1,487 @@
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+use tempfile::NamedTempFile;
+use crate::config::Limits;
+use crate::headers::HeaderMap;
+use crate::util::HttpStatusCode;
+
+#[derive(Debug)]
+pub(crate) enum MultipartError {
+    UnsupportedMediaType,
+    MissingBoundary,
+    Malformed(&'static str),
+    PartTooLarge,
+    TooLarge,
+    TooManyParts,
+    Io(io::Error),
+}
+
+impl MultipartError {
+    pub(crate) fn status(&self) -> HttpStatusCode {
+        match self {
+            MultipartError::UnsupportedMediaType => HttpStatusCode::UnsupportedMediaType,
+            MultipartError::MissingBoundary | MultipartError::Malformed(_) => HttpStatusCode::BadRequest,
+            MultipartError::PartTooLarge | MultipartError::TooLarge | MultipartError::TooManyParts => {
+                HttpStatusCode::ContentTooLarge
+            }
+            MultipartError::Io(_) => HttpStatusCode::InternalServerError,
+        }
+    }
+}
+
+impl Display for MultipartError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MultipartError::UnsupportedMediaType => write!(f, "not multipart/form-data"),
+            MultipartError::MissingBoundary => write!(f, "missing or invalid boundary"),
+            MultipartError::Malformed(ctx) => write!(f, "malformed multipart body: {}", ctx),
+            MultipartError::PartTooLarge => write!(f, "part too large"),
+            MultipartError::TooLarge => write!(f, "multipart body too large"),
+            MultipartError::TooManyParts => write!(f, "too many parts"),
+            MultipartError::Io(err) => write!(f, "spooling part: {}", err),
+        }
+    }
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(err: io::Error) -> Self {
+        MultipartError::Io(err)
+    }
+}
+
+// Where the content of a part ended up.
+#[derive(Debug)]
+enum PartData {
+    Memory(Vec<u8>),
+    // removed again when dropped, unless saved
+    File(NamedTempFile),
+}
+
+#[derive(Debug)]
+pub(crate) struct Part {
+    headers: HeaderMap,
+    name: Option<String>,
+    filename: Option<String>,
+    data: PartData,
+    len: u64,
+}
+
+impl Part {
+    pub(crate) fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    // the field name from Content-Disposition
+    pub(crate) fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    // the client side file name, only set for file inputs
+    pub(crate) fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    // Moves the content to `path`, renaming the spool file where possible.
+    pub(crate) fn save(self, path: &Path) -> io::Result<()> {
+        match self.data {
+            PartData::Memory(bytes) => std::fs::write(path, bytes),
+            PartData::File(file) => match file.persist(path) {
+                Ok(_) => Ok(()),
+                // most likely on another filesystem
+                Err(err) => {
+                    let mut file = err.file;
+                    file.seek(SeekFrom::Start(0))?;
+                    io::copy(&mut file, &mut File::create(path)?).map(|_| ())
+                }
+            },
+        }
+    }
+}
+
+// The outcome of splitting a request body into parts, taken by the handler
+// that wants them. Spooled files can't be shared, so a copy of the request
+// has none.
+#[derive(Debug, Default)]
+pub(crate) struct ReceivedParts(Option<Result<Vec<Part>, MultipartError>>);
+
+impl ReceivedParts {
+    pub(crate) fn new(parts: Result<Vec<Part>, MultipartError>) -> Self {
+        ReceivedParts(Some(parts))
+    }
+
+    pub(crate) fn take(&mut self) -> Result<Vec<Part>, MultipartError> {
+        self.0.take().unwrap_or(Err(MultipartError::UnsupportedMediaType))
+    }
+}
+
+impl Clone for ReceivedParts {
+    fn clone(&self) -> Self {
+        ReceivedParts(None)
+    }
+}
+
+enum State {
+    // before the first boundary
+    Preamble,
+    // the rest of a boundary line, "--" after the last one
+    AfterBoundary,
+    Headers,
+    Body,
+    // after the closing boundary, ignored
+    Epilogue,
+}
+
+// A push parser for multipart/form-data. Input may be split anywhere,
+// including inside a boundary. Parts are kept in memory until they grow
+// past `part_memory`, then moved to a temporary file.
+// https://www.rfc-editor.org/rfc/rfc7578
+pub(crate) struct MultipartParser {
+    // "\r\n--" + boundary
+    delimiter: Vec<u8>,
+    limits: Limits,
+    state: State,
+    buffer: Vec<u8>,
+    total: usize,
+    parts: Vec<Part>,
+    current: Option<Part>,
+}
+
+impl MultipartParser {
+    pub(crate) fn new(boundary: &str, limits: Limits) -> Self {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        MultipartParser {
+            delimiter,
+            limits,
+            state: State::Preamble,
+            // the first boundary doesn't follow a line break
+            buffer: b"\r\n".to_vec(),
+            total: 0,
+            parts: Vec::new(),
+            current: None,
+        }
+    }
+
+    pub(crate) fn feed(&mut self, input: &[u8]) -> Result<(), MultipartError> {
+        self.total += input.len();
+        if self.total > self.limits.multipart_size {
+            return Err(MultipartError::TooLarge);
+        }
+        self.buffer.extend_from_slice(input);
+
+        loop {
+            let progressed = match self.state {
+                State::Preamble => self.skip_preamble(),
+                State::AfterBoundary => self.after_boundary()?,
+                State::Headers => self.headers()?,
+                State::Body => self.body()?,
+                State::Epilogue => {
+                    self.buffer.clear();
+                    false
+                }
+            };
+            if !progressed {
+                return Ok(());
+            }
+        }
+    }
+
+    // All parts, once the closing boundary has been seen.
+    pub(crate) fn finish(self) -> Result<Vec<Part>, MultipartError> {
+        match self.state {
+            State::Epilogue => Ok(self.parts),
+            _ => Err(MultipartError::Malformed("missing closing boundary")),
+        }
+    }
+
+    fn skip_preamble(&mut self) -> bool {
+        match find(&self.buffer, &self.delimiter) {
+            Some(pos) => {
+                self.buffer.drain(..pos + self.delimiter.len());
+                self.state = State::AfterBoundary;
+                true
+            }
+            None => {
+                // keep what could be the start of a delimiter
+                let keep = self.delimiter.len() - 1;
+                if self.buffer.len() > keep {
+                    self.buffer.drain(..self.buffer.len() - keep);
+                }
+                false
+            }
+        }
+    }
+
+    fn after_boundary(&mut self) -> Result<bool, MultipartError> {
+        let line_end = match find(&self.buffer, b"\r\n") {
+            Some(pos) => pos,
+            None if self.buffer.starts_with(b"--") => {
+                self.state = State::Epilogue;
+                return Ok(true);
+            }
+            None if self.buffer.len() > self.limits.header_size => {
+                return Err(MultipartError::Malformed("boundary line too long"));
+            }
+            None => return Ok(false),
+        };
+        let line = &self.buffer[..line_end];
+        if line.starts_with(b"--") {
+            self.state = State::Epilogue;
+            return Ok(true);
+        }
+        // only transport padding may follow the boundary
+        if !line.iter().all(|&b| b == b' ' || b == b'\t') {
+            return Err(MultipartError::Malformed("garbage after boundary"));
+        }
+        if self.parts.len() >= self.limits.multipart_parts {
+            return Err(MultipartError::TooManyParts);
+        }
+        self.buffer.drain(..line_end + 2);
+        self.state = State::Headers;
+        Ok(true)
+    }
+
+    fn headers(&mut self) -> Result<bool, MultipartError> {
+        // a part without any header
+        if self.buffer.starts_with(b"\r\n") {
+            self.buffer.drain(..2);
+            self.start_part(HeaderMap::new());
+            return Ok(true);
+        }
+        let end = match find(&self.buffer, b"\r\n\r\n") {
+            Some(pos) => pos,
+            None if self.buffer.len() > self.limits.header_size => {
+                return Err(MultipartError::Malformed("part headers too large"));
+            }
+            None => return Ok(false),
+        };
+
+        let mut headers = HeaderMap::new();
+        for line in self.buffer[..end].split(|&b| b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let colon = line.iter().position(|&b| b == b':').ok_or(MultipartError::Malformed("part header without colon"))?;
+            let name = std::str::from_utf8(&line[..colon])
+                .map_err(|_| MultipartError::Malformed("part header name"))?;
+            headers.append(name.trim(), line[colon + 1..].trim_ascii());
+        }
+        self.buffer.drain(..end + 4);
+        self.start_part(headers);
+        Ok(true)
+    }
+
+    fn start_part(&mut self, headers: HeaderMap) {
+        let disposition = headers.get("content-disposition")
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+            .unwrap_or_default();
+        self.current = Some(Part {
+            name: disposition_param(&disposition, "name"),
+            filename: disposition_param(&disposition, "filename"),
+            headers,
+            data: PartData::Memory(Vec::new()),
+            len: 0,
+        });
+        self.state = State::Body;
+    }
+
+    fn body(&mut self) -> Result<bool, MultipartError> {
+        match find(&self.buffer, &self.delimiter) {
+            Some(pos) => {
+                let data = self.buffer[..pos].to_vec();
+                self.write(&data)?;
+                self.buffer.drain(..pos + self.delimiter.len());
+                if let Some(part) = self.current.take() {
+                    self.parts.push(part);
+                }
+                self.state = State::AfterBoundary;
+                Ok(true)
+            }
+            None => {
+                // everything but a possible partial delimiter at the end
+                let safe = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+                let data: Vec<u8> = self.buffer.drain(..safe).collect();
+                self.write(&data)?;
+                Ok(false)
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), MultipartError> {
+        let part = match &mut self.current {
+            Some(part) => part,
+            None => return Ok(()),
+        };
+        part.len += data.len() as u64;
+        if part.len > self.limits.part_size as u64 {
+            return Err(MultipartError::PartTooLarge);
+        }
+        match &mut part.data {
+            PartData::Memory(bytes) if part.len > self.limits.part_memory as u64 => {
+                let mut file = NamedTempFile::new()?;
+                file.write_all(&mem::take(bytes))?;
+                file.write_all(data)?;
+                part.data = PartData::File(file);
+            }
+            PartData::Memory(bytes) => bytes.extend_from_slice(data),
+            PartData::File(file) => file.write_all(data)?,
+        }
+        Ok(())
+    }
+}
+
+// The parser for the body of a request, `None` unless it is
+// multipart/form-data.
+pub(crate) fn for_request(headers: &HeaderMap, limits: Limits) -> Option<Result<MultipartParser, MultipartError>> {
+    let content_type = std::str::from_utf8(headers.get("content-type")?).ok()?;
+    match boundary(content_type) {
+        Err(MultipartError::UnsupportedMediaType) => None,
+        boundary => Some(boundary.map(|boundary| MultipartParser::new(&boundary, limits))),
+    }
+}
+
+// The boundary parameter of a multipart/form-data Content-Type.
+pub(crate) fn boundary(content_type: &str) -> Result<String, MultipartError> {
+    let mut params = split_params(content_type).into_iter();
+    let media_type = params.next().unwrap_or_default();
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return Err(MultipartError::UnsupportedMediaType);
+    }
+    let boundary = params
+        .find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            name.trim().eq_ignore_ascii_case("boundary").then(|| unquote(value.trim()))
+        })
+        .ok_or(MultipartError::MissingBoundary)?;
+    // https://www.rfc-editor.org/rfc/rfc2046#section-5.1.1
+    if boundary.is_empty() || boundary.len() > 70 || boundary.ends_with(' ') {
+        return Err(MultipartError::MissingBoundary);
+    }
+    Ok(boundary)
+}
+
+fn disposition_param(disposition: &str, name: &str) -> Option<String> {
+    split_params(disposition).into_iter().skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| unquote(value.trim()))
+    })
+}
+
+// Splits `type; a=b; c="d;e"` on the semicolons outside quoted strings.
+fn split_params(value: &str) -> Vec<String> {
+    let mut params = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for ch in value.chars() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(mem::take(&mut current).trim().to_string());
+                continue;
+            }
+            _ => {}
+        }
+        current.push(ch);
+    }
+    params.push(current.trim().to_string());
+    params
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        Some(inner) => {
+            let mut unquoted = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(ch) = chars.next() {
+                match ch {
+                    '\\' => unquoted.extend(chars.next()),
+                    ch => unquoted.push(ch),
+                }
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use crate::config::Limits;
+    use crate::multipart::{boundary, MultipartError, MultipartParser, PartData};
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\n\
+Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+hello\r\n--XyZ  \r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"a \\\"b\\\";.bin\"\r\n\
+Content-Type: application/octet-stream\r\n\r\n\
+0123456789\r\n--X\r\nabcdef\r\n--XyZ--\r\nepilogue";
+
+    #[test]
+    fn test_multipart_split_anywhere() {
+        let limits = Limits { part_memory: 8, ..Limits::default() };
+        for chunk_size in [1, 2, 3, 7, BODY.len()] {
+            let mut parser = MultipartParser::new("XyZ", limits);
+            for chunk in BODY.chunks(chunk_size) {
+                parser.feed(chunk).unwrap();
+            }
+            let mut parts = parser.finish().unwrap();
+            assert_eq!(parts.len(), 2);
+
+            assert_eq!(parts[0].name(), Some("title"));
+            assert_eq!(parts[0].filename(), None);
+            assert!(matches!(&parts[0].data, PartData::Memory(bytes) if bytes == b"hello"));
+
+            let file = parts.pop().unwrap();
+            assert_eq!(file.name(), Some("file"));
+            assert_eq!(file.filename(), Some("a \"b\";.bin"));
+            assert_eq!(file.headers().get("content-type"), Some(&b"application/octet-stream"[..]));
+            assert_eq!(file.len(), 23);
+            let mut content = Vec::new();
+            match file.data {
+                PartData::File(spooled) => spooled.reopen().unwrap().read_to_end(&mut content).unwrap(),
+                PartData::Memory(_) => panic!("large part should be spooled"),
+            };
+            assert_eq!(content, b"0123456789\r\n--X\r\nabcdef");
+        }
+    }
+
+    #[test]
+    fn test_multipart_limits() {
+        let limits = Limits { part_size: 10, ..Limits::default() };
+        let mut parser = MultipartParser::new("XyZ", limits);
+        assert!(matches!(parser.feed(BODY), Err(MultipartError::PartTooLarge)));
+
+        let limits = Limits { multipart_parts: 1, ..Limits::default() };
+        let mut parser = MultipartParser::new("XyZ", limits);
+        assert!(matches!(parser.feed(BODY), Err(MultipartError::TooManyParts)));
+
+        let limits = Limits { multipart_size: 20, ..Limits::default() };
+        let mut parser = MultipartParser::new("XyZ", limits);
+        assert!(matches!(parser.feed(BODY), Err(MultipartError::TooLarge)));
+
+        let mut parser = MultipartParser::new("XyZ", Limits::default());
+        parser.feed(&BODY[..40]).unwrap();
+        assert!(matches!(parser.finish(), Err(MultipartError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_boundary() {
+        assert_eq!(boundary("multipart/form-data; boundary=\"a;b\"").unwrap(), "a;b");
+        assert_eq!(boundary("Multipart/Form-Data;charset=utf-8; BOUNDARY=xyz").unwrap(), "xyz");
+        assert!(matches!(boundary("multipart/form-data"), Err(MultipartError::MissingBoundary)));
+        assert!(matches!(boundary("text/plain; boundary=x"), Err(MultipartError::UnsupportedMediaType)));
+    }
+}
@@ -1,19 +1,90 @@
-use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use crate::config::{Limits, ObsFold, PathNormalization};
+use crate::headers::HeaderMap;
+use crate::multipart::{self, MultipartError, MultipartParser, Part};
 use crate::util::*;
 use crate::request::HttpRequest;
+use crate::path::{self, InvalidPath};
+use crate::uri::{self, TargetForm, Uri};
 
 const INITIAL_TARGET_CAP: usize = 50;
 
 #[derive(Debug)]
 pub(crate) enum ParserError {
-    InvalidMethod,
     InvalidVersion,
     ExpectedSpace(&'static str),
     UnexpectedChar(&'static str),
+    InvalidContentLength,
+    InvalidTransferEncoding,
+    UnsupportedTransferCoding,
+    InvalidChunk,
+    InvalidRequestLineByte(u8),
+    InvalidTarget,
+    InvalidPath(InvalidPath),
+    InvalidHost,
+    ObsFold,
+    RequestLineTooLong,
+    HeaderTooLarge,
+    TooManyHeaders,
+    BodyTooLarge,
+    Multipart(MultipartError),
     NotReady,
 }
 
+impl ParserError {
+    // The status a client gets for this error.
+    pub(crate) fn status(&self) -> HttpStatusCode {
+        match self {
+            ParserError::InvalidVersion => HttpStatusCode::HTTPVersionNotSupported,
+            ParserError::RequestLineTooLong => HttpStatusCode::URITooLong,
+            ParserError::HeaderTooLarge | ParserError::TooManyHeaders => HttpStatusCode::RequestHeaderFieldsTooLarge,
+            ParserError::BodyTooLarge => HttpStatusCode::ContentTooLarge,
+            ParserError::Multipart(err) => err.status(),
+            ParserError::UnsupportedTransferCoding => HttpStatusCode::NotImplemented,
+            ParserError::NotReady => HttpStatusCode::InternalServerError,
+            ParserError::ExpectedSpace(_)
+            | ParserError::UnexpectedChar(_)
+            | ParserError::InvalidContentLength
+            | ParserError::InvalidTransferEncoding
+            | ParserError::InvalidChunk
+            | ParserError::InvalidRequestLineByte(_)
+            | ParserError::InvalidTarget
+            | ParserError::InvalidPath(_)
+            | ParserError::InvalidHost
+            | ParserError::ObsFold => {
+                HttpStatusCode::BadRequest
+            }
+        }
+    }
+}
+
+impl Display for ParserError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParserError::InvalidVersion => write!(f, "invalid version"),
+            ParserError::ExpectedSpace(ctx) => write!(f, "expected space: {}", ctx),
+            ParserError::UnexpectedChar(ctx) => write!(f, "unexpected char: {}", ctx),
+            ParserError::InvalidContentLength => write!(f, "invalid content-length"),
+            ParserError::InvalidTransferEncoding => write!(f, "invalid transfer-encoding"),
+            ParserError::UnsupportedTransferCoding => write!(f, "unsupported transfer coding"),
+            ParserError::InvalidChunk => write!(f, "invalid chunk"),
+            ParserError::InvalidRequestLineByte(byte) => write!(f, "invalid byte 0x{:02x} in request line", byte),
+            ParserError::InvalidTarget => write!(f, "invalid request target"),
+            ParserError::InvalidPath(err) => write!(f, "{}", err),
+            ParserError::InvalidHost => write!(f, "missing, repeated or invalid host"),
+            ParserError::ObsFold => write!(f, "folded header field value"),
+            ParserError::RequestLineTooLong => write!(f, "request line too long"),
+            ParserError::HeaderTooLarge => write!(f, "header too large"),
+            ParserError::TooManyHeaders => write!(f, "too many headers"),
+            ParserError::BodyTooLarge => write!(f, "body too large"),
+            ParserError::Multipart(err) => write!(f, "{}", err),
+            ParserError::NotReady => write!(f, "parser not ready"),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 enum ReqLineState {
     Method,
     FirstSpaceBeforeUrl,
@@ -36,53 +107,134 @@ enum ReqLineState {
 enum HeaderState {
     Name,
     OWSBeforeValue,
-    FirstValue,
     Value,
-    OWSAfterValue,
+    AlmostDone,
+    LineStart,
     HeadersAlmostDone,
-    AlmostDone
+}
+
+// chunked-body = *chunk last-chunk trailer-section CRLF
+// https://www.rfc-editor.org/rfc/rfc9112#section-7.1
+#[derive(Debug, PartialEq)]
+enum ChunkState {
+    SizeStart,
+    Size,
+    Extension,
+    SizeAlmostDone,
+    Data,
+    DataCR,
+    DataLF,
+    TrailerLineStart,
+    Trailer,
+    TrailerAlmostDone,
+    TrailersAlmostDone,
 }
 
 #[derive(Debug, PartialEq)]
 enum State {
     RequestLine,
     Header,
+    Body,
+    Chunked,
     Done,
 }
 
 pub(crate) struct Parser {
     method: String,
-    header_map: HashMap<String, String>,
+    header_map: HeaderMap,
     current_header_name: String,
-    current_header_value: String,
+    current_header_value: Vec<u8>,
     state: State,
     req_line_state: ReqLineState,
     header_state: HeaderState,
-    method_parsed: HttpMethod,
+    chunk_state: ChunkState,
+    // set once the request line has its method
+    method_parsed: Option<HttpMethod>,
     request_target: String,
+    uri: Option<Uri>,
+    path: String,
     http_version_major: u8,
     http_version_minor: u8,
+    body: Vec<u8>,
+    body_remaining: usize,
+    body_received: usize,
+    // `body_size`, or `multipart_size` if larger for bodies split into parts
+    body_limit: usize,
+    // splits multipart/form-data bodies into parts while they arrive
+    multipart: Option<Result<MultipartParser, MultipartError>>,
+    parts: Option<Result<Vec<Part>, MultipartError>>,
+    consumed: usize,
+    error_offset: usize,
+    limits: Limits,
+    obs_fold: ObsFold,
+    path_normalization: PathNormalization,
+    request_line_len: usize,
+    header_line_len: usize,
+    headers_len: usize,
+    header_count: usize,
 }
 
 impl Parser {
+    #[cfg(test)]
     pub(crate) fn new() -> Self {
+        Parser::with_limits(Limits::default())
+    }
+
+    pub(crate) fn with_limits(limits: Limits) -> Self {
         Parser {
             method: String::with_capacity(7),
-            method_parsed: HttpMethod::Invalid,
+            method_parsed: None,
             request_target: String::with_capacity(INITIAL_TARGET_CAP),
+            uri: None,
+            path: String::new(),
             http_version_major: 0,
             http_version_minor: 0,
-            header_map: HashMap::new(),
+            header_map: HeaderMap::new(),
             req_line_state: ReqLineState::Method,
             state: State::RequestLine,
             header_state: HeaderState::Name,
+            chunk_state: ChunkState::SizeStart,
             current_header_name: String::with_capacity(INITIAL_TARGET_CAP),
-            current_header_value: String::with_capacity(INITIAL_TARGET_CAP)
+            current_header_value: Vec::with_capacity(INITIAL_TARGET_CAP),
+            body: Vec::new(),
+            body_remaining: 0,
+            body_received: 0,
+            body_limit: limits.body_size,
+            multipart: None,
+            parts: None,
+            consumed: 0,
+            error_offset: 0,
+            limits,
+            obs_fold: ObsFold::default(),
+            path_normalization: PathNormalization::default(),
+            request_line_len: 0,
+            header_line_len: 0,
+            headers_len: 0,
+            header_count: 0,
         }
     }
 
-    fn is_token(ch: &char) -> bool {
-        ch.is_ascii() && !INVALID_TOKEN_CHARACTERS.contains(ch)
+    // Hands out this parser and leaves a fresh one with the same limits in
+    // its place, ready for the next request on the connection.
+    pub(crate) fn take(&mut self) -> Parser {
+        let next = Parser::with_limits(self.limits)
+            .with_obs_fold(self.obs_fold)
+            .with_path_normalization(self.path_normalization);
+        std::mem::replace(self, next)
+    }
+
+    pub(crate) fn with_obs_fold(mut self, obs_fold: ObsFold) -> Self {
+        self.obs_fold = obs_fold;
+        self
+    }
+
+    pub(crate) fn with_path_normalization(mut self, path_normalization: PathNormalization) -> Self {
+        self.path_normalization = path_normalization;
+        self
+    }
+
+    fn is_token(ch: u8) -> bool {
+        ch.is_ascii_graphic() && !INVALID_TOKEN_CHARACTERS.contains(&(ch as char))
     }
 
     // VCHAR (0x21-7E) or obs-text (0x80-FF)
@@ -90,34 +242,16 @@ impl Parser {
         (0x21..=0x7E).contains(&ch) || ch >= 0x80
     }
 
-    fn parse_request_line(&mut self, ch: char) -> Result<bool, ParserError> {
+    fn parse_request_line(&mut self, ch: u8) -> Result<bool, ParserError> {
         match self.req_line_state {
             ReqLineState::Method => {
-                self.method.push(ch);
-                if self.method.len() == 3 {
-                    if self.method == "GET" {
-                        self.method_parsed = HttpMethod::Get;
-                    } else if self.method == "PUT" {
-                        self.method_parsed = HttpMethod::Put;
-                    }
-                } else if self.method.len() == 4 {
-                    if self.method == "HEAD" {
-                        self.method_parsed = HttpMethod::Head
-                    } else if self.method == "POST" {
-                        self.method_parsed = HttpMethod::Post
-                    }
-                } else if self.method.len() == 5 && self.method == "TRACE" {
-                    self.method_parsed = HttpMethod::Trace
-                } else if self.method.len() == 6 && self.method == "DELETE" {
-                    self.method_parsed = HttpMethod::Delete
-                } else if self.method.len() == 7 && self.method == "OPTIONS" {
-                    self.method_parsed = HttpMethod::Options
-                }
-
-                if self.method_parsed != HttpMethod::Invalid {
-                    self.req_line_state = ReqLineState::FirstSpaceBeforeUrl;
-                } else if self.method.len() == 7 {
-                    return Err(ParserError::InvalidMethod);
+                if ch == SP && !self.method.is_empty() {
+                    self.method_parsed = Some(HttpMethod::from_name(&self.method));
+                    self.req_line_state = ReqLineState::InUrl;
+                } else if Parser::is_token(ch) {
+                    self.method.push(ch as char);
+                } else {
+                    return Err(ParserError::InvalidRequestLineByte(ch));
                 }
             }
             ReqLineState::FirstSpaceBeforeUrl => {
@@ -128,39 +262,42 @@ impl Parser {
             }
             ReqLineState::SpacesBeforeUrl => {}
             ReqLineState::InUrl => {
-                if !ch.is_ascii_control() && ch != SP {
-                    self.request_target.push(ch)
-                } else if ch == SP {
+                if ch == SP {
                     self.req_line_state = ReqLineState::H;
+                } else if ch.is_ascii_graphic() {
+                    self.request_target.push(ch as char)
+                } else {
+                    // anything else has to be percent-encoded
+                    return Err(ParserError::InvalidRequestLineByte(ch));
                 }
             }
             ReqLineState::SpaceAfterUrl => {}
             ReqLineState::H => {
-                if ch != 'H' {
+                if ch != b'H' {
                     return Err(ParserError::UnexpectedChar("H after TARGET"));
                 }
                 self.req_line_state = ReqLineState::HT;
             }
             ReqLineState::HT => {
-                if ch != 'T' {
+                if ch != b'T' {
                     return Err(ParserError::UnexpectedChar("T after H"));
                 }
                 self.req_line_state = ReqLineState::HTT;
             }
             ReqLineState::HTT => {
-                if ch != 'T' {
+                if ch != b'T' {
                     return Err(ParserError::UnexpectedChar("T after T"));
                 }
                 self.req_line_state = ReqLineState::HTTP;
             }
             ReqLineState::HTTP => {
-                if ch != 'P' {
+                if ch != b'P' {
                     return Err(ParserError::UnexpectedChar("P after T"));
                 }
                 self.req_line_state = ReqLineState::Slash;
             }
             ReqLineState::Slash => {
-                if ch != '/' {
+                if ch != b'/' {
                     return Err(ParserError::UnexpectedChar("/ after P"));
                 }
                 self.req_line_state = ReqLineState::VersionFirstMajor;
@@ -169,42 +306,51 @@ impl Parser {
                 if !ch.is_ascii_digit() {
                     return Err(ParserError::UnexpectedChar("DIGIT after P"));
                 }
-                self.http_version_major = ch as u8 - b'0';
+                self.http_version_major = ch - b'0';
                 if self.http_version_major != 1 {
                     return Err(ParserError::InvalidVersion);
                 }
 
                 self.req_line_state = ReqLineState::VersionMajor;
             }
+            // both parts of the version are a single DIGIT
+            // https://www.rfc-editor.org/rfc/rfc9112#section-2.3
             ReqLineState::VersionMajor => {
-                if ch == '.' {
-                    self.req_line_state = ReqLineState::VersionFirstMinor;
-                } else if ch.is_ascii_digit() {
-                    self.http_version_major *= 10 + (ch as u8 - b'0');
-                } else {
-                    return Err(ParserError::UnexpectedChar("DIGIT after DIGIT"));
+                if ch != b'.' {
+                    return Err(ParserError::UnexpectedChar(". after VERSION MAJOR"));
                 }
+                self.req_line_state = ReqLineState::VersionFirstMinor;
             }
             ReqLineState::VersionFirstMinor => {
                 if !ch.is_ascii_digit() {
                     return Err(ParserError::UnexpectedChar("VERSION MINOR after ."));
                 }
-                self.http_version_minor = ch as u8 - b'0';
+                self.http_version_minor = ch - b'0';
                 self.req_line_state = ReqLineState::VersionMinor;
             }
             ReqLineState::VersionMinor => {
-                if ch == CR {
-                    self.req_line_state = ReqLineState::AlmostDone;
-                } else if ch.is_ascii_digit() {
-                    self.http_version_minor *= 10 + (ch as u8 - b'0');
-                } else {
-                    return Err(ParserError::UnexpectedChar("DIGIT after DIGIT"));
+                if ch != CR {
+                    return Err(ParserError::UnexpectedChar("CR after VERSION MINOR"));
                 }
+                self.req_line_state = ReqLineState::AlmostDone;
             }
             ReqLineState::AlmostDone => {
                 if ch != LF {
                     return Err(ParserError::UnexpectedChar("LF after CR"));
                 }
+                let uri = Uri::parse(&self.request_target).map_err(|_| ParserError::InvalidTarget)?;
+                // authority-form is for CONNECT and nothing else
+                let connect = self.method_parsed == Some(HttpMethod::Connect);
+                let allowed = match uri.form() {
+                    TargetForm::Origin | TargetForm::Absolute => !connect,
+                    TargetForm::Authority => connect,
+                    TargetForm::Asterisk => self.method_parsed == Some(HttpMethod::Options),
+                };
+                if !allowed {
+                    return Err(ParserError::InvalidTarget);
+                }
+                self.path = path::normalize(uri.path(), &self.path_normalization).map_err(ParserError::InvalidPath)?;
+                self.uri = Some(uri);
                 return Ok(true);
             }
         }
@@ -212,59 +358,81 @@ impl Parser {
         Ok(false)
     }
 
-    // FIXME: handle obs-fold
-    // https://datatracker.ietf.org/doc/html/rfc7230#section-3.2
-    fn parse_headers(&mut self, ch: char) -> Result<bool, ParserError> {
+    // field-line = field-name ":" OWS field-value OWS
+    // https://www.rfc-editor.org/rfc/rfc9112#section-5
+    fn parse_headers(&mut self, ch: u8) -> Result<bool, ParserError> {
         match self.header_state {
             HeaderState::Name => {
                 if ch == CR && self.current_header_name.is_empty() {
                     self.header_state = HeaderState::HeadersAlmostDone;
-                } else if Parser::is_token(&ch) {
-                    self.current_header_name.push(ch);
-                } else if ch == ':' {
+                } else if Parser::is_token(ch) {
+                    self.current_header_name.push(ch as char);
+                } else if ch == b':' && !self.current_header_name.is_empty() {
                     self.header_state = HeaderState::OWSBeforeValue;
                 } else {
                     return Err(ParserError::UnexpectedChar("token expected for header field name"));
                 }
             }
             HeaderState::OWSBeforeValue => {
-                if ch == ' ' {
-                    self.header_state = HeaderState::FirstValue;
-                } else if Parser::is_valid_field_content_char(ch as u8) {
+                if ch == SP || ch == HTAB {
+                    // skipped
+                } else if ch == CR {
+                    self.header_state = HeaderState::AlmostDone;
+                } else if Parser::is_valid_field_content_char(ch) {
                     self.current_header_value.push(ch);
                     self.header_state = HeaderState::Value;
                 } else {
                     return Err(ParserError::UnexpectedChar("in OWSBeforeValue"));
                 }
             }
-            HeaderState::FirstValue => {
-                if Parser::is_valid_field_content_char(ch as u8) {
-                    self.current_header_value.push(ch);
-                    self.header_state = HeaderState::Value;
-                }
-            }
             HeaderState::Value => {
-                // FIXME: handle OWS after field-value
-                if ch == ' ' || ch == '\t' || Parser::is_valid_field_content_char(ch as u8) {
+                // trailing whitespace is kept for now and trimmed once the
+                // line is complete
+                if ch == SP || ch == HTAB || Parser::is_valid_field_content_char(ch) {
                     self.current_header_value.push(ch);
                 } else if ch == CR {
                     self.header_state = HeaderState::AlmostDone;
+                } else {
+                    return Err(ParserError::UnexpectedChar("in field value"));
                 }
             }
-            HeaderState::OWSAfterValue => {}
             // In this state we got one 'CR'
             HeaderState::AlmostDone => {
                 if ch == LF {
-                    // we got one complete header. Push it to the HeaderMap
-                    //FIXME: find a way to not clone here
-                    self.header_map.insert(self.current_header_name.to_ascii_lowercase(), self.current_header_value.to_ascii_lowercase());
-                    self.current_header_value.clear();
-                    self.current_header_name.clear();
-                    self.header_state = HeaderState::Name;
+                    self.header_count += 1;
+                    if self.header_count > self.limits.header_count {
+                        return Err(ParserError::TooManyHeaders);
+                    }
+                    self.header_line_len = 0;
+                    self.header_state = HeaderState::LineStart;
                 } else {
                     return Err(ParserError::UnexpectedChar("expected LF after CR"));
                 }
             }
+            // The previous line is only complete once we know the next one
+            // doesn't continue it.
+            HeaderState::LineStart => {
+                if ch == SP || ch == HTAB {
+                    if self.obs_fold == ObsFold::Reject {
+                        return Err(ParserError::ObsFold);
+                    }
+                    // the fold and the whitespace around it become one SP
+                    self.trim_value();
+                    if !self.current_header_value.is_empty() {
+                        self.current_header_value.push(SP);
+                    }
+                    self.header_count -= 1;
+                    self.header_state = HeaderState::OWSBeforeValue;
+                } else {
+                    // we got one complete header. Push it to the HeaderMap
+                    self.trim_value();
+                    let name = std::mem::take(&mut self.current_header_name);
+                    let value = std::mem::take(&mut self.current_header_value);
+                    self.header_map.append(name, value);
+                    self.header_state = HeaderState::Name;
+                    return self.parse_headers(ch);
+                }
+            }
             // In this state we got one 'CR' from HeaderState::Name
             // meaning we already have one CRLF
             HeaderState::HeadersAlmostDone => {
@@ -278,58 +446,359 @@ impl Parser {
         Ok(false)
     }
 
-    pub(crate) fn feed(&mut self, buffer: &[u8]) -> Result<bool, ParserError> {
-        for i in buffer.iter() {
-            if !i.is_ascii() {
-                panic!()
+    fn trim_value(&mut self) {
+        while matches!(self.current_header_value.last(), Some(&SP) | Some(&HTAB)) {
+            self.current_header_value.pop();
+        }
+    }
+
+    // Called once the empty line after the headers has been seen.
+    fn start_body(&mut self) -> Result<(), ParserError> {
+        // HTTP/1.1 requests need exactly one Host
+        // https://www.rfc-editor.org/rfc/rfc9112#section-3.2
+        let mut hosts = self.header_map.get_all("host");
+        match (hosts.next(), hosts.next()) {
+            (Some(host), None) => {
+                let valid = std::str::from_utf8(host).is_ok_and(|host| uri::check_authority(host).is_ok());
+                if !valid {
+                    return Err(ParserError::InvalidHost);
+                }
+            }
+            (None, _) if self.http_version_minor == 0 => {}
+            _ => return Err(ParserError::InvalidHost),
+        }
+        drop(hosts);
+
+        // only the parts are kept of multipart bodies larger than
+        // `body_size`, so those may be as large as a multipart body may be
+        self.multipart = multipart::for_request(&self.header_map, self.limits);
+        if let Some(Ok(_)) = self.multipart {
+            self.body_limit = self.limits.body_size.max(self.limits.multipart_size);
+        }
+        if self.header_map.get("transfer-encoding").is_some() {
+            return self.start_chunked();
+        }
+
+        // repeated Content-Length fields, or lists of them, are only accepted
+        // if they all agree
+        let mut length = None;
+        for value in self.header_map.get_all("content-length").flat_map(|value| value.split(|&b| b == b',')) {
+            // 1*DIGIT, which rules out the sign `parse` would take
+            let value = value.trim_ascii();
+            if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+                return Err(ParserError::InvalidContentLength);
+            }
+            let value = std::str::from_utf8(value).ok()
+                .and_then(|value| value.parse::<usize>().ok())
+                .ok_or(ParserError::InvalidContentLength)?;
+            if length.is_some_and(|length| length != value) {
+                return Err(ParserError::InvalidContentLength);
+            }
+            length = Some(value);
+        }
+        let length = length.unwrap_or(0);
+        if length > self.body_limit {
+            return Err(ParserError::BodyTooLarge);
+        }
+
+        if length == 0 {
+            self.end_body()?;
+        } else {
+            self.body_remaining = length;
+            self.state = State::Body;
+        }
+        Ok(())
+    }
+
+    // Keeps the body, handing it to the multipart parser as well if there is
+    // one. Of a multipart body growing past `body_size` only the parts are
+    // kept, which fails the request once they can't be had either.
+    fn push_body(&mut self, data: &[u8]) -> Result<(), ParserError> {
+        self.body_received += data.len();
+        if let Some(Ok(parser)) = &mut self.multipart {
+            if let Err(err) = parser.feed(data) {
+                self.multipart = Some(Err(err));
+            }
+        }
+        if self.body_received <= self.limits.body_size {
+            self.body.extend_from_slice(data);
+            return Ok(());
+        }
+        self.body = Vec::new();
+        match self.multipart.take() {
+            Some(Err(err)) => Err(ParserError::Multipart(err)),
+            parser => {
+                self.multipart = parser;
+                Ok(())
+            }
+        }
+    }
+
+    fn end_body(&mut self) -> Result<(), ParserError> {
+        self.state = State::Done;
+        match self.multipart.take().map(|parser| parser.and_then(MultipartParser::finish)) {
+            Some(Err(err)) if self.body_received > self.limits.body_size => Err(ParserError::Multipart(err)),
+            parts => {
+                self.parts = parts;
+                Ok(())
             }
+        }
+    }
 
-            let ch = *i as char;
+    // Only the chunked coding is understood, and it has to come last for the
+    // end of the body to be found at all. A Content-Length next to it could
+    // be read differently by whoever else sees the request, so the request
+    // is refused rather than framed either way.
+    // https://www.rfc-editor.org/rfc/rfc9112#section-6.3
+    fn start_chunked(&mut self) -> Result<(), ParserError> {
+        if self.http_version_minor == 0 || self.header_map.get("content-length").is_some() {
+            return Err(ParserError::InvalidTransferEncoding);
+        }
+        let codings: Vec<&[u8]> = self.header_map.get_all("transfer-encoding")
+            .flat_map(|value| value.split(|&b| b == b','))
+            .map(<[u8]>::trim_ascii)
+            .filter(|coding| !coding.is_empty())
+            .collect();
+        match codings.as_slice() {
+            [coding] if coding.eq_ignore_ascii_case(b"chunked") => {}
+            [.., last] if last.eq_ignore_ascii_case(b"chunked") => return Err(ParserError::UnsupportedTransferCoding),
+            _ => return Err(ParserError::InvalidTransferEncoding),
+        }
+        self.header_line_len = 0;
+        self.state = State::Chunked;
+        Ok(())
+    }
+
+    // Takes one byte of a chunked body outside chunk data. Chunk extensions
+    // and trailer fields are read past and dropped.
+    fn parse_chunked(&mut self, ch: u8) -> Result<bool, ParserError> {
+        match self.chunk_state {
+            ChunkState::SizeStart | ChunkState::Size => {
+                if let Some(digit) = (ch as char).to_digit(16) {
+                    self.body_remaining = self.body_remaining.checked_mul(16)
+                        .and_then(|size| size.checked_add(digit as usize))
+                        .ok_or(ParserError::InvalidChunk)?;
+                    self.chunk_state = ChunkState::Size;
+                } else if self.chunk_state == ChunkState::SizeStart {
+                    return Err(ParserError::InvalidChunk);
+                } else if ch == b';' || ch == SP || ch == HTAB {
+                    self.chunk_state = ChunkState::Extension;
+                } else if ch == CR {
+                    self.chunk_state = ChunkState::SizeAlmostDone;
+                } else {
+                    return Err(ParserError::InvalidChunk);
+                }
+            }
+            ChunkState::Extension => {
+                if ch == CR {
+                    self.chunk_state = ChunkState::SizeAlmostDone;
+                } else if ch != SP && ch != HTAB && !Parser::is_valid_field_content_char(ch) {
+                    return Err(ParserError::InvalidChunk);
+                }
+            }
+            ChunkState::SizeAlmostDone => {
+                if ch != LF {
+                    return Err(ParserError::InvalidChunk);
+                }
+                if self.body_remaining > self.body_limit - self.body_received {
+                    return Err(ParserError::BodyTooLarge);
+                }
+                self.header_line_len = 0;
+                self.chunk_state = if self.body_remaining == 0 { ChunkState::TrailerLineStart } else { ChunkState::Data };
+            }
+            // taken whole by `feed_at`
+            ChunkState::Data => unreachable!(),
+            ChunkState::DataCR => {
+                if ch != CR {
+                    return Err(ParserError::InvalidChunk);
+                }
+                self.chunk_state = ChunkState::DataLF;
+            }
+            ChunkState::DataLF => {
+                if ch != LF {
+                    return Err(ParserError::InvalidChunk);
+                }
+                self.header_line_len = 0;
+                self.chunk_state = ChunkState::SizeStart;
+            }
+            ChunkState::TrailerLineStart => {
+                if ch == CR {
+                    self.chunk_state = ChunkState::TrailersAlmostDone;
+                } else if Parser::is_token(ch) {
+                    self.chunk_state = ChunkState::Trailer;
+                } else {
+                    return Err(ParserError::UnexpectedChar("token expected for trailer field name"));
+                }
+            }
+            ChunkState::Trailer => {
+                if ch == CR {
+                    self.chunk_state = ChunkState::TrailerAlmostDone;
+                } else if ch != SP && ch != HTAB && !Parser::is_valid_field_content_char(ch) {
+                    return Err(ParserError::UnexpectedChar("in trailer field"));
+                }
+            }
+            ChunkState::TrailerAlmostDone => {
+                if ch != LF {
+                    return Err(ParserError::UnexpectedChar("expected LF after CR"));
+                }
+                self.header_line_len = 0;
+                self.chunk_state = ChunkState::TrailerLineStart;
+            }
+            ChunkState::TrailersAlmostDone => {
+                if ch != LF {
+                    return Err(ParserError::UnexpectedChar("expected LF after CR"));
+                }
+                // the body is whole now, and framed like any other
+                // https://www.rfc-editor.org/rfc/rfc9112#section-7.1.3
+                self.header_map.remove("transfer-encoding");
+                self.header_map.insert("Content-Length", self.body.len().to_string());
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Returns how many bytes of `buffer` were consumed. Parsing stops right
+    // after a complete request, anything left over (e.g. a pipelined request)
+    // belongs to the next parser.
+    pub(crate) fn feed(&mut self, buffer: &[u8]) -> Result<usize, ParserError> {
+        let mut pos = 0;
+        let result = self.feed_at(buffer, &mut pos);
+        if result.is_err() {
+            // `pos` is already past the byte that was rejected
+            self.error_offset = self.consumed + pos.saturating_sub(1);
+        }
+        self.consumed += pos;
+        result.map(|_| pos)
+    }
+
+    fn feed_at(&mut self, buffer: &[u8], pos: &mut usize) -> Result<(), ParserError> {
+        while *pos < buffer.len() {
+            match self.state {
+                State::Body => {
+                    let n = self.body_remaining.min(buffer.len() - *pos);
+                    self.push_body(&buffer[*pos..*pos + n])?;
+                    self.body_remaining -= n;
+                    *pos += n;
+                    if self.body_remaining == 0 {
+                        self.end_body()?;
+                    }
+                    continue;
+                }
+                State::Chunked if self.chunk_state == ChunkState::Data => {
+                    let n = self.body_remaining.min(buffer.len() - *pos);
+                    self.push_body(&buffer[*pos..*pos + n])?;
+                    self.body_remaining -= n;
+                    *pos += n;
+                    if self.body_remaining == 0 {
+                        self.chunk_state = ChunkState::DataCR;
+                    }
+                    continue;
+                }
+                State::Done => break,
+                State::RequestLine | State::Header | State::Chunked => {}
+            }
+
+            let ch = buffer[*pos];
+            *pos += 1;
 
             match self.state {
                 State::RequestLine => {
+                    self.request_line_len += 1;
+                    if self.request_line_len > self.limits.request_line {
+                        return Err(ParserError::RequestLineTooLong);
+                    }
                     if self.parse_request_line(ch)? {
                         self.state = State::Header;
                     }
                 }
                 State::Header => {
+                    self.headers_len += 1;
+                    self.header_line_len += 1;
+                    if self.header_line_len > self.limits.header_size || self.headers_len > self.limits.headers_total {
+                        return Err(ParserError::HeaderTooLarge);
+                    }
                     if self.parse_headers(ch)? {
-                        self.state = State::Done;
-                        return Ok(true);
+                        self.start_body()?;
                     }
                 }
-                // when we are done the data being fed is irrelevant
-                State::Done => return Ok(true)
+                State::Chunked => {
+                    // chunk size lines count like header lines, trailers
+                    // towards the header section as well
+                    self.header_line_len += 1;
+                    if self.header_line_len > self.limits.header_size {
+                        return Err(ParserError::HeaderTooLarge);
+                    }
+                    if matches!(self.chunk_state, ChunkState::TrailerLineStart | ChunkState::Trailer | ChunkState::TrailerAlmostDone) {
+                        self.headers_len += 1;
+                        if self.headers_len > self.limits.headers_total {
+                            return Err(ParserError::HeaderTooLarge);
+                        }
+                    }
+                    if self.parse_chunked(ch)? {
+                        self.end_body()?;
+                    }
+                }
+                State::Body | State::Done => unreachable!(),
             }
         }
 
-        Ok(false)
+        Ok(())
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    // Offset into the request's bytes of the one that made the last `feed`
+    // fail.
+    pub(crate) fn error_offset(&self) -> usize {
+        self.error_offset
+    }
+
+    // whether any byte of a request has been received yet
+    pub(crate) fn is_started(&self) -> bool {
+        self.consumed > 0
+    }
+
+    pub(crate) fn in_body(&self) -> bool {
+        self.state == State::Body || self.state == State::Chunked
     }
 
     pub(crate) fn finish(self) -> Result<HttpRequest, ParserError> {
         if self.state != State::Done {
             return Err(ParserError::NotReady);
         }
-        Ok(HttpRequest::new(self.request_target, self.header_map, self.method_parsed))
+        let uri = self.uri.ok_or(ParserError::NotReady)?;
+        let method = self.method_parsed.ok_or(ParserError::NotReady)?;
+        let mut request = HttpRequest::new(uri, self.path, self.header_map, method, self.http_version_minor, self.body);
+        if let Some(parts) = self.parts {
+            request.set_parts(parts, self.body_received > self.limits.body_size);
+        }
+        Ok(request)
     }
 }
 
+#[cfg(test)]
 mod test {
-    use crate::parser::Parser;
+    use crate::config::{Limits, ObsFold};
+    use crate::parser::{Parser, ParserError};
+    use crate::path::InvalidPath;
+    use crate::util::{HttpMethod, HttpStatusCode};
 
     #[test]
     fn test_parse_headers_valid() {
         let mut parser = Parser::new();
         let headers = "hello: world\r\nhoware: y ou_doing\r\n\r\n43e6tygse";
-        for c in headers.chars() {
+        for &c in headers.as_bytes() {
             let res = parser.parse_headers(c).expect("shouldn't error");
             if res {
                 break;
             }
         }
-        assert_eq!(parser.header_map.len(), 2);
-        assert_eq!(parser.header_map.get("hello").unwrap(), "world");
-        assert_eq!(parser.header_map.get("howare").unwrap(), "y ou_doing");
+        assert_eq!(parser.header_map.iter().count(), 2);
+        assert_eq!(parser.header_map.get("hello"), Some(&b"world"[..]));
+        assert_eq!(parser.header_map.get("howare"), Some(&b"y ou_doing"[..]));
     }
 
     #[test]
@@ -337,11 +806,267 @@ mod test {
     fn test_parse_headers_invalid() {
         let mut parser = Parser::new();
         let headers = "in<v:alid\r\n\r\n";
-        for c in headers.chars() {
+        for &c in headers.as_bytes() {
             let res = parser.parse_headers(c).unwrap();
             if res {
                 break;
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_feed_body_and_pipelined_request() {
+        let mut parser = Parser::new();
+        let input = b"POST /a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\nHost: x\r\n\r\n";
+        let consumed = parser.feed(input).expect("shouldn't error");
+        assert!(parser.is_done());
+        assert_eq!(&input[consumed..], b"GET /b HTTP/1.1\r\nHost: x\r\n\r\n");
+
+        let request = parser.finish().expect("should be done");
+        assert_eq!(request.path(), "/a");
+        assert_eq!(request.body(), b"hello");
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits { request_line: 20, header_count: 2, body_size: 4, ..Limits::default() };
+
+        let mut parser = Parser::with_limits(limits);
+        let err = parser.feed(b"GET /a-very-long-target HTTP/1.1\r\nHost: x\r\n\r\n").unwrap_err();
+        assert!(matches!(err, ParserError::RequestLineTooLong));
+
+        let mut parser = Parser::with_limits(limits);
+        let err = parser.feed(b"GET / HTTP/1.1\r\nHost: x\r\na: b\r\nc: d\r\n\r\n").unwrap_err();
+        assert!(matches!(err, ParserError::TooManyHeaders));
+
+        let mut parser = Parser::with_limits(limits);
+        let err = parser.feed(b"POST / HTTP/1.1\r\nHost: x\r\ncontent-length: 5\r\n\r\n").unwrap_err();
+        assert!(matches!(err, ParserError::BodyTooLarge));
+    }
+
+    #[test]
+    fn test_multipart_body() {
+        let limits = Limits { body_size: 16, multipart_size: 200, ..Limits::default() };
+        let head = "POST /up HTTP/1.1\r\nHost: x\r\nContent-Type: multipart/form-data; boundary=b\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let body = "--b\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\r\n0123456789abcdef0123456789\r\n--b--\r\n";
+        let chunked = |body: &str| format!("{:x}\r\n{}\r\n0\r\n\r\n", body.len(), body);
+
+        // larger than `body_size`, split into parts a few bytes at a time
+        let mut parser = Parser::with_limits(limits);
+        for chunk in format!("{}{}", head, chunked(body)).as_bytes().chunks(5) {
+            parser.feed(chunk).unwrap();
+        }
+        let mut request = parser.finish().unwrap();
+        assert!(request.body_dropped());
+        assert!(request.body().is_empty());
+        let parts = request.take_parts().unwrap();
+        assert_eq!(parts[0].filename(), Some("a.txt"));
+        assert_eq!(parts[0].len(), 26);
+
+        // kept whole as well while it fits
+        let small = "--b\r\n\r\nhi\r\n--b--";
+        let mut parser = Parser::with_limits(limits);
+        parser.feed(format!("{}{}", head, chunked(small)).as_bytes()).unwrap();
+        let mut request = parser.finish().unwrap();
+        assert!(!request.body_dropped());
+        assert_eq!(request.body(), small.as_bytes());
+        assert_eq!(request.take_parts().unwrap().len(), 1);
+
+        // a broken one only fails once nothing else is left of it
+        let broken = body.replace("--b--", "--c--");
+        let mut parser = Parser::with_limits(limits);
+        let err = parser.feed(format!("{}{}", head, chunked(&broken)).as_bytes()).unwrap_err();
+        assert_eq!(err.status(), HttpStatusCode::BadRequest);
+        let too_large = format!("{}{}", head.replace("multipart/form-data; boundary=b", "text/plain"), chunked(body));
+        let err = Parser::with_limits(limits).feed(too_large.as_bytes()).unwrap_err();
+        assert!(matches!(err, ParserError::BodyTooLarge));
+    }
+
+    #[test]
+    fn test_error_status_and_offset() {
+        let mut parser = Parser::new();
+        parser.feed(b"GET / HT").unwrap();
+        let err = parser.feed(b"XP/1.1\r\n\r\n").unwrap_err();
+        assert_eq!(err.status(), HttpStatusCode::BadRequest);
+        assert_eq!(parser.error_offset(), 8);
+
+        let mut parser = Parser::new();
+        let err = parser.feed(b"GET / HTTP/2.0\r\n\r\n").unwrap_err();
+        assert_eq!(err.status(), HttpStatusCode::HTTPVersionNotSupported);
+        assert_eq!(parser.error_offset(), 11);
+    }
+
+    #[test]
+    fn test_version() {
+        let version_error = |line: &str| {
+            let mut parser = Parser::new();
+            parser.feed(format!("{}\r\nHost: x\r\n\r\n", line).as_bytes()).unwrap_err().status()
+        };
+        // one DIGIT each, anything longer is malformed rather than unsupported
+        assert_eq!(version_error("GET / HTTP/1.1111"), HttpStatusCode::BadRequest);
+        assert_eq!(version_error("GET / HTTP/1999"), HttpStatusCode::BadRequest);
+        assert_eq!(version_error("GET / HTTP/11.1"), HttpStatusCode::BadRequest);
+        assert_eq!(version_error("GET / HTTP/1."), HttpStatusCode::BadRequest);
+        assert_eq!(version_error("GET / HTTP/3.0"), HttpStatusCode::HTTPVersionNotSupported);
+
+        let mut parser = Parser::new();
+        parser.feed(b"GET / HTTP/1.0\r\nHost: x\r\n\r\n").unwrap();
+        assert!(parser.is_done());
+    }
+
+    #[test]
+    fn test_methods() {
+        let method = |input: &[u8]| {
+            let mut parser = Parser::new();
+            parser.feed(input).unwrap();
+            parser.finish().unwrap().method().clone()
+        };
+        assert_eq!(method(b"PATCH / HTTP/1.1\r\nHost: x\r\n\r\n"), HttpMethod::Patch);
+        assert_eq!(method(b"VERSION-CONTROL / HTTP/1.1\r\nHost: x\r\n\r\n"), HttpMethod::VersionControl);
+        assert_eq!(method(b"FOO.BAR_1 / HTTP/1.1\r\nHost: x\r\n\r\n"), HttpMethod::Extension("FOO.BAR_1".to_string()));
+        // method names are case-sensitive
+        assert_eq!(method(b"get / HTTP/1.1\r\nHost: x\r\n\r\n"), HttpMethod::Extension("get".to_string()));
+        assert_eq!(method(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n"), HttpMethod::Connect);
+
+        assert!(HttpMethod::Propfind.is_safe() && HttpMethod::Propfind.is_idempotent());
+        assert!(!HttpMethod::Put.is_safe() && HttpMethod::Put.is_idempotent());
+        assert!(!HttpMethod::Patch.is_idempotent());
+        assert!(!HttpMethod::Extension("GET".to_string()).is_safe());
+
+        for input in [&b"CONNECT / HTTP/1.1\r\nHost: x\r\n\r\n"[..], b"G{T / HTTP/1.1\r\nHost: x\r\n\r\n", b" GET / HTTP/1.1\r\n\r\n"] {
+            assert!(Parser::new().feed(input).is_err());
+        }
+    }
+
+    #[test]
+    fn test_non_ascii_bytes() {
+        let mut parser = Parser::new();
+        parser.feed(b"GET / HTTP/1.1\r\nHost: x\r\nx-name: caf\xe9 \xc3\xa9\r\n\r\n").expect("obs-text is allowed in values");
+        let request = parser.finish().expect("should be done");
+        assert_eq!(request.header("x-name"), Some(&b"caf\xe9 \xc3\xa9"[..]));
+        assert_eq!(request.header_str("x-name"), None);
+
+        let mut parser = Parser::new();
+        let err = parser.feed(b"GET /caf\xc3\xa9 HTTP/1.1\r\nHost: x\r\n\r\n").unwrap_err();
+        assert!(matches!(err, ParserError::InvalidRequestLineByte(0xc3)));
+
+        let mut parser = Parser::new();
+        let err = parser.feed(b"G\xffT / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap_err();
+        assert!(matches!(err, ParserError::InvalidRequestLineByte(0xff)));
+    }
+
+    #[test]
+    fn test_repeated_headers() {
+        let mut parser = Parser::new();
+        parser.feed(b"GET / HTTP/1.1\r\nHost: x\r\nCookie: a=B\r\nX-Token: AbC\r\ncookie: c=D\r\n\r\n").unwrap();
+        let request = parser.finish().expect("should be done");
+        assert_eq!(request.headers().get_combined("COOKIE").unwrap().as_ref(), b"a=B; c=D");
+        assert_eq!(request.headers().iter().nth(2), Some(("X-Token", &b"AbC"[..])));
+
+        let mut parser = Parser::new();
+        parser.feed(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nok").unwrap();
+        assert!(parser.is_done());
+
+        let mut parser = Parser::new();
+        let err = parser.feed(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 2, 3\r\n\r\nok").unwrap_err();
+        assert!(matches!(err, ParserError::InvalidContentLength));
+
+        let mut parser = Parser::new();
+        let err = parser.feed(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: +2\r\n\r\nok").unwrap_err();
+        assert!(matches!(err, ParserError::InvalidContentLength));
+    }
+
+    #[test]
+    fn test_chunked_body() {
+        let mut parser = Parser::new();
+        let input = b"POST /a HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n5;name=value\r\nhello\r\n1\r\n!\r\n0\r\nX-Checksum: 1\r\n\r\nGET /b HTTP/1.1\r\n";
+        // split inside the size line, the data and the trailers
+        let mut consumed = 0;
+        for piece in [&input[..60], &input[60..75], &input[75..]] {
+            consumed += parser.feed(piece).expect("shouldn't error");
+        }
+        assert!(parser.is_done());
+        assert_eq!(&input[consumed..], b"GET /b HTTP/1.1\r\n");
+        let request = parser.finish().expect("should be done");
+        assert_eq!(request.body(), b"hello!");
+        assert_eq!(request.header("transfer-encoding"), None);
+        assert_eq!(request.header("content-length"), Some(&b"6"[..]));
+
+        let error = |input: &[u8]| {
+            let mut parser = Parser::with_limits(Limits { body_size: 8, ..Limits::default() });
+            parser.feed(input).unwrap_err()
+        };
+        // the chunk data must not be taken for the next request
+        let smuggled = error(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nGET / HTTP/1.1\r\n\r\n");
+        assert!(matches!(smuggled, ParserError::InvalidChunk));
+        let both = error(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n");
+        assert!(matches!(both, ParserError::InvalidTransferEncoding));
+        let not_last = error(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked, gzip\r\n\r\n");
+        assert!(matches!(not_last, ParserError::InvalidTransferEncoding));
+        let http10 = error(b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert_eq!(http10.status(), HttpStatusCode::BadRequest);
+        let gzip = error(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip, chunked\r\n\r\n");
+        assert_eq!(gzip.status(), HttpStatusCode::NotImplemented);
+        let large = error(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n4\r\n");
+        assert!(matches!(large, ParserError::BodyTooLarge));
+        let overflow = error(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\nfffffffffffffffff\r\n");
+        assert!(matches!(overflow, ParserError::InvalidChunk));
+    }
+
+    #[test]
+    fn test_ows_and_obs_fold() {
+        let input = b"GET / HTTP/1.1\r\nHost: x\r\nA:\t value \t\r\nB:\r\nC: one  \r\n \t two\r\n\tthree\r\n\r\n";
+
+        let mut parser = Parser::new();
+        let err = parser.feed(input).unwrap_err();
+        assert!(matches!(err, ParserError::ObsFold));
+        assert_eq!(err.status(), HttpStatusCode::BadRequest);
+
+        let mut parser = Parser::new().with_obs_fold(ObsFold::Replace);
+        parser.feed(input).expect("folds are replaced");
+        let request = parser.finish().expect("should be done");
+        assert_eq!(request.header("a"), Some(&b"value"[..]));
+        assert_eq!(request.header("b"), Some(&b""[..]));
+        assert_eq!(request.header("c"), Some(&b"one two three"[..]));
+
+        let mut parser = Parser::new().with_obs_fold(ObsFold::Replace);
+        let err = parser.feed(b"GET / HTTP/1.1\r\n folded: first\r\n\r\n").unwrap_err();
+        assert!(matches!(err, ParserError::UnexpectedChar(_)));
+    }
+
+    #[test]
+    fn test_target_and_host() {
+        let mut parser = Parser::new();
+        parser.feed(b"GET /a?b=c HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        let request = parser.finish().expect("should be done");
+        assert_eq!(request.path(), "/a");
+        assert_eq!(request.query(), Some("b=c"));
+        assert_eq!(request.effective_uri().to_string(), "http://example.com/a?b=c");
+
+        let mut parser = Parser::new();
+        parser.feed(b"GET /static/%2e%2e/secret HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let request = parser.finish().expect("should be done");
+        assert_eq!(request.target().path(), "/static/%2e%2e/secret");
+        assert_eq!(request.path(), "/secret");
+
+        let err = Parser::new().feed(b"GET /a%2fb HTTP/1.1\r\nHost: x\r\n\r\n").unwrap_err();
+        assert!(matches!(err, ParserError::InvalidPath(InvalidPath::EncodedSlash)));
+
+        let mut parser = Parser::new();
+        parser.feed(b"GET http://other.org/x HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        let request = parser.finish().expect("should be done");
+        assert_eq!(request.effective_uri().to_string(), "http://other.org/x");
+
+        let mut parser = Parser::new();
+        parser.feed(b"GET / HTTP/1.0\r\n\r\n").expect("HTTP/1.0 doesn't need Host");
+
+        for input in [&b"GET / HTTP/1.1\r\n\r\n"[..], b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n", b"GET / HTTP/1.1\r\nHost: u@a\r\n\r\n"] {
+            let err = Parser::new().feed(input).unwrap_err();
+            assert!(matches!(err, ParserError::InvalidHost));
+        }
+        for input in [&b"GET * HTTP/1.1\r\nHost: x\r\n\r\n"[..], b"GET /a|b HTTP/1.1\r\nHost: x\r\n\r\n", b"GET x:80 HTTP/1.1\r\nHost: x\r\n\r\n"] {
+            let err = Parser::new().feed(input).unwrap_err();
+            assert!(matches!(err, ParserError::InvalidTarget));
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt::{Display, Formatter};
+use crate::config::{EncodedSlash, PathNormalization};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InvalidPath {
+    EncodedNul,
+    EncodedSlash,
+    NotUtf8,
+}
+
+impl Display for InvalidPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidPath::EncodedNul => write!(f, "encoded NUL in path"),
+            InvalidPath::EncodedSlash => write!(f, "encoded slash in path"),
+            InvalidPath::NotUtf8 => write!(f, "path is not UTF-8"),
+        }
+    }
+}
+
+// Turns the raw path of a request into the one used for routing and file
+// lookup: percent-decoded, with slashes merged if configured and without
+// any "." or ".." segment. Decoding comes first so "%2e%2e" can't slip
+// through as a harmless looking name.
+pub(crate) fn normalize(raw: &str, options: &PathNormalization) -> Result<String, InvalidPath> {
+    let decoded = decode(raw, options.encoded_slash)?;
+    if !decoded.starts_with('/') {
+        return Ok(decoded);
+    }
+    let merged;
+    let path = if options.merge_slashes {
+        merged = merge_slashes(&decoded);
+        &merged
+    } else {
+        &decoded
+    };
+    Ok(remove_dot_segments(path))
+}
+
+fn decode(raw: &str, encoded_slash: EncodedSlash) -> Result<String, InvalidPath> {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match (bytes[i], bytes.get(i + 1..i + 3)) {
+            (b'%', Some(&[hi, lo])) => hex_value(hi).zip(hex_value(lo)).map(|(hi, lo)| hi << 4 | lo),
+            _ => None,
+        };
+        match escaped {
+            Some(0) => return Err(InvalidPath::EncodedNul),
+            Some(b'/') => match encoded_slash {
+                EncodedSlash::Reject => return Err(InvalidPath::EncodedSlash),
+                EncodedSlash::Decode => decoded.push(b'/'),
+                EncodedSlash::Keep => decoded.extend_from_slice(b"%2F"),
+            },
+            Some(byte) => decoded.push(byte),
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+                continue;
+            }
+        }
+        i += 3;
+    }
+    String::from_utf8(decoded).map_err(|_| InvalidPath::NotUtf8)
+}
+
+pub(crate) fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+fn merge_slashes(path: &str) -> String {
+    let mut merged = String::with_capacity(path.len());
+    for ch in path.chars() {
+        if ch != '/' || !merged.ends_with('/') {
+            merged.push(ch);
+        }
+    }
+    merged
+}
+
+// https://www.rfc-editor.org/rfc/rfc3986#section-5.2.4
+// ".." never climbs above the root.
+fn remove_dot_segments(path: &str) -> String {
+    let mut output: Vec<&str> = Vec::new();
+    let mut segments = path.split('/').skip(1).peekable();
+    while let Some(segment) = segments.next() {
+        let last = segments.peek().is_none();
+        match segment {
+            "." => {}
+            ".." => {
+                output.pop();
+            }
+            segment => {
+                output.push(segment);
+                continue;
+            }
+        }
+        // a trailing dot segment still names a directory
+        if last {
+            output.push("");
+        }
+    }
+    format!("/{}", output.join("/"))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{EncodedSlash, PathNormalization};
+    use crate::path::{normalize, InvalidPath};
+
+    #[test]
+    fn test_normalize() {
+        let options = PathNormalization::default();
+        assert_eq!(normalize("/a/./b/../c", &options), Ok("/a/c".to_string()));
+        assert_eq!(normalize("/a/b/..", &options), Ok("/a/".to_string()));
+        assert_eq!(normalize("/../../etc/passwd", &options), Ok("/etc/passwd".to_string()));
+        assert_eq!(normalize("/static/%2e%2e/%2E%2e/secret", &options), Ok("/secret".to_string()));
+        assert_eq!(normalize("//a///b/", &options), Ok("/a/b/".to_string()));
+        assert_eq!(normalize("/caf%C3%A9%20bar", &options), Ok("/café bar".to_string()));
+        assert_eq!(normalize("/a%00.txt", &options), Err(InvalidPath::EncodedNul));
+        assert_eq!(normalize("/a%2Fb", &options), Err(InvalidPath::EncodedSlash));
+        assert_eq!(normalize("/%ff", &options), Err(InvalidPath::NotUtf8));
+        assert_eq!(normalize("", &options), Ok(String::new()));
+
+        let options = PathNormalization { merge_slashes: false, encoded_slash: EncodedSlash::Decode };
+        assert_eq!(normalize("/a//b/%2F..", &options), Ok("/a//b/".to_string()));
+
+        let options = PathNormalization { merge_slashes: true, encoded_slash: EncodedSlash::Keep };
+        assert_eq!(normalize("/a%2f../b", &options), Ok("/a%2F../b".to_string()));
+    }
+}
@@ -1,33 +1,170 @@
-use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use crate::config::Limits;
+use crate::form::{self, FormError, Params};
+use crate::headers::HeaderMap;
+use crate::multipart::{MultipartError, Part, ReceivedParts};
+use crate::tls::TlsInfo;
+use crate::uri::Uri;
 use crate::util::HttpMethod;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct HttpRequest {
     src_addr: Option<SocketAddr>,
-    target: String,
-    headers: HashMap<String, String>,
+    tls: Option<Arc<TlsInfo>>,
+    target: Uri,
+    effective_uri: Uri,
+    // percent-decoded and without dot segments
+    path: String,
+    headers: HeaderMap,
     method: HttpMethod,
+    // HTTP/1.x, the parser refuses other major versions
+    minor_version: u8,
+    body: Vec<u8>,
+    // the parts of a multipart/form-data body, split off while it arrived
+    parts: ReceivedParts,
+    // multipart bodies larger than `body_size` are only kept as parts
+    body_dropped: bool,
 }
 
 impl HttpRequest {
-   pub(crate) fn new(target: String, headers: HashMap<String, String>, method: HttpMethod) -> Self {
-       HttpRequest {
-          src_addr: None,
-           target,
-           headers,
-           method
-       }
-   }
-    pub(crate) fn target(&self) -> &str {
+    pub(crate) fn new(target: Uri, path: String, headers: HeaderMap, method: HttpMethod, minor_version: u8, body: Vec<u8>) -> Self {
+        HttpRequest {
+            src_addr: None,
+            tls: None,
+            effective_uri: effective_uri(&target, &headers, false),
+            target,
+            path,
+            headers,
+            method,
+            minor_version,
+            body,
+            parts: ReceivedParts::default(),
+            body_dropped: false,
+        }
+    }
+
+    pub(crate) fn set_src_addr(&mut self, addr: SocketAddr) {
+        self.src_addr = Some(addr);
+    }
+
+    pub(crate) fn src_addr(&self) -> Option<&SocketAddr> {
+        self.src_addr.as_ref()
+    }
+
+    // Set for requests received over TLS, which makes the effective URI
+    // `https`.
+    pub(crate) fn set_tls(&mut self, tls: Arc<TlsInfo>) {
+        self.tls = Some(tls);
+        self.effective_uri = effective_uri(&self.target, &self.headers, true);
+    }
+
+    pub(crate) fn tls(&self) -> Option<&TlsInfo> {
+        self.tls.as_deref()
+    }
+
+    // the target as it appeared in the request line
+    pub(crate) fn target(&self) -> &Uri {
         &self.target
     }
 
-    pub(crate) fn header(&self, key: &str) -> Option<&String> {
-       self.headers.get(key)
+    // the target with scheme and authority filled in
+    pub(crate) fn effective_uri(&self) -> &Uri {
+        &self.effective_uri
+    }
+
+    // Whether the client is willing to send another request on the
+    // connection. HTTP/1.0 clients have to ask for it.
+    // https://www.rfc-editor.org/rfc/rfc9112#section-9.3
+    pub(crate) fn keep_alive(&self) -> bool {
+        if self.headers.has_token("connection", "close") {
+            return false;
+        }
+        self.minor_version >= 1 || self.headers.has_token("connection", "keep-alive")
+    }
+
+    // The normalized path, what routing and file lookup go by.
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub(crate) fn query(&self) -> Option<&str> {
+        self.target.query()
+    }
+
+    // the decoded query parameters, empty without a query
+    pub(crate) fn query_params(&self) -> Params {
+        form::parse_urlencoded(self.query().unwrap_or("").as_bytes())
+    }
+
+    // Decodes an application/x-www-form-urlencoded body.
+    pub(crate) fn form(&self, limits: &Limits) -> Result<Params, FormError> {
+        let media_type = self.header_str("content-type")
+            .map(|value| value.split(';').next().unwrap_or("").trim());
+        if !media_type.is_some_and(|media_type| media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded")) {
+            return Err(FormError::UnsupportedMediaType);
+        }
+        if self.body.len() > limits.form_size {
+            return Err(FormError::TooLarge);
+        }
+        let params = form::parse_urlencoded(&self.body);
+        if params.len() > limits.form_fields {
+            return Err(FormError::TooManyFields);
+        }
+        Ok(params)
+    }
+
+    // The parts of a multipart/form-data body, large ones spooled to
+    // temporary files. They can only be taken once.
+    pub(crate) fn take_parts(&mut self) -> Result<Vec<Part>, MultipartError> {
+        self.parts.take()
+    }
+
+    pub(crate) fn set_parts(&mut self, parts: Result<Vec<Part>, MultipartError>, body_dropped: bool) {
+        self.parts = ReceivedParts::new(parts);
+        self.body_dropped = body_dropped;
+    }
+
+    // Whether the body was too large to be kept whole, only its parts are
+    // left then.
+    pub(crate) fn body_dropped(&self) -> bool {
+        self.body_dropped
+    }
+
+    pub(crate) fn set_target(&mut self, target: Uri, path: String) {
+        self.effective_uri = effective_uri(&target, &self.headers, self.tls.is_some());
+        self.target = target;
+        self.path = path;
+    }
+
+    pub(crate) fn set_method(&mut self, method: HttpMethod) {
+        self.method = method;
+    }
+
+    // The first value exactly as received, it may contain obs-text.
+    pub(crate) fn header(&self, key: &str) -> Option<&[u8]> {
+        self.headers.get(key)
+    }
+
+    // The first value as text, `None` if it is missing or not valid UTF-8.
+    pub(crate) fn header_str(&self, key: &str) -> Option<&str> {
+        self.header(key).and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    pub(crate) fn headers(&self) -> &HeaderMap {
+        &self.headers
     }
 
     pub(crate) fn method(&self) -> &HttpMethod {
         &self.method
     }
-}
\ No newline at end of file
+
+    pub(crate) fn body(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+fn effective_uri(target: &Uri, headers: &HeaderMap, tls: bool) -> Uri {
+    let host = headers.get("host").and_then(|host| std::str::from_utf8(host).ok());
+    target.effective(if tls { "https" } else { "http" }, host)
+}
@@ -1,24 +1,45 @@
-use std::collections::HashMap;
 use std::io::Write;
-use std::net::TcpStream;
 use std::io::Error as IoError;
-use std::io::Read;
+use std::io::{ErrorKind, Read, Seek};
 use std::fs::File;
+use std::mem;
+use std::sync::mpsc::{self, Receiver, SyncSender};
 
+use crate::headers::HeaderMap;
+use crate::tunnel::Tunnel;
 use crate::util::HttpStatusCode;
 
 pub(crate) struct HttpResponse {
     status: HttpStatusCode,
-    headers: HashMap<String, String>,
+    headers: HeaderMap,
+    body: Option<Box<dyn Body>>,
+    plain_error_body: bool,
+    // the length of the body is announced but the body itself is not sent
+    head_only: bool,
+    from_upstream: bool,
+    // replaces the canonical reason phrase
+    reason: Option<&'static str>,
+    tunnel: Option<Tunnel>,
+    // the connection becomes a tunnel once this is sent, so no length is
+    // announced
+    opens_tunnel: bool,
 }
 
 const DEFAULT_HEADER_CAP: usize = 5;
+const FILE_BUFFER_SIZE: usize = 4096;
 
 impl HttpResponse {
     pub(crate) fn new(status: HttpStatusCode) -> Self {
         HttpResponse {
             status,
-            headers: HashMap::with_capacity(DEFAULT_HEADER_CAP),
+            headers: HeaderMap::with_capacity(DEFAULT_HEADER_CAP),
+            body: None,
+            plain_error_body: false,
+            head_only: false,
+            from_upstream: false,
+            reason: None,
+            tunnel: None,
+            opens_tunnel: false,
         }
     }
 
@@ -30,14 +51,228 @@ impl HttpResponse {
         HttpResponse::new(HttpStatusCode::NotFound)
     }
 
-    pub(crate) fn send(self) {}
+    pub(crate) fn request_timeout() -> Self {
+        HttpResponse::new(HttpStatusCode::RequestTimeout).header("Connection", "close")
+    }
+
+    pub(crate) fn internal_server_error() -> Self {
+        HttpResponse::new(HttpStatusCode::InternalServerError)
+    }
+
+    pub(crate) fn status(&self) -> &HttpStatusCode {
+        &self.status
+    }
+
+    pub(crate) fn with_status(mut self, status: HttpStatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    // answers a HEAD request
+    pub(crate) fn omit_body(mut self) -> Self {
+        self.head_only = true;
+        self
+    }
+
+    // marks a response relayed from a proxied upstream
+    pub(crate) fn mark_upstream(mut self) -> Self {
+        self.from_upstream = true;
+        self
+    }
+
+    pub(crate) fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub(crate) fn is_from_upstream(&self) -> bool {
+        self.from_upstream
+    }
+
+    pub(crate) fn with_reason(mut self, reason: &'static str) -> Self {
+        self.reason = Some(reason);
+        self
+    }
+
+    // Turns the connection into `tunnel` after this response, which has to
+    // be a 2xx to CONNECT.
+    pub(crate) fn with_tunnel(mut self, tunnel: Tunnel) -> Self {
+        self.tunnel = Some(tunnel);
+        self.opens_tunnel = true;
+        self
+    }
+
+    // Engines take the tunnel out before sending the response and run it
+    // once the response is out.
+    pub(crate) fn take_tunnel(&mut self) -> Option<Tunnel> {
+        self.tunnel.take()
+    }
+
+    // sets `name`, replacing any value it already had
+    pub(crate) fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    // adds another value for `name`, as relayed fields like Set-Cookie need
+    pub(crate) fn append_header(mut self, name: &str, value: &[u8]) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub(crate) fn with_body<B: Body + 'static>(self, body: B) -> Self {
+        self.with_boxed_body(Box::new(body))
+    }
+
+    pub(crate) fn with_boxed_body(mut self, body: Box<dyn Body>) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    // Error responses without a body get a generated one, HTML unless the
+    // client asked for text/plain instead.
+    pub(crate) fn prefer_plain_error_body(mut self, plain: bool) -> Self {
+        self.plain_error_body = plain;
+        self
+    }
+
+    // whether the connection can carry another request after this response
+    pub(crate) fn keep_alive(&self) -> bool {
+        let close = self.headers.has_token("Connection", "close");
+        let delimited = match &self.body {
+            Some(body) => body.size().is_some(),
+            None => true,
+        };
+        !close && delimited
+    }
+
+    pub(crate) fn send<W: Write>(self, stream: &mut W) -> Result<(), IoError> {
+        let (head, body) = self.into_parts();
+        stream.write_all(&head)?;
+        if let Some(body) = body {
+            body.write(stream)?;
+        }
+        stream.flush()
+    }
+
+    // Serializes the status line and headers, leaving the body to the caller
+    // so engines can choose how to transfer it.
+    pub(crate) fn into_parts(mut self) -> (Vec<u8>, Option<Box<dyn Body>>) {
+        if !self.status.allows_body() {
+            self.body = None;
+        } else if self.body.is_none() && self.status.is_error() {
+            let (body, content_type) = default_error_body(&self.status, self.plain_error_body);
+            self.headers.insert("Content-Type", content_type);
+            self.body = Some(Box::new(body));
+        }
+
+        let reason = self.reason.unwrap_or(self.status.reason());
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason).into_bytes();
+        for (name, value) in self.headers.iter() {
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.extend_from_slice(b"\r\n");
+        }
+
+        let size = match &self.body {
+            Some(body) => body.size(),
+            None => Some(0),
+        };
+        match size {
+            // 1xx, 204 and a tunnel being opened must not announce a length
+            // at all
+            Some(_) if self.status.is_informational() || self.status == HttpStatusCode::NoContent || self.opens_tunnel => {}
+            // a 304, and an answer to HEAD that already says how long its body
+            // is, describe a body they don't carry, so its length can only be
+            // repeated, never made up as 0. A relayed answer to HEAD says
+            // whatever the origin said.
+            // https://www.rfc-editor.org/rfc/rfc9110#section-8.6
+            Some(_) if self.status == HttpStatusCode::NotModified => {}
+            Some(_) if self.head_only && self.headers.get("content-length").is_some() => {}
+            Some(_) if self.head_only && self.from_upstream && self.body.is_none() => {}
+            Some(size) => head.extend_from_slice(format!("Content-Length: {}\r\n", size).as_bytes()),
+            // without a length the end of the body is signalled by closing the connection
+            None => head.extend_from_slice(b"Connection: close\r\n"),
+        }
+        head.extend_from_slice(b"\r\n");
+
+        let body = if self.head_only { None } else { self.body };
+        (head, body)
+    }
+}
 
-    pub(crate) fn with_body<B: Body>(body: B) {}
+fn default_error_body(status: &HttpStatusCode, plain: bool) -> (String, &'static str) {
+    let title = format!("{} {}", status, status.reason());
+    if plain {
+        (format!("{}\n", title.trim_end()), "text/plain; charset=utf-8")
+    } else {
+        let html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n</body>\n</html>\n",
+            title.trim_end()
+        );
+        (html, "text/html; charset=utf-8")
+    }
 }
 
-pub(crate) trait Body {
+pub(crate) trait Body: Send {
     fn size(&self) -> Option<u64>;
-    fn write(self, stream: &mut TcpStream) -> Result<(), IoError>;
+    fn write(self: Box<Self>, stream: &mut dyn Write) -> Result<(), IoError>;
+
+    // Bodies backed by a file can be transferred without copying through
+    // userspace. The file is read from the offset given on, never from its
+    // own position, so a descriptor can be shared between responses.
+    #[cfg_attr(not(feature = "io-uring"), allow(dead_code))]
+    fn as_file(&self) -> Option<(&File, u64)> {
+        None
+    }
+}
+
+// Hands a body on from a worker to an engine thread a chunk at a time,
+// calling `wake` after each. The channel holds a single chunk, so the worker
+// waits while the client is slow and gives up once the receiver is gone.
+// `None` marks the end, a channel closed without it a body that broke off.
+pub(crate) struct ChunkWriter {
+    chunks: SyncSender<Option<Vec<u8>>>,
+    wake: Box<dyn Fn() + Send>,
+    chunk_size: usize,
+    buf: Vec<u8>,
+}
+
+impl ChunkWriter {
+    pub(crate) fn new(chunk_size: usize, wake: Box<dyn Fn() + Send>) -> (Self, Receiver<Option<Vec<u8>>>) {
+        let (chunks, receiver) = mpsc::sync_channel(1);
+        (ChunkWriter { chunks, wake, chunk_size, buf: Vec::with_capacity(chunk_size) }, receiver)
+    }
+
+    fn send(&mut self, chunk: Option<Vec<u8>>) -> Result<(), IoError> {
+        self.chunks.send(chunk).map_err(|_| IoError::new(ErrorKind::BrokenPipe, "connection closed"))?;
+        (self.wake)();
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<(), IoError> {
+        if !self.buf.is_empty() {
+            let chunk = mem::take(&mut self.buf);
+            self.send(Some(chunk))?;
+        }
+        self.send(None)
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let n = buf.len().min(self.chunk_size - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == self.chunk_size {
+            let chunk = mem::replace(&mut self.buf, Vec::with_capacity(self.chunk_size));
+            self.send(Some(chunk))?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
 }
 
 impl Body for String {
@@ -45,7 +280,7 @@ impl Body for String {
         Some(self.len() as u64)
     }
 
-    fn write(self, stream: &mut TcpStream) -> Result<(), IoError> {
+    fn write(self: Box<Self>, stream: &mut dyn Write) -> Result<(), IoError> {
         stream.write_all(self.as_bytes())
     }
 }
@@ -58,9 +293,55 @@ impl Body for File {
         }
     }
 
-    fn write(mut self, stream: &mut TcpStream) -> Result<(), IoError> {
-        let mut buf: [u8; 4096] = [0; 4096];
-        todo!()
+    fn as_file(&self) -> Option<(&File, u64)> {
+        let offset = (&*self).stream_position().ok()?;
+        Some((self, offset))
     }
 
-}
\ No newline at end of file
+    fn write(mut self: Box<Self>, stream: &mut dyn Write) -> Result<(), IoError> {
+        let mut buf: [u8; FILE_BUFFER_SIZE] = [0; FILE_BUFFER_SIZE];
+        loop {
+            let n = self.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            stream.write_all(&buf[..n])?;
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+    use crate::response::HttpResponse;
+    use crate::util::HttpStatusCode;
+
+    fn serialize(response: HttpResponse) -> String {
+        let mut out = Vec::new();
+        response.send(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_default_error_body() {
+        let html = serialize(HttpResponse::new(HttpStatusCode::Forbidden));
+        assert!(html.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(html.contains("Content-Type: text/html"));
+        assert!(html.contains("<h1>403 Forbidden</h1>"));
+
+        let plain = serialize(HttpResponse::new(HttpStatusCode::BadGateway).prefer_plain_error_body(true));
+        assert!(plain.ends_with("\r\n\r\n502 Bad Gateway\n"));
+
+        let no_content = serialize(HttpResponse::new(HttpStatusCode::NoContent).with_body("ignored".to_string()));
+        assert_eq!(no_content, "HTTP/1.1 204 No Content\r\n\r\n");
+
+        let not_modified = serialize(HttpResponse::new(HttpStatusCode::NotModified).header("ETag", "\"v1\""));
+        assert_eq!(not_modified, "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n");
+
+        // a relayed answer to HEAD keeps the length it announced
+        let head = serialize(HttpResponse::ok().header("Content-Length", "1234").omit_body());
+        assert_eq!(head, "HTTP/1.1 200 OK\r\nContent-Length: 1234\r\n\r\n");
+        let head = serialize(HttpResponse::ok().with_body("hello".to_string()).omit_body());
+        assert_eq!(head, "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n");
+    }
+}
@@ -1,35 +1,231 @@
+use crate::config::{Engine, ServerConfig};
 use crate::connection::HttpConnection;
 use crate::error::Error;
+use crate::event_loop::EventLoop;
 use crate::threadpool::ThreadPool;
+use crate::tls;
 use std::net::{SocketAddr, TcpListener};
 use std::str::FromStr;
+use std::sync::Arc;
 
 pub struct Server {
     socket: Option<TcpListener>,
     socket_addr: SocketAddr,
-    threadpool: ThreadPool,
+    threadpool: Arc<ThreadPool>,
+    config: Arc<ServerConfig>,
+    tls: Option<Arc<rustls::ServerConfig>>,
 }
 
-const NUM_THREADS: usize = 10;
-
 impl Server {
     pub fn new(addr: &str) -> Result<Self, Error> {
+        Server::with_config(addr, ServerConfig::default())
+    }
+
+    pub fn with_config(addr: &str, config: ServerConfig) -> Result<Self, Error> {
         let socket_addr = SocketAddr::from_str(addr)?;
+        let tls = config.tls.as_ref().map(tls::server_config).transpose()?;
         Ok(Server {
             socket: None,
             socket_addr,
-            threadpool: ThreadPool::new(NUM_THREADS),
+            threadpool: Arc::new(ThreadPool::new(config.worker_threads)),
+            config: Arc::new(config),
+            tls,
         })
     }
 
     pub fn run(&mut self) -> Result<(), Error> {
-        self.socket = Some(TcpListener::bind(self.socket_addr)?);
+        let listener = TcpListener::bind(self.socket_addr)?;
+        self.serve(listener)
+    }
+
+    // Serves on a listener that is already bound, e.g. to a port the system
+    // picked.
+    pub(crate) fn serve(&mut self, listener: TcpListener) -> Result<(), Error> {
+        self.socket = Some(listener);
+
+        match self.config.engine {
+            Engine::Threaded => self.run_threaded(),
+            Engine::EventLoop { threads } => self.run_event_loops(threads),
+            #[cfg(feature = "io-uring")]
+            Engine::IoUring { .. } if self.tls.is_some() => {
+                Err(Error::Tls("the io_uring engine can't serve TLS".to_string()))
+            }
+            #[cfg(feature = "io-uring")]
+            Engine::IoUring { entries } => {
+                let listener = self.socket.as_ref().expect("socket is none");
+                crate::uring::Uring::new(entries, Arc::clone(&self.threadpool), Arc::clone(&self.config))?.run(listener)?;
+                Ok(())
+            }
+        }
+    }
 
+    fn run_threaded(&self) -> Result<(), Error> {
         loop {
             let (stream, addr) = self.socket.as_ref().expect("socket is none").accept()?;
+            let config = Arc::clone(&self.config);
+            let tls = self.tls.clone();
             self.threadpool.execute(move || {
-                HttpConnection::init(stream);
+                HttpConnection::init(stream, addr, config, tls);
             });
         }
     }
+
+    fn run_event_loops(&self, threads: usize) -> Result<(), Error> {
+        let handles = (0..threads.max(1))
+            .map(|id| EventLoop::spawn(id, Arc::clone(&self.threadpool), Arc::clone(&self.config), self.tls.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut next = 0;
+        loop {
+            let (stream, addr) = self.socket.as_ref().expect("socket is none").accept()?;
+            handles[next % handles.len()].register(stream, addr)?;
+            next += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crate::config::{Engine, Handler, Location, ServerConfig, Timeouts, WriteAccess};
+    use crate::server::Server;
+
+    const TIMEOUT: Duration = Duration::from_millis(300);
+
+    // A server on a free loopback port serving the crate's own files.
+    fn start(engine: Engine) -> SocketAddr {
+        start_with(engine, Vec::new())
+    }
+
+    // Serves the crate's directory at `/`, and `locations` next to it.
+    fn start_with(engine: Engine, locations: Vec<Location>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut config = ServerConfig {
+            engine,
+            timeouts: Timeouts { header: TIMEOUT, body: TIMEOUT, keep_alive: TIMEOUT, send: Duration::from_secs(5) },
+            ..ServerConfig::default()
+        };
+        config.locations.push(Location::new("/", Handler::Static { root: PathBuf::from(env!("CARGO_MANIFEST_DIR")) }));
+        config.locations.extend(locations);
+        thread::spawn(move || Server::with_config(&addr.to_string(), config).unwrap().serve(listener));
+        addr
+    }
+
+    // Sends `request` and reads until the server closes the connection.
+    // Returns what came back and how long the server took to close.
+    fn exchange(addr: SocketAddr, request: &str) -> (String, Duration) {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let started = Instant::now();
+        client.write_all(request.as_bytes()).unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        (String::from_utf8_lossy(&received).into_owned(), started.elapsed())
+    }
+
+    fn check_timeouts(engine: Engine) {
+        let addr = start(engine);
+
+        // answered, then closed once idle for the keep-alive timeout
+        let (received, elapsed) = exchange(addr, "GET /Cargo.toml HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}: {}", engine, received);
+        assert!(received.contains("[package]"), "{:?}: {}", engine, received);
+        assert!(!received.contains("408"), "{:?}: {}", engine, received);
+        assert!(elapsed >= TIMEOUT, "{:?} closed after {:?}", engine, elapsed);
+
+        // headers that never end
+        let (received, elapsed) = exchange(addr, "GET /Cargo.toml HTTP/1.1\r\nHost");
+        assert!(received.starts_with("HTTP/1.1 408 "), "{:?}: {}", engine, received);
+        assert!(elapsed >= TIMEOUT, "{:?} answered after {:?}", engine, elapsed);
+
+        // a body that stops short
+        let (received, _) = exchange(addr, "POST /Cargo.toml HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\nabc");
+        assert!(received.starts_with("HTTP/1.1 408 "), "{:?}: {}", engine, received);
+
+        // a connection that never sends anything is closed without a word
+        let (received, _) = exchange(addr, "");
+        assert_eq!(received, "", "{:?}", engine);
+    }
+
+    fn check_connection_close(engine: Engine) {
+        let addr = start(engine);
+
+        // closed right after the response when the client says so
+        for request in ["GET /Cargo.toml HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n", "GET /Cargo.toml HTTP/1.0\r\n\r\n"] {
+            let (received, elapsed) = exchange(addr, request);
+            assert!(received.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}: {}", engine, received);
+            assert!(received.contains("\r\nConnection: close\r\n"), "{:?}: {}", engine, received);
+            assert!(elapsed < TIMEOUT, "{:?} closed after {:?}", engine, elapsed);
+        }
+
+        // HTTP/1.0 clients can ask to keep it open
+        let (received, elapsed) = exchange(addr, "GET /Cargo.toml HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+        assert!(!received.contains("Connection: close"), "{:?}: {}", engine, received);
+        assert!(elapsed >= TIMEOUT, "{:?} closed after {:?}", engine, elapsed);
+    }
+
+    // Requests sent before the answers to the earlier ones are all answered,
+    // in order.
+    fn check_pipelining(engine: Engine) {
+        let addr = start(engine);
+        let mut requests = "GET /Cargo.toml HTTP/1.1\r\nHost: x\r\n\r\n".repeat(200);
+        requests.push_str("GET /missing HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n");
+        let (received, elapsed) = exchange(addr, &requests);
+        assert_eq!(received.matches("HTTP/1.1 200 OK\r\n").count(), 200, "{:?}", engine);
+        assert!(received.contains("HTTP/1.1 404 Not Found\r\n"), "{:?}", engine);
+        assert!(elapsed < TIMEOUT, "{:?} closed after {:?}", engine, elapsed);
+    }
+
+    // Uploads larger than `body_size` are saved, other bodies that large
+    // still refused.
+    fn check_upload(engine: Engine) {
+        let root = tempfile::tempdir().unwrap();
+        // the whole request path is looked up below the root
+        std::fs::create_dir(root.path().join("uploads")).unwrap();
+        let uploads = Location { write_access: WriteAccess::Write, ..Location::new("/uploads", Handler::Static { root: root.path().to_path_buf() }) };
+        let addr = start_with(engine, vec![uploads]);
+
+        let content = "0123456789abcdef".repeat(128 * 1024);
+        let body = format!("--xyz\r\nContent-Disposition: form-data; name=\"file\"; filename=\"big.txt\"\r\n\r\n{}\r\n--xyz--\r\n", content);
+        for path in ["/uploads/", "/Cargo.toml"] {
+            let request = format!(
+                "POST {} HTTP/1.1\r\nHost: x\r\nContent-Type: multipart/form-data; boundary=xyz\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                path, body.len(), body,
+            );
+            let (received, _) = exchange(addr, &request);
+            let expected = if path == "/uploads/" { "HTTP/1.1 201 Created\r\n" } else { "HTTP/1.1 413 " };
+            assert!(received.starts_with(expected), "{:?}: {}", engine, received);
+        }
+        assert!(std::fs::read_to_string(root.path().join("uploads/big.txt")).unwrap() == content, "{:?}", engine);
+    }
+
+    #[test]
+    fn test_threaded() {
+        check_timeouts(Engine::Threaded);
+        check_connection_close(Engine::Threaded);
+        check_pipelining(Engine::Threaded);
+        check_upload(Engine::Threaded);
+    }
+
+    #[test]
+    fn test_event_loop() {
+        check_timeouts(Engine::EventLoop { threads: 2 });
+        check_connection_close(Engine::EventLoop { threads: 2 });
+        check_pipelining(Engine::EventLoop { threads: 2 });
+        check_upload(Engine::EventLoop { threads: 2 });
+    }
+
+    #[cfg(feature = "io-uring")]
+    #[test]
+    fn test_io_uring() {
+        check_timeouts(Engine::IoUring { entries: 256 });
+        check_connection_close(Engine::IoUring { entries: 256 });
+        check_pipelining(Engine::IoUring { entries: 256 });
+        check_upload(Engine::IoUring { entries: 256 });
+    }
 }
@@ -0,0 +1,17 @@
+// Fixtures shared by the unit tests.
+
+use crate::parser::Parser;
+use crate::request::HttpRequest;
+
+// Parses `text`, which has to be one complete request and nothing more.
+pub(crate) fn request(text: &str) -> HttpRequest {
+    let mut parser = Parser::new();
+    let consumed = parser.feed(text.as_bytes()).unwrap();
+    assert_eq!(consumed, text.len(), "more than one request");
+    parser.finish().unwrap()
+}
+
+// A request with the request line and fields of `head`, a Host and `body`.
+pub(crate) fn request_with_body(head: &str, body: &str) -> HttpRequest {
+    request(&format!("{}\r\nHost: x\r\nContent-Length: {}\r\n\r\n{}", head, body.len(), body))
+}
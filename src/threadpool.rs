@@ -1,27 +1,27 @@
-use std::thread::{self, JoinHandle};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
 use std::sync::mpsc;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, PoisonError};
+use log::{debug, error};
 
 pub(crate) struct ThreadPool {
-    threads: Vec<Worker>,
     sender: mpsc::Sender<Job>
 }
 
 impl ThreadPool {
     pub(crate) fn new(num_threads: usize) -> Self {
         assert!(num_threads > 0);
-        let mut threads = Vec::with_capacity(num_threads);
 
         let (sender, recviever) = mpsc::channel();
         let recviever = Arc::new(Mutex::new(recviever));
 
         for i in 0..num_threads {
-            let worker = Worker::new(i, Arc::clone(&recviever)); 
-            threads.push(worker);
+            Worker::spawn(i, Arc::clone(&recviever));
         }
 
-        ThreadPool { threads, sender }
+        ThreadPool { sender }
     }
 
     pub(crate) fn execute<T>(&self, job: T)
@@ -30,26 +30,92 @@ impl ThreadPool {
     {
         let job = Box::new(job);
 
-        self.sender.send(job).unwrap();        
+        self.sender.send(job).unwrap();
     }
 }
 
-struct Worker{
+// A worker owns no JoinHandle. It lives inside its own thread and acts as a
+// sentinel: if the thread unwinds past the catch_unwind around jobs, dropping
+// the worker spawns a replacement so the pool never shrinks.
+struct Worker {
     id: usize,
-    thread: JoinHandle<()>
+    recviever: Arc<Mutex<mpsc::Receiver<Job>>>,
 }
 
 impl Worker {
-    fn new(id: usize, recviever: Arc<Mutex<mpsc::Receiver<Job>>>) -> Self {
-        let thread =thread::spawn(move || loop {
-            let job = recviever.lock().unwrap().recv().unwrap();
+    fn spawn(id: usize, recviever: Arc<Mutex<mpsc::Receiver<Job>>>) {
+        let builder = thread::Builder::new().name(format!("worker-{}", id));
+        let res = builder.spawn(move || {
+            let worker = Worker { id, recviever };
+            worker.run();
+        });
+
+        if let Err(err) = res {
+            error!("failed to spawn worker {}: {}", id, err);
+        }
+    }
 
-            println!("Worker {} got a job; executing.", id);
+    fn run(&self) {
+        loop {
+            // a panicking job never holds the lock, but don't let a poisoned
+            // mutex take down every other worker if that ever changes
+            let job = match self.recviever.lock().unwrap_or_else(PoisonError::into_inner).recv() {
+                Ok(job) => job,
+                // the pool was dropped
+                Err(_) => return,
+            };
 
-            job();
-        });
-        Worker { id, thread }
+            debug!("Worker {} got a job; executing.", self.id);
+
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                error!("worker {} recovered from panicking job: {}", self.id, panic_message(&*payload));
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            error!("worker {} died, respawning", self.id);
+            Worker::spawn(self.id, Arc::clone(&self.recviever));
+        }
+    }
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&'static str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic payload"
     }
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use crate::threadpool::ThreadPool;
+
+    #[test]
+    fn test_pool_survives_panicking_jobs() {
+        let pool = ThreadPool::new(2);
+        for _ in 0..4 {
+            pool.execute(|| panic!("boom"));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        for i in 0..4 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(i).unwrap());
+        }
+
+        let mut got: Vec<i32> = (0..4).map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        got.sort();
+        assert_eq!(got, vec![0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,327 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::Arc;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, NoServerSessionStorage, ResolvesServerCert, ServerSessionMemoryCache};
+use rustls::sign::CertifiedKey;
+use rustls::version::{TLS12, TLS13};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use crate::config::{TlsCertificate, TlsConfig, TlsVersion};
+use crate::error::Error;
+
+// What a request gets to know about the TLS connection it came in on.
+#[derive(Debug)]
+pub(crate) struct TlsInfo {
+    // the host name the client asked for
+    pub(crate) server_name: Option<String>,
+    pub(crate) protocol: &'static str,
+    pub(crate) cipher: &'static str,
+    pub(crate) alpn: Option<String>,
+}
+
+impl TlsInfo {
+    // Only complete once the handshake is.
+    pub(crate) fn new(connection: &ServerConnection) -> Self {
+        TlsInfo {
+            server_name: connection.server_name().map(str::to_string),
+            protocol: connection.protocol_version().and_then(|version| version.as_str()).unwrap_or("unknown"),
+            cipher: connection.negotiated_cipher_suite().and_then(|suite| suite.suite().as_str()).unwrap_or("unknown"),
+            alpn: connection.alpn_protocol().map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+        }
+    }
+}
+
+// Loads the certificates and settles what is offered, so a broken
+// configuration is noticed on startup rather than by the first client.
+pub(crate) fn server_config(config: &TlsConfig) -> Result<Arc<ServerConfig>, Error> {
+    let mut provider = ring::default_provider();
+    if !config.cipher_suites.is_empty() {
+        provider.cipher_suites = config.cipher_suites.iter()
+            .map(|name| {
+                ring::ALL_CIPHER_SUITES.iter()
+                    .find(|suite| suite.suite().as_str() == Some(name.as_str()))
+                    .copied()
+                    .ok_or_else(|| Error::Tls(format!("unknown cipher suite {}", name)))
+            })
+            .collect::<Result<_, _>>()?;
+    }
+    let versions: Vec<_> = config.versions.iter()
+        .map(|version| match version {
+            TlsVersion::Tls12 => &TLS12,
+            TlsVersion::Tls13 => &TLS13,
+        })
+        .collect();
+    let certificates = Certificates::load(&provider, &config.certificates)?;
+
+    let mut server = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(&versions)
+        .map_err(|err| Error::Tls(err.to_string()))?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(certificates));
+    server.alpn_protocols = config.alpn_protocols.iter().map(|protocol| protocol.as_bytes().to_vec()).collect();
+    server.session_storage = match config.session_cache_size {
+        0 => Arc::new(NoServerSessionStorage {}),
+        size => ServerSessionMemoryCache::new(size),
+    };
+    if config.session_tickets {
+        server.ticketer = ring::Ticketer::new().map_err(|err| Error::Tls(err.to_string()))?;
+    }
+    Ok(Arc::new(server))
+}
+
+// Picks the certificate by the name the client asks for.
+// https://www.rfc-editor.org/rfc/rfc6066#section-3
+#[derive(Debug)]
+struct Certificates {
+    // exact names ahead of wildcards
+    by_name: Vec<(String, Arc<CertifiedKey>)>,
+    default: Arc<CertifiedKey>,
+}
+
+impl Certificates {
+    fn load(provider: &CryptoProvider, certificates: &[TlsCertificate]) -> Result<Self, Error> {
+        let mut by_name = Vec::new();
+        let mut default = None;
+        for certificate in certificates {
+            let key = certified_key(provider, certificate)?;
+            by_name.extend(certificate.server_names.iter().map(|name| (name.to_ascii_lowercase(), Arc::clone(&key))));
+            default.get_or_insert(key);
+        }
+        by_name.sort_by_key(|(name, _)| name.starts_with("*."));
+        let default = default.ok_or_else(|| Error::Tls("no certificate configured".to_string()))?;
+        Ok(Certificates { by_name, default })
+    }
+
+    fn find(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let found = server_name.and_then(|server_name| {
+            self.by_name.iter().find(|(name, _)| name_matches(name, server_name))
+        });
+        Arc::clone(found.map_or(&self.default, |(_, key)| key))
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.find(client_hello.server_name()))
+    }
+}
+
+fn name_matches(name: &str, server_name: &str) -> bool {
+    match name.strip_prefix("*.") {
+        Some(parent) => server_name.split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(parent)),
+        None => name.eq_ignore_ascii_case(server_name),
+    }
+}
+
+fn certified_key(provider: &CryptoProvider, certificate: &TlsCertificate) -> Result<Arc<CertifiedKey>, Error> {
+    let failed = |path: &std::path::Path, err: &dyn std::fmt::Display| Error::Tls(format!("{}: {}", path.display(), err));
+    let chain = CertificateDer::pem_file_iter(&certificate.cert_chain)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| failed(&certificate.cert_chain, &err))?;
+    if chain.is_empty() {
+        return Err(failed(&certificate.cert_chain, &"no certificate found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(&certificate.private_key)
+        .map_err(|err| failed(&certificate.private_key, &err))?;
+    let key = provider.key_provider.load_private_key(key)
+        .map_err(|err| failed(&certificate.private_key, &err))?;
+    let certified = CertifiedKey::new(chain, key);
+    certified.keys_match().map_err(|err| failed(&certificate.private_key, &err))?;
+    Ok(Arc::new(certified))
+}
+
+// A blocking connection, encrypted or not.
+pub(crate) enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    pub(crate) fn new(tcp: TcpStream, tls: Option<&Arc<ServerConfig>>) -> io::Result<Self> {
+        match tls {
+            Some(config) => {
+                let connection = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+                Ok(Stream::Tls(Box::new(StreamOwned::new(connection, tcp))))
+            }
+            None => Ok(Stream::Plain(tcp)),
+        }
+    }
+
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(tcp) => tcp,
+            Stream::Tls(stream) => stream.get_ref(),
+        }
+    }
+
+    // what can be handed on as is, e.g. to a tunnel
+    pub(crate) fn plain(&self) -> Option<&TcpStream> {
+        match self {
+            Stream::Plain(tcp) => Some(tcp),
+            Stream::Tls(_) => None,
+        }
+    }
+
+    // `None` before the handshake is complete
+    pub(crate) fn tls_info(&self) -> Option<TlsInfo> {
+        match self {
+            Stream::Tls(stream) if !stream.conn.is_handshaking() => Some(TlsInfo::new(&stream.conn)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn shutdown(&mut self) {
+        if let Stream::Tls(stream) = self {
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+        }
+        let _ = self.tcp().shutdown(Shutdown::Both);
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// Reads plaintext off a non-blocking socket. `WouldBlock` once everything
+// received so far has been decrypted and read.
+pub(crate) fn read_plaintext(connection: &mut ServerConnection, socket: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match connection.reader().read(buf) {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            result => return result,
+        }
+        if connection.read_tls(socket)? == 0 {
+            return Ok(0);
+        }
+        connection.process_new_packets().map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+    use rustls::crypto::ring;
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use crate::config::{TlsCertificate, TlsConfig, TlsVersion};
+    use crate::error::Error;
+    use crate::tls::{name_matches, server_config, Stream, TlsInfo};
+
+    // A self-signed certificate for `names`, written to `dir`.
+    fn certificate(dir: &Path, names: &[&str]) -> (TlsCertificate, CertificateDer<'static>) {
+        let generated = rcgen::generate_simple_self_signed(names.iter().map(|name| name.to_string()).collect::<Vec<_>>()).unwrap();
+        let (cert_chain, private_key) = (dir.join(format!("{}.pem", names[0])), dir.join(format!("{}.key", names[0])));
+        std::fs::write(&cert_chain, generated.cert.pem()).unwrap();
+        std::fs::write(&private_key, generated.key_pair.serialize_pem()).unwrap();
+        (TlsCertificate::new(names, cert_chain, private_key), generated.cert.der().clone())
+    }
+
+    // Shakes hands over loopback, asking for `server_name`. Returns what the
+    // server learned and the certificate the client got.
+    fn handshake(config: &TlsConfig, roots: &[CertificateDer<'static>], server_name: &str) -> (TlsInfo, CertificateDer<'static>) {
+        let server = server_config(config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let mut stream = Stream::new(tcp, Some(&server)).unwrap();
+            let mut ping = [0; 4];
+            stream.read_exact(&mut ping).unwrap();
+            stream.write_all(b"pong").unwrap();
+            stream.flush().unwrap();
+            stream.tls_info().unwrap()
+        });
+
+        let mut store = RootCertStore::empty();
+        for root in roots {
+            store.add(root.clone()).unwrap();
+        }
+        let mut client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(store)
+            .with_no_client_auth();
+        client.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let connection = ClientConnection::new(Arc::new(client), ServerName::try_from(server_name.to_string()).unwrap()).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+        stream.write_all(b"ping").unwrap();
+        let mut pong = [0; 4];
+        stream.read_exact(&mut pong).unwrap();
+        let presented = stream.conn.peer_certificates().unwrap()[0].clone().into_owned();
+        (accepted.join().unwrap(), presented)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let dir = tempfile::tempdir().unwrap();
+        // clients connecting by address send no name and get the first one
+        let (first, first_der) = certificate(dir.path(), &["a.test", "127.0.0.1"]);
+        let (second, second_der) = certificate(dir.path(), &["b.test"]);
+        let roots = [first_der.clone(), second_der.clone()];
+        let mut config = TlsConfig::new(vec![first, second]);
+
+        let (info, presented) = handshake(&config, &roots, "b.test");
+        assert_eq!(presented, second_der);
+        assert_eq!(info.server_name.as_deref(), Some("b.test"));
+        assert_eq!(info.protocol, "TLSv1_3");
+        assert!(info.cipher.starts_with("TLS13_"), "{}", info.cipher);
+        assert_eq!(info.alpn.as_deref(), Some("http/1.1"));
+
+        let (info, presented) = handshake(&config, &roots, "127.0.0.1");
+        assert_eq!(presented, first_der);
+        assert_eq!(info.server_name, None);
+
+        config.versions = vec![TlsVersion::Tls12];
+        let (info, presented) = handshake(&config, &roots, "a.test");
+        assert_eq!(presented, first_der);
+        assert_eq!(info.protocol, "TLSv1_2");
+    }
+
+    #[test]
+    fn test_server_config() {
+        assert!(name_matches("example.com", "EXAMPLE.com"));
+        assert!(name_matches("*.example.com", "www.example.com"));
+        assert!(!name_matches("*.example.com", "example.com"));
+        assert!(!name_matches("*.example.com", "a.b.example.com"));
+
+        let failure = |config: &TlsConfig| match server_config(config) {
+            Err(Error::Tls(message)) => message,
+            _ => panic!("expected a TLS error"),
+        };
+        assert_eq!(failure(&TlsConfig::new(Vec::new())), "no certificate configured");
+        let missing = TlsConfig::new(vec![TlsCertificate::new(&["example.com"], "/nonexistent/cert.pem", "/nonexistent/key.pem")]);
+        assert!(failure(&missing).starts_with("/nonexistent/cert.pem: "));
+        let mut unknown_suite = TlsConfig::new(Vec::new());
+        unknown_suite.cipher_suites = vec!["TLS_NULL_WITH_NULL_NULL".to_string()];
+        assert_eq!(failure(&unknown_suite), "unknown cipher suite TLS_NULL_WITH_NULL_NULL");
+    }
+}
@@ -0,0 +1,129 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, error, info};
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+// The connection to the destination of a CONNECT. Once the 2xx is out the
+// client connection stops being HTTP and bytes are copied between the two
+// until both sides are done.
+// https://www.rfc-editor.org/rfc/rfc9110#section-9.3.6
+pub(crate) struct Tunnel {
+    upstream: TcpStream,
+    // "host:port" as the client asked for it
+    target: String,
+    idle_timeout: Duration,
+}
+
+impl Tunnel {
+    pub(crate) fn new(upstream: TcpStream, target: String, idle_timeout: Duration) -> Self {
+        Tunnel { upstream, target, idle_timeout }
+    }
+
+    // For engines that can't block: runs the tunnel on a thread of its own.
+    // `to_client` is what is still owed to the client, the response
+    // included, `to_upstream` what the client sent after its request.
+    pub(crate) fn spawn(self, client: TcpStream, peer_addr: SocketAddr, to_client: Vec<u8>, to_upstream: Vec<u8>) {
+        let spawned = thread::Builder::new().name("tunnel".to_string()).spawn(move || {
+            if let Err(err) = client.set_nonblocking(false).and_then(|()| (&client).write_all(&to_client)) {
+                failed_to_start(peer_addr, &self.target, err);
+                return;
+            }
+            self.run(client, peer_addr, &to_upstream);
+        });
+        if let Err(err) = spawned {
+            error!("failed to start a tunnel thread: {}", err);
+        }
+    }
+
+    // Copies in both directions until both have closed, either side fails or
+    // nothing moved for the idle timeout. Blocks until then.
+    pub(crate) fn run(self, client: TcpStream, peer_addr: SocketAddr, to_upstream: &[u8]) {
+        let started = Instant::now();
+        let activity = Activity { started, last: AtomicU64::new(0) };
+        let streams = (|| -> io::Result<_> {
+            (&self.upstream).write_all(to_upstream)?;
+            for stream in [&client, &self.upstream] {
+                stream.set_write_timeout(Some(self.idle_timeout))?;
+            }
+            Ok((client.try_clone()?, self.upstream.try_clone()?))
+        })();
+        let (client_out, upstream_out) = match streams {
+            Ok(streams) => streams,
+            Err(err) => {
+                failed_to_start(peer_addr, &self.target, err);
+                return;
+            }
+        };
+
+        let (sent, received) = thread::scope(|scope| {
+            let sent = scope.spawn(|| pump(client, upstream_out, self.idle_timeout, &activity));
+            let received = pump(self.upstream, client_out, self.idle_timeout, &activity);
+            (sent.join().unwrap_or(0) + to_upstream.len() as u64, received)
+        });
+        info!(
+            "{} CONNECT {} closed after {:.1?}, {} bytes sent, {} bytes received",
+            peer_addr, self.target, started.elapsed(), sent, received
+        );
+    }
+}
+
+fn failed_to_start(peer_addr: SocketAddr, target: &str, err: io::Error) {
+    debug!("{} CONNECT {} failed to start: {}", peer_addr, target, err);
+}
+
+// When the last byte went through in either direction.
+struct Activity {
+    started: Instant,
+    // milliseconds since `started`
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn touch(&self) {
+        self.last.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+}
+
+// Copies `from` into `to` until `from` closes, which is passed on as a
+// half-close. A failure or the idle timeout shuts both sockets down, which
+// also ends the pump going the other way. Returns the bytes copied.
+fn pump(mut from: TcpStream, mut to: TcpStream, idle_timeout: Duration, activity: &Activity) -> u64 {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut copied = 0;
+    loop {
+        // the other direction may have kept the tunnel alive meanwhile
+        let idle_for = activity.idle_for();
+        if idle_for >= idle_timeout || from.set_read_timeout(Some(idle_timeout - idle_for)).is_err() {
+            break;
+        }
+        match from.read(&mut buffer) {
+            Ok(0) => {
+                let _ = to.shutdown(Shutdown::Write);
+                return copied;
+            }
+            Ok(n) => {
+                if to.write_all(&buffer[..n]).is_err() {
+                    break;
+                }
+                copied += n as u64;
+                activity.touch();
+            }
+            // the idle time is re-checked at the top of the loop
+            Err(err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {}
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+    let _ = from.shutdown(Shutdown::Both);
+    let _ = to.shutdown(Shutdown::Both);
+    copied
+}
@@ -0,0 +1,279 @@
+use std::fmt::{Display, Formatter};
+
+// The four shapes a request-target can take.
+// https://www.rfc-editor.org/rfc/rfc9112#section-3.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TargetForm {
+    // "/path?query", the usual case
+    Origin,
+    // "http://host/path?query", sent to proxies
+    Absolute,
+    // "host:port", only for CONNECT
+    Authority,
+    // "*", only for a server-wide OPTIONS
+    Asterisk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct InvalidUri;
+
+impl Display for InvalidUri {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid uri")
+    }
+}
+
+// A request-target split into its components. Nothing is decoded, the parts
+// are exactly what the client sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Uri {
+    form: TargetForm,
+    // lowercased, schemes are case-insensitive
+    scheme: Option<String>,
+    authority: Option<String>,
+    path: String,
+    query: Option<String>,
+}
+
+impl Uri {
+    pub(crate) fn parse(target: &str) -> Result<Uri, InvalidUri> {
+        if target == "*" {
+            return Ok(Uri { form: TargetForm::Asterisk, scheme: None, authority: None, path: String::new(), query: None });
+        }
+        if target.starts_with('/') {
+            let (path, query) = split_query(target)?;
+            return Ok(Uri { form: TargetForm::Origin, scheme: None, authority: None, path, query });
+        }
+        if let Some((scheme, rest)) = target.split_once("://") {
+            if !is_scheme(scheme) {
+                return Err(InvalidUri);
+            }
+            let (authority, path_and_query) = match rest.find(['/', '?']) {
+                Some(pos) => rest.split_at(pos),
+                None => (rest, ""),
+            };
+            check_authority(authority)?;
+            let (path, query) = split_query(path_and_query)?;
+            return Ok(Uri {
+                form: TargetForm::Absolute,
+                scheme: Some(scheme.to_ascii_lowercase()),
+                authority: Some(authority.to_string()),
+                path,
+                query,
+            });
+        }
+        // authority-form requires a port
+        match target.rsplit_once(':') {
+            Some((_, port)) if !port.is_empty() => {
+                check_authority(target)?;
+                Ok(Uri { form: TargetForm::Authority, scheme: None, authority: Some(target.to_string()), path: String::new(), query: None })
+            }
+            _ => Err(InvalidUri),
+        }
+    }
+
+    // The target the request is really about, with scheme and authority
+    // filled in from the connection and `Host` when the client sent only a
+    // path.
+    // https://www.rfc-editor.org/rfc/rfc9112#section-3.3
+    pub(crate) fn effective(&self, scheme: &str, host: Option<&str>) -> Uri {
+        match self.form {
+            TargetForm::Absolute => self.clone(),
+            TargetForm::Authority => Uri { scheme: Some(scheme.to_string()), ..self.clone() },
+            TargetForm::Origin | TargetForm::Asterisk => Uri {
+                form: TargetForm::Absolute,
+                scheme: Some(scheme.to_string()),
+                authority: host.map(str::to_string),
+                path: self.path.clone(),
+                query: self.query.clone(),
+            },
+        }
+    }
+
+    pub(crate) fn form(&self) -> TargetForm {
+        self.form
+    }
+
+    pub(crate) fn scheme(&self) -> Option<&str> {
+        self.scheme.as_deref()
+    }
+
+    pub(crate) fn authority(&self) -> Option<&str> {
+        self.authority.as_deref()
+    }
+
+    // the authority without its port
+    pub(crate) fn host(&self) -> Option<&str> {
+        self.authority.as_deref().map(|authority| split_port(authority).0)
+    }
+
+    pub(crate) fn port(&self) -> Option<u16> {
+        self.authority.as_deref().and_then(|authority| split_port(authority).1)
+    }
+
+    // Empty for the asterisk and authority forms. An absolute-form target
+    // without a path has "/".
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub(crate) fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+}
+
+impl Display for Uri {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.form {
+            TargetForm::Asterisk if self.scheme.is_none() => return write!(f, "*"),
+            TargetForm::Authority if self.scheme.is_none() => {
+                return write!(f, "{}", self.authority.as_deref().unwrap_or(""));
+            }
+            _ => {}
+        }
+        if let Some(scheme) = &self.scheme {
+            write!(f, "{}://{}", scheme, self.authority.as_deref().unwrap_or(""))?;
+        }
+        write!(f, "{}", self.path)?;
+        if let Some(query) = &self.query {
+            write!(f, "?{}", query)?;
+        }
+        Ok(())
+    }
+}
+
+// Checks a Host header value, which is an authority without userinfo.
+pub(crate) fn check_authority(authority: &str) -> Result<(), InvalidUri> {
+    let (host, port) = match authority.strip_prefix('[') {
+        // IP-literal
+        Some(rest) => {
+            let (literal, rest) = rest.split_once(']').ok_or(InvalidUri)?;
+            if literal.is_empty() || !literal.bytes().all(|b| b.is_ascii_hexdigit() || b == b':' || b == b'.') {
+                return Err(InvalidUri);
+            }
+            ("", rest)
+        }
+        None => match authority.find(':') {
+            Some(pos) => authority.split_at(pos),
+            None => (authority, ""),
+        },
+    };
+    // a reg-name can't be empty, and userinfo ("user@") is not allowed in
+    // http(s) URIs
+    let ip_literal = authority.starts_with('[');
+    if (host.is_empty() && !ip_literal) || !valid_chars(host, |b| is_unreserved(b) || is_sub_delim(b)) {
+        return Err(InvalidUri);
+    }
+    match port.strip_prefix(':') {
+        Some(port) if port.bytes().all(|b| b.is_ascii_digit()) => Ok(()),
+        None if port.is_empty() => Ok(()),
+        _ => Err(InvalidUri),
+    }
+}
+
+fn split_query(target: &str) -> Result<(String, Option<String>), InvalidUri> {
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+    if !valid_chars(path, is_pchar_or_slash) {
+        return Err(InvalidUri);
+    }
+    if let Some(query) = query {
+        if !valid_chars(query, |b| is_pchar_or_slash(b) || b == b'?') {
+            return Err(InvalidUri);
+        }
+    }
+    let path = if path.is_empty() { "/" } else { path };
+    Ok((path.to_string(), query.map(str::to_string)))
+}
+
+fn split_port(authority: &str) -> (&str, Option<u16>) {
+    // the colons inside an IPv6 literal don't start a port
+    let host_end = authority.rfind(']').unwrap_or(0);
+    match authority[host_end..].rfind(':') {
+        Some(pos) => {
+            let (host, port) = authority.split_at(host_end + pos);
+            (host, port[1..].parse().ok())
+        }
+        None => (authority, None),
+    }
+}
+
+// Every byte is allowed by `allowed` or part of a well-formed "%XX" escape.
+fn valid_chars(s: &str, allowed: impl Fn(u8) -> bool) -> bool {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if !(i + 2 < bytes.len() && bytes[i + 1].is_ascii_hexdigit() && bytes[i + 2].is_ascii_hexdigit()) {
+                return false;
+            }
+            i += 3;
+        } else if allowed(bytes[i]) {
+            i += 1;
+        } else {
+            return false;
+        }
+    }
+    true
+}
+
+fn is_scheme(scheme: &str) -> bool {
+    let mut bytes = scheme.bytes();
+    bytes.next().is_some_and(|b| b.is_ascii_alphabetic())
+        && bytes.all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'-' || b == b'.')
+}
+
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"-._~".contains(&b)
+}
+
+fn is_sub_delim(b: u8) -> bool {
+    b"!$&'()*+,;=".contains(&b)
+}
+
+fn is_pchar_or_slash(b: u8) -> bool {
+    is_unreserved(b) || is_sub_delim(b) || b == b':' || b == b'@' || b == b'/'
+}
+
+#[cfg(test)]
+mod test {
+    use crate::uri::{TargetForm, Uri};
+
+    #[test]
+    fn test_parse_uri() {
+        let uri = Uri::parse("/a/b%20c?x=1&y=/?").unwrap();
+        assert_eq!(uri.form(), TargetForm::Origin);
+        assert_eq!(uri.path(), "/a/b%20c");
+        assert_eq!(uri.query(), Some("x=1&y=/?"));
+
+        let uri = Uri::parse("HTTP://example.com:8080?q").unwrap();
+        assert_eq!(uri.form(), TargetForm::Absolute);
+        assert_eq!(uri.scheme(), Some("http"));
+        assert_eq!(uri.host(), Some("example.com"));
+        assert_eq!(uri.port(), Some(8080));
+        assert_eq!(uri.path(), "/");
+        assert_eq!(uri.to_string(), "http://example.com:8080/?q");
+
+        let uri = Uri::parse("[::1]:443").unwrap();
+        assert_eq!(uri.form(), TargetForm::Authority);
+        assert_eq!(uri.host(), Some("[::1]"));
+        assert_eq!(uri.port(), Some(443));
+
+        assert_eq!(Uri::parse("*").unwrap().form(), TargetForm::Asterisk);
+
+        for invalid in ["/a b", "/a%2", "/a%zz", "/<script>", "http://user@host/", "example.com", "1http://x/", "/a#frag"] {
+            assert!(Uri::parse(invalid).is_err(), "{} should be rejected", invalid);
+        }
+    }
+
+    #[test]
+    fn test_effective_uri() {
+        let target = Uri::parse("/index.html?x").unwrap();
+        assert_eq!(target.effective("http", Some("example.com")).to_string(), "http://example.com/index.html?x");
+
+        let target = Uri::parse("http://other.org/").unwrap();
+        assert_eq!(target.effective("http", Some("example.com")).to_string(), "http://other.org/");
+    }
+}
@@ -0,0 +1,728 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use io_uring::{opcode, squeue, types, IoUring};
+use log::{debug, error};
+use crate::config::ServerConfig;
+use crate::connection::ReadTimer;
+use crate::handler;
+use crate::parser::{Parser, ParserError};
+use crate::request::HttpRequest;
+use crate::response::{Body, ChunkWriter, HttpResponse};
+use crate::threadpool::{panic_message, ThreadPool};
+use crate::tunnel::Tunnel;
+
+const BUFFER_SIZE: usize = 4096;
+// every connection owns one registered buffer, so this also caps the number
+// of concurrent connections
+const REGISTERED_BUFFERS: usize = 1024;
+const SPLICE_CHUNK: u32 = 64 * 1024;
+// Bodies that aren't files are passed on by the worker in chunks of this
+// size, sent from a registered buffer of their own while one is free.
+const SEND_BUFFER_SIZE: usize = 64 * 1024;
+const SEND_BUFFERS: usize = 64;
+
+// user_data carries the connection id in the upper bits and the operation in
+// the lowest byte. Accept and wake-up reads use connection id 0.
+const OP_ACCEPT: u64 = 0;
+const OP_WAKE: u64 = 1;
+const OP_READ: u64 = 2;
+const OP_SEND: u64 = 3;
+const OP_SPLICE_IN: u64 = 4;
+const OP_SPLICE_OUT: u64 = 5;
+// linked to reads, sends and splices; its own completion carries no news
+const OP_TIMEOUT: u64 = 6;
+const OP_WRITE_FIXED: u64 = 7;
+
+fn user_data(conn_id: u64, op: u64) -> u64 {
+    conn_id << 8 | op
+}
+
+// eventfd the workers poke after queueing a completion
+struct Waker(OwnedFd);
+
+impl Waker {
+    fn wake(&self) {
+        let one: u64 = 1;
+        // SAFETY: writes 8 bytes from a live u64 to an fd we own
+        unsafe { libc::write(self.0.as_raw_fd(), &one as *const u64 as *const libc::c_void, 8) };
+    }
+}
+
+// A response travelling from a worker back to the ring. File bodies are kept
+// as they are so they can be spliced, other large ones follow through
+// `chunks`, anything else is already serialized.
+struct Completion {
+    conn_id: u64,
+    bytes: Vec<u8>,
+    file: Option<Box<dyn Body>>,
+    chunks: Option<Receiver<Option<Vec<u8>>>>,
+    keep_alive: bool,
+    tunnel: Option<Tunnel>,
+}
+
+struct Splice {
+    body: Box<dyn Body>,
+    // where in the file the next splice starts
+    offset: u64,
+    remaining: u64,
+    // bytes moved into the pipe but not yet out to the socket
+    in_pipe: u32,
+}
+
+// Every connection has at most one operation in flight, so neither `out` nor
+// the registered buffer is touched while the kernel may still use them and a
+// connection is only ever closed from a completion.
+struct Connection {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    parser: Parser,
+    buf_index: u16,
+    out: Vec<u8>,
+    written: usize,
+    splice: Option<Splice>,
+    pipe: Option<(OwnedFd, OwnedFd)>,
+    // the rest of a body the worker passes on in chunks
+    chunks: Option<Receiver<Option<Vec<u8>>>>,
+    // nothing is in flight while the worker is still producing the next chunk
+    awaiting_chunk: bool,
+    // the registered buffer chunks are sent from, if one was free, and how
+    // much of it the current chunk fills
+    send_buf: Option<u16>,
+    send_len: usize,
+    keep_alive: bool,
+    // bytes left over after a complete request, e.g. a pipelined one
+    pending: Vec<u8>,
+    timer: ReadTimer,
+    // the kernel copies it when the linked timeout is submitted, it only has
+    // to stay put until then
+    timeout: Box<types::Timespec>,
+}
+
+pub(crate) struct Uring {
+    ring: IoUring,
+    buffers: Vec<Box<[u8]>>,
+    // what the kernel knows the buffers as; reads are always submitted from
+    // here since a buffer may be borrowed out of `buffers` at the time
+    iovecs: Vec<libc::iovec>,
+    free_buffers: Vec<u16>,
+    // indices of the send buffers, which follow the read buffers
+    free_send_buffers: Vec<u16>,
+    connections: HashMap<u64, Connection>,
+    next_conn_id: u64,
+    waker: Arc<Waker>,
+    wake_buf: Box<u64>,
+    completed_tx: Sender<Completion>,
+    completed_rx: Receiver<Completion>,
+    pool: Arc<ThreadPool>,
+    config: Arc<ServerConfig>,
+}
+
+impl Uring {
+    pub(crate) fn new(entries: u32, pool: Arc<ThreadPool>, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let ring = IoUring::new(entries)?;
+
+        let mut buffers: Vec<Box<[u8]>> = (0..REGISTERED_BUFFERS)
+            .map(|_| vec![0; BUFFER_SIZE].into_boxed_slice())
+            .chain((0..SEND_BUFFERS).map(|_| vec![0; SEND_BUFFER_SIZE].into_boxed_slice()))
+            .collect();
+        let iovecs: Vec<libc::iovec> = buffers.iter_mut()
+            .map(|buf| libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() })
+            .collect();
+        // SAFETY: the boxed buffers are owned by `Uring` and never move or get
+        // freed before the ring is dropped
+        unsafe { ring.submitter().register_buffers(&iovecs)? };
+
+        // SAFETY: plain syscall, the returned fd is checked before use
+        let wake_fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if wake_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `wake_fd` is a freshly created descriptor nobody else owns
+        let waker = Arc::new(Waker(unsafe { OwnedFd::from_raw_fd(wake_fd) }));
+
+        let (completed_tx, completed_rx) = mpsc::channel();
+        Ok(Uring {
+            ring,
+            buffers,
+            iovecs,
+            free_buffers: (0..REGISTERED_BUFFERS as u16).rev().collect(),
+            free_send_buffers: (REGISTERED_BUFFERS as u16..(REGISTERED_BUFFERS + SEND_BUFFERS) as u16).rev().collect(),
+            connections: HashMap::new(),
+            next_conn_id: 1,
+            waker,
+            wake_buf: Box::new(0),
+            completed_tx,
+            completed_rx,
+            pool,
+            config,
+        })
+    }
+
+    pub(crate) fn run(mut self, listener: &TcpListener) -> io::Result<()> {
+        let listen_fd = listener.as_raw_fd();
+        self.submit_accept(listen_fd)?;
+        self.submit_wake()?;
+
+        loop {
+            // everything queued since the last iteration goes out in one batch
+            if let Err(err) = self.ring.submit_and_wait(1) {
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+
+            let completions: Vec<(u64, i32)> = self.ring.completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+            for (data, res) in completions {
+                let conn_id = data >> 8;
+                match data & 0xff {
+                    OP_ACCEPT => {
+                        self.submit_accept(listen_fd)?;
+                        self.on_accept(res)?;
+                    }
+                    OP_WAKE => {
+                        self.submit_wake()?;
+                        self.collect_completed()?;
+                    }
+                    OP_READ => self.on_read(conn_id, res)?,
+                    OP_SEND => self.on_send(conn_id, res)?,
+                    OP_WRITE_FIXED => self.on_write_fixed(conn_id, res)?,
+                    OP_SPLICE_IN => self.on_splice_in(conn_id, res)?,
+                    OP_SPLICE_OUT => self.on_splice_out(conn_id, res)?,
+                    OP_TIMEOUT => {}
+                    op => error!("unknown io_uring operation {}", op),
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        loop {
+            // SAFETY: every buffer an entry points to is owned by `self` and,
+            // because of the one-operation-per-connection rule, stays put
+            // until its completion has been reaped
+            if unsafe { self.ring.submission().push(&entry) }.is_ok() {
+                return Ok(());
+            }
+            self.ring.submit()?;
+        }
+    }
+
+    fn submit_accept(&mut self, listen_fd: RawFd) -> io::Result<()> {
+        let entry = opcode::Accept::new(types::Fd(listen_fd), std::ptr::null_mut(), std::ptr::null_mut())
+            .flags(libc::SOCK_CLOEXEC)
+            .build()
+            .user_data(user_data(0, OP_ACCEPT));
+        self.push(entry)
+    }
+
+    fn submit_wake(&mut self) -> io::Result<()> {
+        let entry = opcode::Read::new(types::Fd(self.waker.0.as_raw_fd()), &mut *self.wake_buf as *mut u64 as *mut u8, 8)
+            .build()
+            .user_data(user_data(0, OP_WAKE));
+        self.push(entry)
+    }
+
+    // Pushes `entry` followed by a timeout that cancels it after `timeout`.
+    fn push_with_timeout(&mut self, conn_id: u64, entry: squeue::Entry, timeout: Duration) -> io::Result<()> {
+        let timespec = match self.connections.get_mut(&conn_id) {
+            Some(conn) => {
+                *conn.timeout = types::Timespec::from(timeout);
+                &*conn.timeout as *const types::Timespec
+            }
+            None => return Ok(()),
+        };
+        // both entries have to land in the same submission for the link to hold
+        let free = {
+            let sq = self.ring.submission();
+            sq.capacity() - sq.len()
+        };
+        if free < 2 {
+            self.ring.submit()?;
+        }
+        self.push(entry.flags(squeue::Flags::IO_LINK))?;
+        self.push(opcode::LinkTimeout::new(timespec).build().user_data(user_data(conn_id, OP_TIMEOUT)))
+    }
+
+    fn submit_read(&mut self, conn_id: u64) -> io::Result<()> {
+        let (fd, buf_index, deadline) = match self.connections.get(&conn_id) {
+            Some(conn) => (conn.stream.as_raw_fd(), conn.buf_index, conn.timer.deadline(&conn.parser)),
+            None => return Ok(()),
+        };
+        let remaining = deadline.at.saturating_duration_since(Instant::now());
+        if remaining == Duration::ZERO {
+            return self.on_read_timeout(conn_id);
+        }
+
+        let buf = self.iovecs[buf_index as usize].iov_base as *mut u8;
+        let entry = opcode::ReadFixed::new(types::Fd(fd), buf, BUFFER_SIZE as u32, buf_index)
+            .build()
+            .user_data(user_data(conn_id, OP_READ));
+        self.push_with_timeout(conn_id, entry, remaining)
+    }
+
+    fn on_read_timeout(&mut self, conn_id: u64) -> io::Result<()> {
+        let conn = match self.connections.get_mut(&conn_id) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        if !conn.timer.deadline(&conn.parser).respond {
+            self.close(conn_id);
+            return Ok(());
+        }
+
+        conn.parser.take();
+        Self::respond_and_close(conn, HttpResponse::request_timeout());
+        self.submit_send(conn_id)
+    }
+
+    fn submit_send(&mut self, conn_id: u64) -> io::Result<()> {
+        let entry = match self.connections.get(&conn_id) {
+            Some(conn) => {
+                let rest = &conn.out[conn.written..];
+                opcode::Send::new(types::Fd(conn.stream.as_raw_fd()), rest.as_ptr(), rest.len() as u32)
+                    .flags(libc::MSG_NOSIGNAL)
+                    .build()
+                    .user_data(user_data(conn_id, OP_SEND))
+            }
+            None => return Ok(()),
+        };
+        self.push_with_timeout(conn_id, entry, self.config.timeouts.send)
+    }
+
+    // Rust programs ignore SIGPIPE, so writing to a socket the peer has
+    // closed fails with EPIPE like a send with MSG_NOSIGNAL would.
+    fn submit_write_fixed(&mut self, conn_id: u64) -> io::Result<()> {
+        let entry = match self.connections.get(&conn_id) {
+            Some(Connection { stream, send_buf: Some(index), send_len, written, .. }) => {
+                let buf = (self.iovecs[*index as usize].iov_base as *const u8).wrapping_add(*written);
+                opcode::WriteFixed::new(types::Fd(stream.as_raw_fd()), buf, (send_len - written) as u32, *index)
+                    .build()
+                    .user_data(user_data(conn_id, OP_WRITE_FIXED))
+            }
+            _ => return Ok(()),
+        };
+        self.push_with_timeout(conn_id, entry, self.config.timeouts.send)
+    }
+
+    fn submit_splice_in(&mut self, conn_id: u64) -> io::Result<()> {
+        let entry = match self.connections.get(&conn_id) {
+            Some(Connection { splice: Some(splice), pipe: Some((_, pipe_w)), .. }) => {
+                let (file, _) = splice.body.as_file().expect("spliced body is not a file");
+                let len = splice.remaining.min(SPLICE_CHUNK as u64) as u32;
+                // an explicit offset leaves the file position alone, which
+                // other responses sharing the descriptor may rely on
+                opcode::Splice::new(types::Fd(file.as_raw_fd()), splice.offset as i64, types::Fd(pipe_w.as_raw_fd()), -1, len)
+                    .build()
+                    .user_data(user_data(conn_id, OP_SPLICE_IN))
+            }
+            _ => return Ok(()),
+        };
+        self.push(entry)
+    }
+
+    fn submit_splice_out(&mut self, conn_id: u64) -> io::Result<()> {
+        let entry = match self.connections.get(&conn_id) {
+            Some(Connection { stream, splice: Some(splice), pipe: Some((pipe_r, _)), .. }) => {
+                opcode::Splice::new(types::Fd(pipe_r.as_raw_fd()), -1, types::Fd(stream.as_raw_fd()), -1, splice.in_pipe)
+                    .build()
+                    .user_data(user_data(conn_id, OP_SPLICE_OUT))
+            }
+            _ => return Ok(()),
+        };
+        self.push_with_timeout(conn_id, entry, self.config.timeouts.send)
+    }
+
+    fn on_accept(&mut self, res: i32) -> io::Result<()> {
+        if res < 0 {
+            error!("accept failed: {}", io::Error::from_raw_os_error(-res));
+            return Ok(());
+        }
+
+        // SAFETY: the kernel handed us a new descriptor that nobody else owns
+        let stream = unsafe { TcpStream::from_raw_fd(res) };
+        let buf_index = match self.free_buffers.pop() {
+            Some(index) => index,
+            None => {
+                error!("too many connections, dropping new one");
+                return Ok(());
+            }
+        };
+        let peer_addr = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(err) => {
+                debug!("peer went away before being served: {}", err);
+                self.free_buffers.push(buf_index);
+                return Ok(());
+            }
+        };
+
+        let conn_id = self.next_conn_id;
+        self.next_conn_id += 1;
+        self.connections.insert(conn_id, Connection {
+            stream,
+            peer_addr,
+            parser: Parser::with_limits(self.config.limits)
+                .with_obs_fold(self.config.obs_fold)
+                .with_path_normalization(self.config.path_normalization),
+            buf_index,
+            out: Vec::new(),
+            written: 0,
+            splice: None,
+            pipe: None,
+            chunks: None,
+            awaiting_chunk: false,
+            send_buf: None,
+            send_len: 0,
+            keep_alive: true,
+            pending: Vec::new(),
+            timer: ReadTimer::new(self.config.timeouts),
+            timeout: Box::new(types::Timespec::new()),
+        });
+        self.submit_read(conn_id)
+    }
+
+    fn on_read(&mut self, conn_id: u64, res: i32) -> io::Result<()> {
+        if res == -libc::ECANCELED {
+            return self.on_read_timeout(conn_id);
+        }
+        if res <= 0 {
+            if res < 0 {
+                debug!("Error reading from socket {}", io::Error::from_raw_os_error(-res));
+            }
+            self.close(conn_id);
+            return Ok(());
+        }
+
+        let conn = match self.connections.get_mut(&conn_id) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        // borrow the buffer out of the table while parsing; moving the box
+        // leaves the registered memory where it is
+        let index = conn.buf_index as usize;
+        let buffer = mem::take(&mut self.buffers[index]);
+        let res = self.process(conn_id, &buffer[..res as usize]);
+        self.buffers[index] = buffer;
+        res
+    }
+
+    // Parses what is left over from the last read before asking for more.
+    fn process_pending(&mut self, conn_id: u64) -> io::Result<()> {
+        let pending = match self.connections.get_mut(&conn_id) {
+            Some(conn) => mem::take(&mut conn.pending),
+            None => return Ok(()),
+        };
+        if pending.is_empty() {
+            return self.submit_read(conn_id);
+        }
+        self.process(conn_id, &pending)
+    }
+
+    fn process(&mut self, conn_id: u64, data: &[u8]) -> io::Result<()> {
+        let conn = match self.connections.get_mut(&conn_id) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+
+        // parsing happens on the ring thread, where a panic would take every
+        // connection down with it
+        let parsed = match panic::catch_unwind(AssertUnwindSafe(|| Self::parse(conn, data))) {
+            Ok(parsed) => parsed,
+            Err(payload) => {
+                error!("io_uring engine recovered from panicking parser: {}", panic_message(&*payload));
+                conn.parser.take();
+                Self::respond_and_close(conn, HttpResponse::internal_server_error());
+                return self.submit_send(conn_id);
+            }
+        };
+
+        match parsed {
+            // the request is incomplete, keep reading
+            Ok((_, None)) => self.submit_read(conn_id),
+            Ok((consumed, Some(mut request))) => {
+                conn.pending.extend_from_slice(&data[consumed..]);
+                request.set_src_addr(conn.peer_addr);
+                self.dispatch(conn_id, request);
+                Ok(())
+            }
+            Err(e) => {
+                let response = handler::parse_error(&e, conn.parser.error_offset(), conn.peer_addr);
+                Self::respond_and_close(conn, response);
+                self.submit_send(conn_id)
+            }
+        }
+    }
+
+    fn parse(conn: &mut Connection, data: &[u8]) -> Result<(usize, Option<HttpRequest>), ParserError> {
+        let consumed = conn.parser.feed(data)?;
+        conn.timer.on_read(&conn.parser);
+        if !conn.parser.is_done() {
+            return Ok((consumed, None));
+        }
+        conn.parser.take().finish().map(|request| (consumed, Some(request)))
+    }
+
+    // Queues `response` as the last thing sent on this connection.
+    fn respond_and_close(conn: &mut Connection, response: HttpResponse) {
+        conn.keep_alive = false;
+        conn.out.clear();
+        conn.written = 0;
+        let _ = response.header("Connection", "close").send(&mut conn.out);
+    }
+
+    fn dispatch(&self, conn_id: u64, mut request: HttpRequest) {
+        let completed_tx = self.completed_tx.clone();
+        let waker = Arc::clone(&self.waker);
+        let config = Arc::clone(&self.config);
+        self.pool.execute(move || {
+            let (mut response, payload) = handler::handle_or_500(&mut request, &config);
+            let tunnel = response.take_tunnel();
+            let mut keep_alive = response.keep_alive();
+
+            let (mut bytes, body) = response.into_parts();
+            match body {
+                Some(body) if body.as_file().is_some() => {
+                    let _ = completed_tx.send(Completion { conn_id, bytes, file: Some(body), chunks: None, keep_alive, tunnel });
+                    waker.wake();
+                }
+                // a large body is passed on while it is being written, so no
+                // more than a chunk of it is held at a time
+                Some(body) if body.size().is_none_or(|size| size > SEND_BUFFER_SIZE as u64) => {
+                    let wake_ring = Arc::clone(&waker);
+                    let (mut writer, chunks) = ChunkWriter::new(SEND_BUFFER_SIZE, Box::new(move || wake_ring.wake()));
+                    let _ = completed_tx.send(Completion { conn_id, bytes, file: None, chunks: Some(chunks), keep_alive, tunnel });
+                    waker.wake();
+                    // the connection is closed once the chunks stop without an end
+                    if let Err(err) = body.write(&mut writer).and_then(|()| writer.finish()) {
+                        debug!("Error writing response body {}", err);
+                    }
+                }
+                body => {
+                    if let Err(err) = body.map_or(Ok(()), |body| body.write(&mut bytes)) {
+                        error!("Error serializing response {}", err);
+                        bytes.clear();
+                        keep_alive = false;
+                    }
+                    let _ = completed_tx.send(Completion { conn_id, bytes, file: None, chunks: None, keep_alive, tunnel });
+                    waker.wake();
+                }
+            }
+
+            // let the pool log it
+            if let Some(payload) = payload {
+                panic::resume_unwind(payload);
+            }
+        });
+    }
+
+    fn collect_completed(&mut self) -> io::Result<()> {
+        while let Ok(completion) = self.completed_rx.try_recv() {
+            let conn_id = completion.conn_id;
+            if let Some(tunnel) = completion.tunnel {
+                // nothing is in flight while a request is handled, so the
+                // connection can leave the ring right away
+                if let Some(conn) = self.connections.remove(&conn_id) {
+                    self.free_buffers.push(conn.buf_index);
+                    tunnel.spawn(conn.stream, conn.peer_addr, completion.bytes, conn.pending);
+                }
+                continue;
+            }
+            let conn = match self.connections.get_mut(&conn_id) {
+                Some(conn) => conn,
+                None => continue,
+            };
+
+            conn.out = completion.bytes;
+            conn.written = 0;
+            conn.keep_alive = completion.keep_alive;
+            if let Some(body) = completion.file {
+                if conn.pipe.is_none() {
+                    conn.pipe = Some(pipe()?);
+                }
+                let offset = body.as_file().map_or(0, |(_, offset)| offset);
+                conn.splice = Some(Splice { remaining: body.size().unwrap_or(0), offset, body, in_pipe: 0 });
+            }
+            if let Some(chunks) = completion.chunks {
+                conn.chunks = Some(chunks);
+                conn.send_buf = self.free_send_buffers.pop();
+            }
+
+            if !conn.out.is_empty() {
+                self.submit_send(conn_id)?;
+            } else {
+                self.continue_body(conn_id)?;
+            }
+        }
+
+        // workers wake the ring for every chunk they pass on
+        let awaiting: Vec<u64> = self.connections.iter()
+            .filter(|(_, conn)| conn.awaiting_chunk)
+            .map(|(conn_id, _)| *conn_id)
+            .collect();
+        for conn_id in awaiting {
+            if let Some(conn) = self.connections.get_mut(&conn_id) {
+                conn.awaiting_chunk = false;
+            }
+            self.continue_body(conn_id)?;
+        }
+        Ok(())
+    }
+
+    fn on_send(&mut self, conn_id: u64, res: i32) -> io::Result<()> {
+        let conn = match self.connections.get_mut(&conn_id) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        if res <= 0 {
+            if res < 0 {
+                debug!("Error writing to socket {}", io::Error::from_raw_os_error(-res));
+            }
+            self.close(conn_id);
+            return Ok(());
+        }
+
+        conn.written += res as usize;
+        if conn.written < conn.out.len() {
+            return self.submit_send(conn_id);
+        }
+        self.continue_body(conn_id)
+    }
+
+    fn on_write_fixed(&mut self, conn_id: u64, res: i32) -> io::Result<()> {
+        let conn = match self.connections.get_mut(&conn_id) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        if res <= 0 {
+            if res < 0 {
+                debug!("Error writing to socket {}", io::Error::from_raw_os_error(-res));
+            }
+            self.close(conn_id);
+            return Ok(());
+        }
+
+        conn.written += res as usize;
+        if conn.written < conn.send_len {
+            return self.submit_write_fixed(conn_id);
+        }
+        self.continue_body(conn_id)
+    }
+
+    fn on_splice_in(&mut self, conn_id: u64, res: i32) -> io::Result<()> {
+        let splice = match self.connections.get_mut(&conn_id).and_then(|conn| conn.splice.as_mut()) {
+            Some(splice) => splice,
+            None => return Ok(()),
+        };
+        // a short file cannot fill the announced Content-Length, so the
+        // connection has to go
+        if res <= 0 {
+            self.close(conn_id);
+            return Ok(());
+        }
+
+        splice.in_pipe = res as u32;
+        splice.offset += res as u64;
+        self.submit_splice_out(conn_id)
+    }
+
+    fn on_splice_out(&mut self, conn_id: u64, res: i32) -> io::Result<()> {
+        let splice = match self.connections.get_mut(&conn_id).and_then(|conn| conn.splice.as_mut()) {
+            Some(splice) => splice,
+            None => return Ok(()),
+        };
+        if res <= 0 {
+            self.close(conn_id);
+            return Ok(());
+        }
+
+        splice.in_pipe -= res as u32;
+        splice.remaining -= res as u64;
+        if splice.in_pipe > 0 {
+            return self.submit_splice_out(conn_id);
+        }
+        self.continue_body(conn_id)
+    }
+
+    // Called whenever the previous chunk of a response is out: either splice
+    // more of the file or finish the response.
+    fn continue_body(&mut self, conn_id: u64) -> io::Result<()> {
+        let conn = match self.connections.get_mut(&conn_id) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+
+        if conn.splice.as_ref().is_some_and(|splice| splice.remaining > 0) {
+            return self.submit_splice_in(conn_id);
+        }
+        if let Some(chunks) = &conn.chunks {
+            match chunks.try_recv() {
+                Ok(Some(chunk)) => {
+                    conn.written = 0;
+                    return match conn.send_buf {
+                        Some(index) => {
+                            self.buffers[index as usize][..chunk.len()].copy_from_slice(&chunk);
+                            conn.send_len = chunk.len();
+                            self.submit_write_fixed(conn_id)
+                        }
+                        None => {
+                            conn.out = chunk;
+                            self.submit_send(conn_id)
+                        }
+                    };
+                }
+                Ok(None) => {
+                    conn.chunks = None;
+                    self.free_send_buffers.extend(conn.send_buf.take());
+                }
+                Err(TryRecvError::Empty) => {
+                    conn.awaiting_chunk = true;
+                    return Ok(());
+                }
+                // the body broke off, all the client can be told is that it
+                // ends short
+                Err(TryRecvError::Disconnected) => {
+                    self.close(conn_id);
+                    return Ok(());
+                }
+            }
+        }
+
+        conn.splice = None;
+        conn.out.clear();
+        conn.written = 0;
+        conn.timer.on_response_sent();
+        if conn.keep_alive {
+            self.process_pending(conn_id)
+        } else {
+            self.close(conn_id);
+            Ok(())
+        }
+    }
+
+    fn close(&mut self, conn_id: u64) {
+        if let Some(conn) = self.connections.remove(&conn_id) {
+            self.free_buffers.push(conn.buf_index);
+            self.free_send_buffers.extend(conn.send_buf);
+        }
+    }
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds: [RawFd; 2] = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors pipe2 writes
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: both descriptors were just created and are owned by nobody else
+    unsafe { Ok((OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1]))) }
+}
@@ -1,6 +1,9 @@
 use std::collections::HashSet;
 use std::fmt::{Display, Formatter};
+use std::time::SystemTime;
 use lazy_static::lazy_static;
+use time::format_description::{self, FormatItem};
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
 
 lazy_static! {
     pub(crate) static ref INVALID_TOKEN_CHARACTERS: HashSet<char> = {
@@ -25,72 +28,337 @@ lazy_static! {
 
         m
     };
+
+    static ref IMF_FIXDATE: Vec<FormatItem<'static>> = format_description::parse(
+        "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+    ).unwrap();
 }
 
-// FIXME: add CONNECT
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub(crate) enum HttpMethod {
-    Get,
-    Put,
-    Head,
-    Post,
-    Trace,
-    Delete,
-    Options,
-    Invalid,
+// Defines `HttpMethod` from a single table, like the status codes below.
+macro_rules! methods {
+    ($($name:ident = $token:literal, $safe:literal, $idempotent:literal;)*) => {
+        /// Every method in the IANA HTTP Method Registry, plus `Extension`
+        /// for any other token. Method names are case-sensitive, "get" is an
+        /// extension method.
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub(crate) enum HttpMethod {
+            $($name,)*
+            Extension(String),
+        }
+
+        impl HttpMethod {
+            // `name` must be a token, which the parser makes sure of.
+            pub(crate) fn from_name(name: &str) -> HttpMethod {
+                match name {
+                    $($token => HttpMethod::$name,)*
+                    name => HttpMethod::Extension(name.to_string()),
+                }
+            }
+
+            pub(crate) fn as_str(&self) -> &str {
+                match self {
+                    $(HttpMethod::$name => $token,)*
+                    HttpMethod::Extension(name) => name,
+                }
+            }
+
+            // Safe methods are read-only, so they can be answered from a
+            // cache or without write access.
+            // https://www.rfc-editor.org/rfc/rfc9110#section-9.2.1
+            pub(crate) fn is_safe(&self) -> bool {
+                match self {
+                    $(HttpMethod::$name => $safe,)*
+                    HttpMethod::Extension(_) => false,
+                }
+            }
+
+            // Idempotent requests may be retried when a connection fails
+            // before the response arrives.
+            // https://www.rfc-editor.org/rfc/rfc9110#section-9.2.2
+            pub(crate) fn is_idempotent(&self) -> bool {
+                match self {
+                    $(HttpMethod::$name => $idempotent,)*
+                    HttpMethod::Extension(_) => false,
+                }
+            }
+        }
+    };
+}
+
+// name = token, safe, idempotent
+methods! {
+    Acl = "ACL", false, true;
+    BaselineControl = "BASELINE-CONTROL", false, true;
+    Bind = "BIND", false, true;
+    Checkin = "CHECKIN", false, true;
+    Checkout = "CHECKOUT", false, true;
+    Connect = "CONNECT", false, false;
+    Copy = "COPY", false, true;
+    Delete = "DELETE", false, true;
+    Get = "GET", true, true;
+    Head = "HEAD", true, true;
+    Label = "LABEL", false, true;
+    Link = "LINK", false, true;
+    Lock = "LOCK", false, false;
+    Merge = "MERGE", false, true;
+    Mkactivity = "MKACTIVITY", false, true;
+    Mkcalendar = "MKCALENDAR", false, true;
+    Mkcol = "MKCOL", false, true;
+    Mkredirectref = "MKREDIRECTREF", false, true;
+    Mkworkspace = "MKWORKSPACE", false, true;
+    Move = "MOVE", false, true;
+    Options = "OPTIONS", true, true;
+    Orderpatch = "ORDERPATCH", false, true;
+    Patch = "PATCH", false, false;
+    Post = "POST", false, false;
+    Pri = "PRI", true, true;
+    Propfind = "PROPFIND", true, true;
+    Proppatch = "PROPPATCH", false, true;
+    Put = "PUT", false, true;
+    Rebind = "REBIND", false, true;
+    Report = "REPORT", true, true;
+    Search = "SEARCH", true, true;
+    Trace = "TRACE", true, true;
+    Unbind = "UNBIND", false, true;
+    Uncheckout = "UNCHECKOUT", false, true;
+    Unlink = "UNLINK", false, true;
+    Unlock = "UNLOCK", false, true;
+    Update = "UPDATE", false, true;
+    Updateredirectref = "UPDATEREDIRECTREF", false, true;
+    VersionControl = "VERSION-CONTROL", false, true;
 }
 
 impl Display for HttpMethod {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            HttpMethod::Get => { write!(f, "GET") }
-            HttpMethod::Put => { write!(f, "PUT") }
-            HttpMethod::Head => { write!(f, "HEAD") }
-            HttpMethod::Post => { write!(f, "POST") }
-            HttpMethod::Trace => { write!(f, "TRACE") }
-            HttpMethod::Delete => { write!(f, "DELETE") }
-            HttpMethod::Options => { write!(f, "OPTIONS") }
-            HttpMethod::Invalid => { write!(f, "INVALID") }
-        }
+        write!(f, "{}", self.as_str())
     }
 }
 
-#[derive(Debug, PartialEq, Default)]
-#[allow(dead_code)]
-#[non_exhaustive]
-pub(crate) enum HttpStatusCode {
-    Continue,
-    SwitchingProtocols,
+// Defines `HttpStatusCode` from a single table so codes and reason phrases
+// can't drift apart.
+macro_rules! status_codes {
+    ($($(#[$attr:meta])* $name:ident = $code:literal, $reason:literal;)*) => {
+        /// Every status code in the IANA HTTP Status Code Registry, plus
+        /// `Unregistered` for anything else an upstream may send.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+        #[allow(clippy::upper_case_acronyms)]
+        pub(crate) enum HttpStatusCode {
+            $($(#[$attr])* $name,)*
+            Unregistered(u16),
+        }
+
+        impl HttpStatusCode {
+            pub(crate) fn code(&self) -> u16 {
+                match self {
+                    $(HttpStatusCode::$name => $code,)*
+                    HttpStatusCode::Unregistered(code) => *code,
+                }
+            }
+
+            /// The canonical reason phrase, empty for unregistered codes.
+            pub(crate) fn reason(&self) -> &'static str {
+                match self {
+                    $(HttpStatusCode::$name => $reason,)*
+                    HttpStatusCode::Unregistered(_) => "",
+                }
+            }
+
+            pub(crate) fn from_code(code: u16) -> Self {
+                match code {
+                    $($code => HttpStatusCode::$name,)*
+                    code => HttpStatusCode::Unregistered(code),
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    Continue = 100, "Continue";
+    SwitchingProtocols = 101, "Switching Protocols";
+    Processing = 102, "Processing";
+    EarlyHints = 103, "Early Hints";
     #[default]
-    OK,
-    Accepted,
-    NonAuthoritativeInformation,
-    NoContent,
-    NotModified,
-    NotFound,
-    MethodNotAllowed,
-    InternalServerError,
+    OK = 200, "OK";
+    Created = 201, "Created";
+    Accepted = 202, "Accepted";
+    NonAuthoritativeInformation = 203, "Non-Authoritative Information";
+    NoContent = 204, "No Content";
+    ResetContent = 205, "Reset Content";
+    PartialContent = 206, "Partial Content";
+    MultiStatus = 207, "Multi-Status";
+    AlreadyReported = 208, "Already Reported";
+    IMUsed = 226, "IM Used";
+    MultipleChoices = 300, "Multiple Choices";
+    MovedPermanently = 301, "Moved Permanently";
+    Found = 302, "Found";
+    SeeOther = 303, "See Other";
+    NotModified = 304, "Not Modified";
+    UseProxy = 305, "Use Proxy";
+    TemporaryRedirect = 307, "Temporary Redirect";
+    PermanentRedirect = 308, "Permanent Redirect";
+    BadRequest = 400, "Bad Request";
+    Unauthorized = 401, "Unauthorized";
+    PaymentRequired = 402, "Payment Required";
+    Forbidden = 403, "Forbidden";
+    NotFound = 404, "Not Found";
+    MethodNotAllowed = 405, "Method Not Allowed";
+    NotAcceptable = 406, "Not Acceptable";
+    ProxyAuthenticationRequired = 407, "Proxy Authentication Required";
+    RequestTimeout = 408, "Request Timeout";
+    Conflict = 409, "Conflict";
+    Gone = 410, "Gone";
+    LengthRequired = 411, "Length Required";
+    PreconditionFailed = 412, "Precondition Failed";
+    ContentTooLarge = 413, "Content Too Large";
+    URITooLong = 414, "URI Too Long";
+    UnsupportedMediaType = 415, "Unsupported Media Type";
+    RangeNotSatisfiable = 416, "Range Not Satisfiable";
+    ExpectationFailed = 417, "Expectation Failed";
+    MisdirectedRequest = 421, "Misdirected Request";
+    UnprocessableContent = 422, "Unprocessable Content";
+    Locked = 423, "Locked";
+    FailedDependency = 424, "Failed Dependency";
+    TooEarly = 425, "Too Early";
+    UpgradeRequired = 426, "Upgrade Required";
+    PreconditionRequired = 428, "Precondition Required";
+    TooManyRequests = 429, "Too Many Requests";
+    RequestHeaderFieldsTooLarge = 431, "Request Header Fields Too Large";
+    UnavailableForLegalReasons = 451, "Unavailable For Legal Reasons";
+    InternalServerError = 500, "Internal Server Error";
+    NotImplemented = 501, "Not Implemented";
+    BadGateway = 502, "Bad Gateway";
+    ServiceUnavailable = 503, "Service Unavailable";
+    GatewayTimeout = 504, "Gateway Timeout";
+    HTTPVersionNotSupported = 505, "HTTP Version Not Supported";
+    VariantAlsoNegotiates = 506, "Variant Also Negotiates";
+    InsufficientStorage = 507, "Insufficient Storage";
+    LoopDetected = 508, "Loop Detected";
+    NotExtended = 510, "Not Extended";
+    NetworkAuthenticationRequired = 511, "Network Authentication Required";
+}
+
+impl HttpStatusCode {
+    pub(crate) fn is_informational(&self) -> bool {
+        (100..200).contains(&self.code())
+    }
+
+    pub(crate) fn is_success(&self) -> bool {
+        (200..300).contains(&self.code())
+    }
+
+    pub(crate) fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.code())
+    }
+
+    pub(crate) fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.code())
+    }
+
+    pub(crate) fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.code())
+    }
+
+    pub(crate) fn is_error(&self) -> bool {
+        self.is_client_error() || self.is_server_error()
+    }
+
+    // 1xx, 204 and 304 responses never carry content
+    // https://www.rfc-editor.org/rfc/rfc9110#section-6.4.1
+    pub(crate) fn allows_body(&self) -> bool {
+        !self.is_informational() && *self != HttpStatusCode::NoContent && *self != HttpStatusCode::NotModified
+    }
 }
 
 impl Display for HttpStatusCode {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-       let status_code = match self {
-           HttpStatusCode::Continue => 100,
-           HttpStatusCode::SwitchingProtocols => 101,
-           HttpStatusCode::OK => 200,
-           HttpStatusCode::Accepted => 202,
-           HttpStatusCode::NonAuthoritativeInformation => 203,
-           HttpStatusCode::NoContent => 204,
-           HttpStatusCode::NotModified => 304,
-           HttpStatusCode::NotFound => 404,
-           HttpStatusCode::MethodNotAllowed => 405,
-           HttpStatusCode::InternalServerError => 500,
-           
-       };
-        write!(f, "{}", status_code)
+        write!(f, "{}", self.code())
     }
 }
 
-pub(crate) const CR: char = '\r';
-pub(crate) const LF: char = '\n';
-pub(crate) const SP: char = ' ';
+pub(crate) const CR: u8 = b'\r';
+pub(crate) const LF: u8 = b'\n';
+pub(crate) const SP: u8 = b' ';
+pub(crate) const HTAB: u8 = b'\t';
+
+// e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+pub(crate) fn format_http_date(time: SystemTime) -> String {
+    OffsetDateTime::from(time).format(&IMF_FIXDATE).unwrap_or_default()
+}
+
+// Accepts all three HTTP-date formats: IMF-fixdate, the obsolete RFC 850
+// form and asctime.
+// https://www.rfc-editor.org/rfc/rfc9110#section-5.6.7
+pub(crate) fn parse_http_date(value: &str) -> Option<SystemTime> {
+    // every format starts with the day of the week, which adds nothing
+    let (_, rest) = value.trim().split_once([',', ' '])?;
+    let mut fields: Vec<&str> = rest.split_whitespace().collect();
+    if fields.last() == Some(&"GMT") {
+        fields.pop();
+    }
+    let (day, month, year, time) = match fields[..] {
+        // asctime: "Nov  6 08:49:37 1994"
+        [month, day, time, year] if month.starts_with(|ch: char| ch.is_ascii_alphabetic()) => {
+            (day, month, year.parse().ok()?, time)
+        }
+        // IMF-fixdate: "06 Nov 1994 08:49:37"
+        [day, month, year, time] => (day, month, year.parse().ok()?, time),
+        // RFC 850: "06-Nov-94 08:49:37"
+        [date, time] => {
+            let mut parts = date.split('-');
+            let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+            let year: i32 = year.parse().ok()?;
+            (day, month, if year < 70 { 2000 + year } else { 1900 + year }, time)
+        }
+        _ => return None,
+    };
+
+    let month = match month {
+        "Jan" => Month::January,
+        "Feb" => Month::February,
+        "Mar" => Month::March,
+        "Apr" => Month::April,
+        "May" => Month::May,
+        "Jun" => Month::June,
+        "Jul" => Month::July,
+        "Aug" => Month::August,
+        "Sep" => Month::September,
+        "Oct" => Month::October,
+        "Nov" => Month::November,
+        "Dec" => Month::December,
+        _ => return None,
+    };
+    let date = Date::from_calendar_date(year, month, day.parse().ok()?).ok()?;
+    let mut hms = time.split(':').map(|field| field.parse::<u8>().ok());
+    let time = Time::from_hms(hms.next()??, hms.next()??, hms.next()??).ok()?;
+    Some(PrimitiveDateTime::new(date, time).assume_utc().into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+    use crate::util::{format_http_date, parse_http_date, HttpStatusCode};
+
+    #[test]
+    fn test_status_code_registry() {
+        assert_eq!(HttpStatusCode::from_code(416), HttpStatusCode::RangeNotSatisfiable);
+        assert_eq!(HttpStatusCode::RangeNotSatisfiable.reason(), "Range Not Satisfiable");
+        assert_eq!(HttpStatusCode::from_code(599), HttpStatusCode::Unregistered(599));
+        assert_eq!(HttpStatusCode::Unregistered(599).to_string(), "599");
+
+        assert!(HttpStatusCode::EarlyHints.is_informational());
+        assert!(!HttpStatusCode::NotModified.allows_body());
+        assert!(HttpStatusCode::BadGateway.is_server_error());
+    }
+
+    #[test]
+    fn test_http_date() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), Some(time));
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(time));
+        assert_eq!(parse_http_date("yesterday"), None);
+        assert_eq!(parse_http_date("Sun, 31 Feb 1994 08:49:37 GMT"), None);
+    }
+}